@@ -1,26 +1,70 @@
-use crate::state::TrafficState;
-use crate::storage::Storage;
+mod access;
+mod auth;
+mod error;
+mod events;
+mod stream;
+
+use crate::config::{Config, TokenScope};
+use crate::health::{CaptureHealth, ExportStats, InterfaceHealth, PcapStats, PipelineStats};
+use crate::metrics;
+use crate::rates::{RateSample, RateSummary, HISTORY_SECONDS};
+use crate::state::{FlowEvent, PacketMetadata, TrafficState};
+use crate::rollups::{Dimension, Resolution, RollupPoint};
+use crate::storage::{FlowKind, FlowRecord, HistoryQuery, SortOrder, Storage};
 use axum::{
-    extract::{Query, State, WebSocketUpgrade, ws::{Message, WebSocket}},
-    response::IntoResponse,
+    extract::{Request, State, WebSocketUpgrade, ws::{Message, WebSocket}},
+    http::{header, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
     routing::get,
-    Json, Router,
+    Extension, Json, Router,
 };
+use error::{ApiError, ApiQuery};
+
+pub use access::Allowlist;
+pub use auth::Tokens;
+pub use events::EventLog;
 use serde::{Deserialize, Serialize};
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Instant;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+
+/// Response header carrying the cursor of the next `/api/history` page
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
 
 pub struct AppState {
     pub traffic: Arc<TrafficState>,
     pub storage: Arc<Storage>,
+    pub health: Arc<CaptureHealth>,
+    pub pipeline: Arc<PipelineStats>,
+    /// Sender side of the storage writer queue, used to report its depth
+    pub writer_queue: Sender<PacketMetadata>,
+    /// Counters of the enabled exporters, by exporter name
+    pub exports: Vec<(&'static str, Arc<ExportStats>)>,
     pub start_time: Instant,
+    pub config: Arc<Config>,
+    /// Clients allowed to use the API (None = everyone)
+    pub allowlist: Option<Arc<Allowlist>>,
+    /// Tokens accepted by the API (None = no authentication)
+    pub tokens: Option<Arc<Tokens>>,
+    /// Numbered flow events for the live streams
+    pub events: Arc<EventLog>,
 }
 
 #[derive(Serialize)]
 pub struct HealthResponse {
     status: String,
+    /// "ok", or why the database cannot be read
+    storage: String,
     active_connections: usize,
     total_packets: u64,
+    captures: BTreeMap<String, InterfaceHealth>,
 }
 
 #[derive(Serialize)]
@@ -29,31 +73,185 @@ pub struct StatsResponse {
     total_packets: u64,
     total_bytes: u64,
     active_connections: usize,
+    /// Rate of the latest complete second
     packets_per_second: f64,
     bytes_per_second: f64,
+    rates: RateSummary,
+    capture: BTreeMap<String, PcapStats>,
+    pipeline: PipelineResponse,
+}
+
+#[derive(Serialize)]
+pub struct PipelineResponse {
+    writer_queue_depth: usize,
+    writer_queue_capacity: usize,
+    channel_full_events: u64,
+    dropped_packets: u64,
+    storage_write_failures: u64,
+    lost_flow_records: u64,
 }
 
 #[derive(Deserialize)]
 pub struct HistoryParams {
     limit: Option<usize>,
+    from: Option<i64>,
+    to: Option<i64>,
+    /// IP address or CIDR block
+    src: Option<String>,
+    dst: Option<String>,
+    ip: Option<String>,
+    port: Option<u16>,
+    protocol: Option<String>,
+    min_length: Option<usize>,
+    interface: Option<String>,
+    order: Option<SortOrder>,
+    cursor: Option<String>,
+}
+
+impl HistoryParams {
+    fn into_query(self) -> Result<HistoryQuery, String> {
+        Ok(HistoryQuery {
+            from_ms: self.from,
+            to_ms: self.to,
+            src: self.src.as_deref().map(parse_network).transpose()?,
+            dst: self.dst.as_deref().map(parse_network).transpose()?,
+            ip: self.ip.as_deref().map(parse_network).transpose()?,
+            port: self.port,
+            protocol: self.protocol,
+            min_length: self.min_length,
+            interface: self.interface,
+            order: self.order.unwrap_or_default(),
+            cursor: self.cursor.as_deref().map(str::parse).transpose()?,
+            limit: self.limit.unwrap_or(100).clamp(1, 1000),
+        })
+    }
+}
+
+/// Accept a CIDR block or a single address
+fn parse_network(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid IP address or CIDR block '{}'", value))
+}
+
+#[derive(Deserialize)]
+pub struct RollupParams {
+    /// Range start, ms since Unix epoch (default: one hour before `to`)
+    from: Option<i64>,
+    /// Range end, ms since Unix epoch (default: now)
+    to: Option<i64>,
+    /// Fixed resolution; picked from the range length when omitted
+    resolution: Option<Resolution>,
+    dimension: Option<Dimension>,
+}
+
+#[derive(Serialize)]
+pub struct RollupResponse {
+    resolution: Resolution,
+    bucket_seconds: i64,
+    dimension: Dimension,
+    from: i64,
+    to: i64,
+    points: Vec<RollupPoint>,
+}
+
+#[derive(Deserialize)]
+pub struct TimeseriesParams {
+    /// Length of the series (default: 300, at most one hour)
+    seconds: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct TimeseriesResponse {
+    bucket_seconds: i64,
+    points: Vec<RateSample>,
+}
+
+#[derive(Deserialize)]
+pub struct FlowsParams {
+    limit: Option<usize>,
+    kind: Option<FlowKind>,
 }
 
 pub fn router(state: Arc<AppState>) -> Router {
-    Router::new()
+    let allowlist = state.allowlist.clone();
+    let tokens = state.tokens.clone();
+    let mut router = Router::new()
         .route("/api/live", get(get_live_stats))
         .route("/api/history", get(get_history))
+        .route("/api/flows", get(get_flows))
+        .route("/api/rollups", get(get_rollups))
         .route("/api/health", get(get_health))
         .route("/api/stats", get(get_stats))
+        .route("/api/timeseries", get(get_timeseries))
+        .route("/api/interfaces", get(get_interfaces))
         .route("/api/stream", get(ws_handler))
-        .with_state(state)
+        .route("/api/flow-events", get(flow_events_handler))
+        .route("/api/events", get(events::get_events))
+        .route("/metrics", get(get_metrics))
+        .fallback(not_found)
+        .with_state(state);
+    // The allowlist is checked before tokens, so unknown clients learn nothing about them
+    if let Some(tokens) = tokens {
+        router = router.layer(middleware::from_fn_with_state(tokens, auth::require_token));
+    }
+    if let Some(allowlist) = allowlist {
+        router = router.layer(middleware::from_fn_with_state(allowlist, access::enforce_allowlist));
+    }
+
+    router
+        // Layers run bottom-up on requests: assign an ID, open a span carrying it,
+        // then copy it onto the response
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(|request: &Request| {
+            let request_id = request
+                .headers()
+                .get("x-request-id")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            tracing::info_span!(
+                "request",
+                method = %request.method(),
+                uri = %auth::redacted_uri(request.uri()),
+                request_id
+            )
+        }))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
 }
 
-async fn get_health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
-    Json(HealthResponse {
-        status: "ok".to_string(),
+async fn not_found(request: Request) -> ApiError {
+    ApiError::not_found(format!("no route for {}", request.uri().path()))
+}
+
+/// 200 while at least one capture runs and the database is readable, 503 otherwise
+async fn get_health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthResponse>) {
+    let captures = state.health.interfaces
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+
+    let storage = match state.storage.check() {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.to_string(),
+    };
+    let mut status = state.health.overall_status();
+    if storage != "ok" {
+        status = "down";
+    }
+    let code = if status == "down" {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    (code, Json(HealthResponse {
+        status: status.to_string(),
+        storage,
         active_connections: state.traffic.active_connections.load(std::sync::atomic::Ordering::Relaxed),
         total_packets: state.traffic.total_packets.load(std::sync::atomic::Ordering::Relaxed),
-    })
+        captures,
+    }))
 }
 
 async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
@@ -62,16 +260,21 @@ async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
     let total_bytes = state.traffic.total_bytes.load(std::sync::atomic::Ordering::Relaxed);
     let active_connections = state.traffic.active_connections.load(std::sync::atomic::Ordering::Relaxed);
 
-    let packets_per_second = if uptime > 0 {
-        total_packets as f64 / uptime as f64
-    } else {
-        0.0
-    };
+    let rates = state.traffic.rates.lock().unwrap().summary();
 
-    let bytes_per_second = if uptime > 0 {
-        total_bytes as f64 / uptime as f64
-    } else {
-        0.0
+    let capture = state.health.interfaces
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().pcap))
+        .collect();
+
+    let queue = &state.writer_queue;
+    let pipeline = PipelineResponse {
+        writer_queue_depth: queue.max_capacity() - queue.capacity(),
+        writer_queue_capacity: queue.max_capacity(),
+        channel_full_events: state.pipeline.channel_full.load(std::sync::atomic::Ordering::Relaxed),
+        dropped_packets: state.pipeline.dropped_packets.load(std::sync::atomic::Ordering::Relaxed),
+        storage_write_failures: state.storage.write_failures(),
+        lost_flow_records: state.storage.lost_flow_records(),
     };
 
     Json(StatsResponse {
@@ -79,21 +282,73 @@ async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
         total_packets,
         total_bytes,
         active_connections,
-        packets_per_second,
-        bytes_per_second,
+        packets_per_second: rates.current.packets_per_second,
+        bytes_per_second: rates.current.bytes_per_second,
+        rates,
+        capture,
+        pipeline,
+    })
+}
+
+/// Packets and bytes per second over the last `seconds` seconds, oldest first
+async fn get_timeseries(
+    State(state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<TimeseriesParams>,
+) -> Json<TimeseriesResponse> {
+    let seconds = params.seconds.unwrap_or(300).clamp(1, HISTORY_SECONDS);
+    Json(TimeseriesResponse {
+        bucket_seconds: 1,
+        points: state.traffic.rates.lock().unwrap().series(seconds),
     })
 }
 
+/// Prometheus scrape endpoint
+async fn get_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        metrics::render(&state),
+    )
+}
+
+async fn get_interfaces(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    let mut interfaces: Vec<_> = state.traffic.interfaces
+        .iter()
+        .map(|entry| {
+            let (name, stats) = entry.pair();
+            serde_json::json!({
+                "interface": name,
+                "packets": stats.packets,
+                "bytes": stats.bytes,
+            })
+        })
+        .collect();
+
+    interfaces.sort_by(|a, b| a["interface"].as_str().cmp(&b["interface"].as_str()));
+
+    Json(serde_json::json!({ "interfaces": interfaces }))
+}
+
 async fn get_live_stats(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
-    // Return a snapshot of current connections
+    // Return a snapshot of current conversations, one entry per flow with both directions
     // Limiting to top 50 for performance
     let mut connections: Vec<_> = state.traffic.connections
         .iter()
         .map(|entry| {
-            let (key, stats) = entry.pair();
+            let stats = entry.value();
             serde_json::json!({
-                "connection": key,
-                "stats": stats
+                "connection": format!("{} -> {}", stats.client, stats.server),
+                "client": stats.client,
+                "server": stats.server,
+                "protocol": stats.protocol,
+                "state": stats.state,
+                "flow": entry.key(),
+                "stats": {
+                    "bytes_sent": stats.bytes_sent,
+                    "bytes_received": stats.bytes_received,
+                    "packets_sent": stats.packets_sent,
+                    "packets_received": stats.packets_received,
+                    "packets_count": stats.packets_count,
+                }
             })
         })
         .collect();
@@ -107,45 +362,102 @@ async fn get_live_stats(State(state): State<Arc<AppState>>) -> Json<serde_json::
 
     connections.truncate(50);
 
+    let recently_closed: Vec<_> = state.traffic.recently_closed.lock().unwrap().iter().cloned().collect();
+
     Json(serde_json::json!({
         "connections": connections,
+        "recently_closed": recently_closed,
         "total_packets": state.traffic.total_packets.load(std::sync::atomic::Ordering::Relaxed),
         "total_bytes": state.traffic.total_bytes.load(std::sync::atomic::Ordering::Relaxed),
     }))
 }
 
+/// Returns the matching packets as a JSON array. When more rows may follow, the
+/// `X-Next-Cursor` header carries the `cursor` value for the next page.
 async fn get_history(
     State(state): State<Arc<AppState>>,
-    Query(params): Query<HistoryParams>,
-) -> Json<serde_json::Value> {
+    ApiQuery(params): ApiQuery<HistoryParams>,
+) -> Result<Response, ApiError> {
+    let query = params.into_query().map_err(ApiError::bad_request)?;
+    let page = state.storage.query_history(&query)?;
+
+    let mut response = Json(page.packets).into_response();
+    if let Some(cursor) = page.next_cursor {
+        if let Ok(value) = HeaderValue::from_str(&cursor.to_string()) {
+            response.headers_mut().insert(NEXT_CURSOR_HEADER, value);
+        }
+    }
+    Ok(response)
+}
+
+async fn get_flows(
+    State(state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<FlowsParams>,
+) -> Result<Json<Vec<FlowRecord>>, ApiError> {
     let limit = params.limit.unwrap_or(100).min(1000);
-    match state.storage.query_history(limit) {
-        Ok(data) => Json(serde_json::json!(data)),
-        Err(e) => Json(serde_json::json!({ "error": e.to_string() })),
+    Ok(Json(state.storage.query_flows(limit, params.kind)?))
+}
+
+async fn get_rollups(
+    State(state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<RollupParams>,
+) -> Result<Json<RollupResponse>, ApiError> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let to = params.to.unwrap_or(now);
+    let from = params.from.unwrap_or(to - 3_600_000);
+    let resolution = params
+        .resolution
+        .unwrap_or_else(|| Resolution::auto(from, to, now, |r| state.config.rollup_retention(r)));
+    let dimension = params.dimension.unwrap_or(Dimension::Total);
+    if from > to {
+        return Err(ApiError::bad_request("`from` must not be after `to`"));
     }
+
+    // Include the bucket `from` falls into
+    let bucket_ms = resolution.bucket_ms();
+    let from = from.div_euclid(bucket_ms) * bucket_ms;
+
+    let points = state.storage.query_rollups(resolution, dimension, from, to)?;
+    Ok(Json(RollupResponse {
+        resolution,
+        bucket_seconds: bucket_ms / 1000,
+        dimension,
+        from,
+        to,
+        points,
+    }))
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    scope: Option<Extension<TokenScope>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    let scope = scope.map(|Extension(scope)| scope);
+    ws.on_upgrade(move |socket| stream::run(socket, state.traffic.clone(), state.events.clone(), scope))
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
-    // Simple polling implementation - sends stats every second
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+async fn flow_events_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let events = state.traffic.subscribe();
+    ws.on_upgrade(move |socket| handle_flow_events(socket, events))
+}
 
+/// Forward flow start/end events as they happen, one JSON message per event
+async fn handle_flow_events(mut socket: WebSocket, mut events: broadcast::Receiver<FlowEvent>) {
     loop {
-        interval.tick().await;
-
-        let stats = serde_json::json!({
-            "total_packets": state.traffic.total_packets.load(std::sync::atomic::Ordering::Relaxed),
-            "total_bytes": state.traffic.total_bytes.load(std::sync::atomic::Ordering::Relaxed),
-            "active_connections": state.traffic.active_connections.load(std::sync::atomic::Ordering::Relaxed),
-        });
-
-        if socket.send(Message::Text(stats.to_string().into())).await.is_err() {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // Slow client: skip the events it missed
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let Ok(text) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
             break;
         }
     }
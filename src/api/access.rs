@@ -0,0 +1,146 @@
+use super::auth::redacted_uri;
+use super::error::ApiError;
+use super::parse_network;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Networks allowed to use the API. Requests arriving through a trusted proxy are
+/// checked against the client address the proxy put in `X-Forwarded-For`.
+#[derive(Debug)]
+pub struct Allowlist {
+    allowed: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl Allowlist {
+    /// Parse the configured addresses and CIDR blocks. Returns `None` when the allowlist
+    /// is empty, i.e. every client is allowed.
+    pub fn new(allowed: &[String], trusted_proxies: &[String]) -> Result<Option<Self>, String> {
+        if allowed.is_empty() {
+            return Ok(None);
+        }
+        let parse = |values: &[String]| values.iter().map(|value| parse_network(value)).collect::<Result<Vec<_>, _>>();
+        Ok(Some(Self {
+            allowed: parse(allowed)?,
+            trusted_proxies: parse(trusted_proxies)?,
+        }))
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address a request came from. Starting at the peer, `X-Forwarded-For` is
+    /// walked from its last entry for as long as the hop that added an entry is a
+    /// trusted proxy, so a client cannot spoof its address by sending the header itself.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let hops: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            if !self.is_trusted_proxy(client) {
+                break;
+            }
+            match hop.parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    /// Check a request, returning the client address it was judged by
+    fn check(&self, peer: IpAddr, headers: &HeaderMap) -> Result<IpAddr, IpAddr> {
+        let client = self.client_ip(peer, headers);
+        if self.allowed.iter().any(|net| net.contains(&client)) {
+            Ok(client)
+        } else {
+            Err(client)
+        }
+    }
+}
+
+/// Middleware rejecting clients outside the allowlist with 403. Needs the server to
+/// provide `ConnectInfo<SocketAddr>`; requests without it are rejected.
+pub async fn enforce_allowlist(State(allowlist): State<Arc<Allowlist>>, request: Request, next: Next) -> Response {
+    let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        tracing::warn!("Rejected API request to {}: peer address unknown", redacted_uri(request.uri()));
+        return ApiError::new(StatusCode::FORBIDDEN, "client address unknown").into_response();
+    };
+
+    match allowlist.check(peer.ip(), request.headers()) {
+        Ok(_) => next.run(request).await,
+        Err(client) => {
+            tracing::warn!(
+                "Rejected API request from {} (peer {}) to {}: not in api_allowlist",
+                client,
+                peer,
+                redacted_uri(request.uri())
+            );
+            ApiError::new(StatusCode::FORBIDDEN, format!("client {} is not allowed", client)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn allowlist(allowed: &[&str], proxies: &[&str]) -> Allowlist {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        Allowlist::new(&strings(allowed), &strings(proxies)).unwrap().unwrap()
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let list = allowlist(&["10.0.0.0/8", "192.168.1.5", "fd00::/8"], &[]);
+        let none = HeaderMap::new();
+        assert!(list.check("10.1.2.3".parse().unwrap(), &none).is_ok());
+        assert!(list.check("192.168.1.5".parse().unwrap(), &none).is_ok());
+        assert!(list.check("fd00::1".parse().unwrap(), &none).is_ok());
+        assert!(list.check("192.168.1.6".parse().unwrap(), &none).is_err());
+        // Without trusted proxies the header is ignored
+        assert!(list.check("8.8.8.8".parse().unwrap(), &forwarded("10.0.0.1")).is_err());
+
+        assert!(Allowlist::new(&[], &[]).unwrap().is_none());
+        assert!(Allowlist::new(&["10.0.0.0/33".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_forwarded_for_from_trusted_proxies() {
+        let list = allowlist(&["10.0.0.0/8"], &["172.16.0.0/12"]);
+        let proxy = "172.16.0.2".parse().unwrap();
+
+        assert_eq!(list.check(proxy, &forwarded("10.0.0.7")), Ok("10.0.0.7".parse().unwrap()));
+        // Chained proxies are skipped, and entries added by the client are not believed
+        assert_eq!(
+            list.check(proxy, &forwarded("10.0.0.9, 8.8.8.8, 172.16.0.3")),
+            Err("8.8.8.8".parse().unwrap())
+        );
+        // A proxy forwarding without the header is judged by its own address
+        assert!(list.check(proxy, &HeaderMap::new()).is_err());
+        assert!(list.check(proxy, &forwarded("garbage")).is_err());
+    }
+}
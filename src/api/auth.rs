@@ -0,0 +1,285 @@
+use super::error::ApiError;
+use crate::config::{ApiToken, TokenScope};
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::fs;
+use std::sync::Arc;
+
+/// Header carrying a token as an alternative to `Authorization: Bearer`
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Query parameter carrying the token of a WebSocket upgrade or event stream request, since
+/// browsers cannot set headers on WebSocket or `EventSource` requests
+const TOKEN_PARAM: &str = "token";
+
+/// The Server-Sent Events endpoint, which accepts `TOKEN_PARAM`
+const EVENTS_PATH: &str = "/api/events";
+
+/// Static API tokens, with their secrets loaded
+pub struct Tokens {
+    tokens: Vec<LoadedToken>,
+}
+
+struct LoadedToken {
+    /// Label for logs
+    name: String,
+    secret: Vec<u8>,
+    scope: TokenScope,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AuthError {
+    Missing,
+    Invalid,
+    /// A valid token whose scope does not cover the request
+    Forbidden(String),
+}
+
+impl Tokens {
+    /// Load the configured tokens, reading secrets from environment variables and files.
+    /// Returns `None` when no tokens are configured, i.e. authentication is off.
+    pub fn load(configured: &[ApiToken]) -> Result<Option<Self>, String> {
+        if configured.is_empty() {
+            return Ok(None);
+        }
+        let mut tokens = Vec::with_capacity(configured.len());
+        for (i, token) in configured.iter().enumerate() {
+            let name = token.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+            let secret = match (&token.token, &token.token_env, &token.token_file) {
+                (Some(secret), None, None) => secret.clone(),
+                (None, Some(var), None) => std::env::var(var)
+                    .map_err(|_| format!("token {}: environment variable {} is not set", name, var))?,
+                // Secret files usually end with a newline
+                (None, None, Some(path)) => fs::read_to_string(path)
+                    .map_err(|e| format!("token {}: {}: {}", name, path, e))?
+                    .trim_end()
+                    .to_string(),
+                _ => return Err(format!("token {}: set exactly one of token, token_env and token_file", name)),
+            };
+            if secret.is_empty() {
+                return Err(format!("token {} is empty", name));
+            }
+            tokens.push(LoadedToken {
+                name,
+                secret: secret.into_bytes(),
+                scope: token.scope,
+            });
+        }
+        Ok(Some(Self { tokens }))
+    }
+
+    /// Check the token presented with a request against the scope the request needs,
+    /// returning the token's scope
+    fn authorize(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> Result<TokenScope, AuthError> {
+        let presented = presented_token(uri, headers).ok_or(AuthError::Missing)?;
+
+        // Compare against every token so the time taken does not reveal which one matched
+        let mut matched = None;
+        for token in &self.tokens {
+            if constant_time_eq(presented.as_bytes(), &token.secret) {
+                matched = Some(token);
+            }
+        }
+        let token = matched.ok_or(AuthError::Invalid)?;
+
+        let required = required_scope(method);
+        if token.scope < required {
+            return Err(AuthError::Forbidden(format!(
+                "token {} has {} scope, {} is required",
+                token.name,
+                token.scope.as_str(),
+                required.as_str()
+            )));
+        }
+        Ok(token.scope)
+    }
+}
+
+/// Requests that only read need the read scope, anything else the admin scope. Handlers
+/// can require more from the token's scope, which is added to the request's extensions.
+fn required_scope(method: &Method) -> TokenScope {
+    if method == Method::GET || method == Method::HEAD {
+        TokenScope::Read
+    } else {
+        TokenScope::Admin
+    }
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// The token from `Authorization: Bearer`, `X-API-Key` or, for WebSocket upgrades and
+/// the event stream, the `token` query parameter
+fn presented_token(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    let header_value = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+
+    if let Some(authorization) = header_value(header::AUTHORIZATION.as_str()) {
+        let (scheme, token) = authorization.split_once(' ')?;
+        return scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string());
+    }
+    if let Some(key) = header_value(API_KEY_HEADER) {
+        return Some(key.to_string());
+    }
+    if is_websocket_upgrade(headers) || uri.path() == EVENTS_PATH {
+        return query_token(uri);
+    }
+    None
+}
+
+fn query_token(uri: &Uri) -> Option<String> {
+    #[derive(Deserialize)]
+    struct TokenQuery {
+        token: Option<String>,
+    }
+    Query::<TokenQuery>::try_from_uri(uri).ok()?.0.token
+}
+
+/// Equality check whose running time depends only on the lengths of the inputs
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The request URI with the value of a `token` query parameter hidden, for logging
+pub fn redacted_uri(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((TOKEN_PARAM, _)) => format!("{}=REDACTED", TOKEN_PARAM),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+/// Middleware requiring a valid token with sufficient scope: 401 without a valid token,
+/// 403 when its scope is too narrow
+pub async fn require_token(State(tokens): State<Arc<Tokens>>, mut request: Request, next: Next) -> Response {
+    match tokens.authorize(request.method(), request.uri(), request.headers()) {
+        Ok(scope) => {
+            request.extensions_mut().insert(scope);
+            next.run(request).await
+        }
+        Err(AuthError::Forbidden(detail)) => {
+            tracing::warn!("Rejected API request to {}: {}", redacted_uri(request.uri()), detail);
+            ApiError::new(StatusCode::FORBIDDEN, detail).into_response()
+        }
+        Err(error) => {
+            let detail = match error {
+                AuthError::Missing => "an API token is required",
+                _ => "invalid API token",
+            };
+            tracing::warn!("Rejected API request to {}: {}", redacted_uri(request.uri()), detail);
+            let mut response = ApiError::new(StatusCode::UNAUTHORIZED, detail).into_response();
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn tokens() -> Tokens {
+        let token = |name: &str, secret: &str, scope| ApiToken {
+            name: Some(name.to_string()),
+            token: Some(secret.to_string()),
+            token_env: None,
+            token_file: None,
+            scope,
+        };
+        Tokens::load(&[
+            token("dashboard", "read-secret", TokenScope::Read),
+            token("ops", "admin-secret", TokenScope::Admin),
+        ])
+        .unwrap()
+        .unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_token_scopes() {
+        let tokens = tokens();
+        let uri: Uri = "/api/live".parse().unwrap();
+        let check = |method: Method, headers: HeaderMap| tokens.authorize(&method, &uri, &headers);
+
+        assert_eq!(check(Method::GET, headers(&[])), Err(AuthError::Missing));
+        assert_eq!(check(Method::GET, headers(&[("authorization", "Bearer read-secret")])), Ok(TokenScope::Read));
+        assert_eq!(check(Method::GET, headers(&[("x-api-key", "admin-secret")])), Ok(TokenScope::Admin));
+        assert_eq!(check(Method::GET, headers(&[("authorization", "Bearer read-secre")])), Err(AuthError::Invalid));
+        assert_eq!(check(Method::GET, headers(&[("authorization", "Basic read-secret")])), Err(AuthError::Missing));
+        assert!(matches!(
+            check(Method::POST, headers(&[("authorization", "Bearer read-secret")])),
+            Err(AuthError::Forbidden(_))
+        ));
+        assert_eq!(check(Method::POST, headers(&[("authorization", "Bearer admin-secret")])), Ok(TokenScope::Admin));
+    }
+
+    #[test]
+    fn test_websocket_query_token() {
+        let tokens = tokens();
+        let uri: Uri = "/api/stream?token=read%2Dsecret".parse().unwrap();
+        let upgrade = headers(&[("upgrade", "websocket")]);
+        assert_eq!(tokens.authorize(&Method::GET, &uri, &upgrade), Ok(TokenScope::Read));
+        let events: Uri = "/api/events?channels=flow_end&token=read-secret".parse().unwrap();
+        assert_eq!(tokens.authorize(&Method::GET, &events, &HeaderMap::new()), Ok(TokenScope::Read));
+        // Plain requests must use a header, so tokens do not end up in URLs
+        assert_eq!(tokens.authorize(&Method::GET, &uri, &HeaderMap::new()), Err(AuthError::Missing));
+        // Asking for an event stream does not make other routes accept query tokens
+        let history: Uri = "/api/history?token=read-secret".parse().unwrap();
+        let event_stream = headers(&[("accept", "text/event-stream")]);
+        assert_eq!(tokens.authorize(&Method::GET, &history, &event_stream), Err(AuthError::Missing));
+
+        assert_eq!(redacted_uri(&uri), "/api/stream?token=REDACTED");
+        assert_eq!(redacted_uri(&"/api/history?limit=5".parse().unwrap()), "/api/history?limit=5");
+    }
+
+    #[test]
+    fn test_token_sources() {
+        let path = testing::temp_path("token");
+        fs::write(&path, "file-secret\n").unwrap();
+        let from_file = ApiToken {
+            name: None,
+            token: None,
+            token_env: None,
+            token_file: Some(path.to_str().unwrap().to_string()),
+            scope: TokenScope::Read,
+        };
+        let tokens = Tokens::load(std::slice::from_ref(&from_file)).unwrap().unwrap();
+        assert_eq!(tokens.tokens[0].secret, b"file-secret");
+        assert_eq!(tokens.tokens[0].name, "#1");
+
+        let ambiguous = ApiToken {
+            token: Some("x".to_string()),
+            ..from_file
+        };
+        assert!(Tokens::load(&[ambiguous]).is_err());
+        assert!(Tokens::load(&[]).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+// Numbered flow events for the live streams, and the `/api/events` Server-Sent Events
+// endpoint. Numbering lets an SSE client that reconnects with `Last-Event-ID` pick up
+// the flow events it missed from a short replay buffer.
+
+use super::stream::{Feed, Outgoing, Subscription};
+use super::error::{ApiError, ApiQuery};
+use super::AppState;
+use crate::config::TokenScope;
+use crate::state::FlowEvent;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Extension,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Number of flow events kept for replay
+const REPLAY_LEN: usize = 1024;
+
+/// Capacity of the numbered event channel; slower subscribers miss the oldest events
+const EVENT_CAPACITY: usize = 4096;
+
+/// Messages buffered for an SSE client before its feed stops reading events
+const SSE_BUFFER: usize = 64;
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// A flow event and its ID
+#[derive(Debug, Clone)]
+pub struct Sequenced {
+    pub id: u64,
+    pub event: FlowEvent,
+}
+
+struct Journal {
+    /// Latest events, oldest first
+    events: VecDeque<Sequenced>,
+    next_id: u64,
+}
+
+/// Numbers the flow events of `TrafficState` and keeps the latest for replay
+pub struct EventLog {
+    journal: Mutex<Journal>,
+    /// ID of the first event of this process
+    first_id: u64,
+    sender: broadcast::Sender<Sequenced>,
+}
+
+/// Events to replay to a resuming subscriber
+pub struct Replay {
+    pub events: Vec<Sequenced>,
+    /// Events after the subscriber's last one that are no longer buffered
+    pub missed: u64,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        // IDs start from the startup time in microseconds, so they keep increasing across
+        // restarts and a client resuming from an earlier run gets the whole buffer
+        let first_id = chrono::Utc::now().timestamp_micros().max(0) as u64;
+        Self {
+            journal: Mutex::new(Journal {
+                events: VecDeque::with_capacity(REPLAY_LEN),
+                next_id: first_id,
+            }),
+            first_id,
+            sender: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Number and publish the events of `events` until the channel closes
+    pub async fn run(self: Arc<Self>, mut events: broadcast::Receiver<FlowEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => self.record(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Event log fell behind, {} flow events lost", missed);
+                    // Leave a gap in the IDs, so resuming clients learn about it
+                    self.journal.lock().unwrap().next_id += missed;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn record(&self, event: FlowEvent) {
+        let mut journal = self.journal.lock().unwrap();
+        let event = Sequenced {
+            id: journal.next_id,
+            event,
+        };
+        journal.next_id += 1;
+        if journal.events.len() == REPLAY_LEN {
+            journal.events.pop_front();
+        }
+        journal.events.push_back(event.clone());
+        // Sent under the lock, so `subscribe_after` sees each event exactly once
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Sequenced> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribe, also returning the buffered events after `last_id`
+    pub fn subscribe_after(&self, last_id: u64) -> (broadcast::Receiver<Sequenced>, Replay) {
+        let journal = self.journal.lock().unwrap();
+        let events: Vec<_> = journal.events.iter().filter(|e| e.id > last_id).cloned().collect();
+        let first_kept = events.first().map_or(journal.next_id, |e| e.id);
+        // IDs from an earlier run say nothing about what was missed
+        let missed = if last_id >= self.first_id {
+            first_kept.saturating_sub(last_id + 1)
+        } else {
+            0
+        };
+        (self.sender.subscribe(), Replay { events, missed })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventsParams {
+    /// Comma-separated channel names
+    channels: Option<String>,
+    ip: Option<String>,
+    port: Option<u16>,
+    protocol: Option<String>,
+    interval_ms: Option<u64>,
+    packet_sample: Option<u64>,
+}
+
+impl EventsParams {
+    fn into_subscription(self) -> Result<Subscription, String> {
+        let channels = self.channels.as_deref().unwrap_or("totals,flow_start,flow_end");
+        let message = serde_json::json!({
+            "channels": channels.split(',').map(str::trim).filter(|c| !c.is_empty()).collect::<Vec<_>>(),
+            "filter": {
+                "ip": self.ip,
+                "port": self.port,
+                "protocol": self.protocol,
+            },
+            "interval_ms": self.interval_ms,
+            "packet_sample": self.packet_sample,
+        });
+        Subscription::from_value(message)
+    }
+}
+
+fn sse_event(outgoing: Outgoing) -> Result<Event, Infallible> {
+    let mut event = Event::default().event(outgoing.kind()).data(outgoing.message.to_string());
+    if let Some(id) = outgoing.id {
+        event = event.id(id.to_string());
+    }
+    Ok(event)
+}
+
+/// Server-Sent Events with the same messages as `/api/stream`, subscribed to with query
+/// parameters. Flow events carry IDs; a client reconnecting with `Last-Event-ID` first
+/// gets the buffered flow events it missed.
+pub async fn get_events(
+    State(state): State<Arc<AppState>>,
+    scope: Option<Extension<TokenScope>>,
+    headers: HeaderMap,
+    ApiQuery(params): ApiQuery<EventsParams>,
+) -> Result<Sse<ReceiverStream<Result<Event, Infallible>>>, ApiError> {
+    let subscription = params.into_subscription().map_err(ApiError::bad_request)?;
+    subscription
+        .authorize(scope.map(|Extension(scope)| scope))
+        .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?;
+    let last_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+
+    let mut feed = Feed::new(state.traffic.clone(), state.events.clone());
+    let initial = feed.subscribe(subscription, last_id);
+    let (tx, rx) = mpsc::channel(SSE_BUFFER);
+    tokio::spawn(async move {
+        for outgoing in initial {
+            if tx.send(sse_event(outgoing)).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            let batch = tokio::select! {
+                _ = tx.closed() => return,
+                batch = feed.next() => batch,
+            };
+            let Some(batch) = batch else {
+                return;
+            };
+            for outgoing in batch {
+                if tx.send(sse_event(outgoing)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{FlowKey, Protocol};
+
+    fn start_event(port: u16) -> FlowEvent {
+        let key = FlowKey {
+            src_ip: "10.0.0.1".parse().unwrap(),
+            dst_ip: "10.0.0.2".parse().unwrap(),
+            src_port: 40000,
+            dst_port: port,
+            protocol: 17,
+            vlan: None,
+            interface: Arc::from("eth0"),
+        };
+        FlowEvent::Start {
+            client: key.src(),
+            server: key.dst(),
+            key,
+            protocol: Protocol::Udp,
+            timestamp_ns: 0,
+        }
+    }
+
+    #[test]
+    fn test_replay_after_last_event_id() {
+        let log = EventLog::new();
+        let mut live = log.subscribe();
+        for port in 0..REPLAY_LEN as u16 + 10 {
+            log.record(start_event(port));
+        }
+        let first = log.first_id;
+        assert_eq!(live.try_recv().unwrap().id, first);
+
+        // Resuming within the buffer replays exactly the later events
+        let last = first + REPLAY_LEN as u64;
+        let (mut receiver, replay) = log.subscribe_after(last);
+        assert_eq!(replay.missed, 0);
+        assert_eq!(replay.events.iter().map(|e| e.id).collect::<Vec<_>>(), (last + 1..last + 10).collect::<Vec<_>>());
+        log.record(start_event(1));
+        assert_eq!(receiver.try_recv().unwrap().id, last + 10);
+
+        // Resuming from before the buffer reports the gap
+        let (_, replay) = log.subscribe_after(first + 2);
+        assert_eq!(replay.events.len(), REPLAY_LEN);
+        assert_eq!(replay.missed, 8);
+
+        // An ID from an earlier run replays everything buffered
+        let (_, replay) = log.subscribe_after(first - 100);
+        assert_eq!((replay.events.len(), replay.missed), (REPLAY_LEN, 0));
+    }
+
+    #[test]
+    fn test_events_params() {
+        let params = |channels: Option<&str>, protocol: Option<&str>| EventsParams {
+            channels: channels.map(str::to_string),
+            ip: Some("10.0.0.0/8".to_string()),
+            port: None,
+            protocol: protocol.map(str::to_string),
+            interval_ms: None,
+            packet_sample: None,
+        };
+        assert!(params(None, None).into_subscription().is_ok());
+        assert!(params(Some("flow_end, packets"), Some("tcp")).into_subscription().is_ok());
+        assert!(params(Some("flow_end,bogus"), None).into_subscription().is_err());
+        assert!(params(None, Some("icmp")).into_subscription().is_err());
+    }
+}
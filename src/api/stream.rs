@@ -0,0 +1,585 @@
+// The `/api/stream` WebSocket. Clients send a subscription message choosing channels, a
+// filter and an update interval; the stream then pushes events from the traffic state's
+// broadcast channels. Without a subscription it sends unfiltered totals every second.
+// `/api/events` serves the same messages over Server-Sent Events.
+
+use super::events::{EventLog, Sequenced};
+use super::parse_network;
+use crate::config::TokenScope;
+use crate::state::{FlowEvent, FlowKey, PacketEvent, Protocol, TrafficCounters, TrafficState};
+use axum::extract::ws::{Message, WebSocket};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast;
+use tokio::time::{interval_at, Duration, Interval};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const MIN_INTERVAL_MS: u64 = 100;
+const MAX_INTERVAL_MS: u64 = 60_000;
+
+/// By default one matching packet in this many is sent on the `packets` channel
+const DEFAULT_PACKET_SAMPLE: u64 = 100;
+
+/// Most flows in one `flows` update, busiest first
+const MAX_FLOW_UPDATES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Channel {
+    /// Traffic totals, once per interval
+    Totals,
+    /// Active flows that saw traffic, once per interval
+    Flows,
+    /// A flow started
+    FlowStart,
+    /// A flow ended
+    FlowEnd,
+    /// Sampled packets as they are captured
+    Packets,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterParams {
+    /// IP address or CIDR block, matching either endpoint
+    ip: Option<String>,
+    /// Port of either endpoint
+    port: Option<u16>,
+    protocol: Option<String>,
+}
+
+/// Message a client sends to replace its subscription
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscribeMessage {
+    channels: BTreeSet<Channel>,
+    #[serde(default)]
+    filter: FilterParams,
+    interval_ms: Option<u64>,
+    packet_sample: Option<u64>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct StreamFilter {
+    ip: Option<IpNet>,
+    port: Option<u16>,
+    protocol: Option<Protocol>,
+}
+
+impl StreamFilter {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn matches(&self, src: IpAddr, dst: IpAddr, src_port: u16, dst_port: u16, protocol: Protocol) -> bool {
+        self.ip.is_none_or(|net| net.contains(&src) || net.contains(&dst))
+            && self.port.is_none_or(|port| src_port == port || dst_port == port)
+            && self.protocol.is_none_or(|wanted| protocol == wanted)
+    }
+
+    fn matches_flow(&self, key: &FlowKey, protocol: Protocol) -> bool {
+        self.matches(key.src_ip, key.dst_ip, key.src_port, key.dst_port, protocol)
+    }
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    channels: BTreeSet<Channel>,
+    filter: StreamFilter,
+    interval: Duration,
+    packet_sample: u64,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            channels: BTreeSet::from([Channel::Totals]),
+            filter: StreamFilter::default(),
+            interval: Duration::from_millis(DEFAULT_INTERVAL_MS),
+            packet_sample: DEFAULT_PACKET_SAMPLE,
+        }
+    }
+}
+
+impl Subscription {
+    fn parse(text: &str) -> Result<Self, String> {
+        Self::from_message(serde_json::from_str(text).map_err(|e| e.to_string())?)
+    }
+
+    /// Read a subscription from a JSON value shaped like the subscription message
+    pub fn from_value(value: Value) -> Result<Self, String> {
+        Self::from_message(serde_json::from_value(value).map_err(|e| e.to_string())?)
+    }
+
+    fn from_message(message: SubscribeMessage) -> Result<Self, String> {
+        let protocol = match message.filter.protocol.as_deref().map(Protocol::from_str) {
+            Some(Ok(Protocol::Unknown)) => {
+                return Err(format!("unknown protocol '{}'", message.filter.protocol.unwrap_or_default()))
+            }
+            Some(Ok(protocol)) => Some(protocol),
+            _ => None,
+        };
+        Ok(Self {
+            channels: message.channels,
+            filter: StreamFilter {
+                ip: message.filter.ip.as_deref().map(parse_network).transpose()?,
+                port: message.filter.port,
+                protocol,
+            },
+            interval: Duration::from_millis(
+                message
+                    .interval_ms
+                    .unwrap_or(DEFAULT_INTERVAL_MS)
+                    .clamp(MIN_INTERVAL_MS, MAX_INTERVAL_MS),
+            ),
+            packet_sample: message.packet_sample.unwrap_or(DEFAULT_PACKET_SAMPLE).max(1),
+        })
+    }
+
+    fn wants(&self, channel: Channel) -> bool {
+        self.channels.contains(&channel)
+    }
+
+    /// Whether packet events are needed. Unfiltered totals come from the global counters.
+    fn needs_packets(&self) -> bool {
+        self.wants(Channel::Flows)
+            || self.wants(Channel::Packets)
+            || (self.wants(Channel::Totals) && !self.filter.is_empty())
+    }
+
+    fn needs_flow_events(&self) -> bool {
+        self.wants(Channel::FlowStart) || self.wants(Channel::FlowEnd)
+    }
+
+    /// Individual packets show more than aggregates, so when tokens are configured the
+    /// `packets` channel needs an admin token. `scope` is `None` without authentication.
+    pub fn authorize(&self, scope: Option<TokenScope>) -> Result<(), String> {
+        match scope {
+            Some(scope) if scope < TokenScope::Admin && self.wants(Channel::Packets) => {
+                Err("the packets channel requires an admin token".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A message for a subscriber. Flow events carry their event log ID.
+#[derive(Debug)]
+pub struct Outgoing {
+    pub id: Option<u64>,
+    pub message: Value,
+}
+
+impl Outgoing {
+    fn new(message: Value) -> Self {
+        Self { id: None, message }
+    }
+
+    /// The message's `type`
+    pub fn kind(&self) -> &str {
+        self.message["type"].as_str().unwrap_or("message")
+    }
+}
+
+/// State of one stream connection between interval updates
+struct Session {
+    subscription: Subscription,
+    /// Matching traffic since the last update
+    packets: u64,
+    bytes: u64,
+    flows: HashMap<FlowKey, TrafficCounters>,
+    /// Matching packet events seen, for sampling
+    seen: u64,
+    /// Global counters at the last update
+    last_totals: (u64, u64),
+    last_update: Instant,
+    /// Events missed because the client fell behind, reported with the next update
+    dropped: u64,
+}
+
+impl Session {
+    fn new(traffic: &TrafficState) -> Self {
+        Self {
+            subscription: Subscription::default(),
+            packets: 0,
+            bytes: 0,
+            flows: HashMap::new(),
+            seen: 0,
+            last_totals: global_totals(traffic),
+            last_update: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    /// Replace the subscription and start a fresh interval
+    fn subscribe(&mut self, subscription: Subscription, traffic: &TrafficState) -> Outgoing {
+        self.subscription = subscription;
+        self.packets = 0;
+        self.bytes = 0;
+        self.flows.clear();
+        self.seen = 0;
+        self.last_totals = global_totals(traffic);
+        self.last_update = Instant::now();
+        Outgoing::new(json!({
+            "type": "subscribed",
+            "channels": self.subscription.channels,
+            "interval_ms": self.subscription.interval.as_millis() as u64,
+            "packet_sample": self.subscription.packet_sample,
+        }))
+    }
+
+    /// Account for a packet event, returning a message when it is sampled
+    fn on_packet(&mut self, event: &PacketEvent) -> Option<Outgoing> {
+        let packet = &event.packet;
+        let filter = &self.subscription.filter;
+        if !filter.matches(packet.src_ip, packet.dst_ip, packet.src_port, packet.dst_port, packet.protocol) {
+            return None;
+        }
+        self.packets += event.packets;
+        self.bytes += packet.length as u64;
+        if self.subscription.wants(Channel::Flows) {
+            let counters = self.flows.entry(FlowKey::from_packet(packet).canonical()).or_default();
+            counters.packets += event.packets;
+            counters.bytes += packet.length as u64;
+        }
+
+        let sampled = self.seen % self.subscription.packet_sample == 0;
+        self.seen += 1;
+        if !self.subscription.wants(Channel::Packets) || !sampled {
+            return None;
+        }
+        let mut message = serde_json::to_value(packet).ok()?;
+        message["type"] = json!("packet");
+        message["packets"] = json!(event.packets);
+        Some(Outgoing::new(message))
+    }
+
+    fn on_flow_event(&self, sequenced: &Sequenced) -> Option<Outgoing> {
+        let event = &sequenced.event;
+        let (channel, key, protocol) = match event {
+            FlowEvent::Start { key, protocol, .. } => (Channel::FlowStart, key, *protocol),
+            FlowEvent::End(summary) => (Channel::FlowEnd, &summary.key, summary.protocol),
+        };
+        if !self.subscription.wants(channel) || !self.subscription.filter.matches_flow(key, protocol) {
+            return None;
+        }
+        let mut message = serde_json::to_value(event).ok()?;
+        message["type"] = serde_json::to_value(channel).ok()?;
+        Some(Outgoing {
+            id: Some(sequenced.id),
+            message,
+        })
+    }
+
+    /// Messages due at the end of an interval
+    fn update(&mut self, traffic: &TrafficState) -> Vec<Outgoing> {
+        let mut messages = Vec::new();
+        let elapsed = self.last_update.elapsed().as_secs_f64().max(0.001);
+        self.last_update = Instant::now();
+
+        let totals = global_totals(traffic);
+        if self.subscription.filter.is_empty() {
+            self.packets = totals.0.saturating_sub(self.last_totals.0);
+            self.bytes = totals.1.saturating_sub(self.last_totals.1);
+        }
+        self.last_totals = totals;
+
+        if self.subscription.wants(Channel::Totals) {
+            messages.push(Outgoing::new(json!({
+                "type": "totals",
+                "total_packets": totals.0,
+                "total_bytes": totals.1,
+                "active_connections": traffic.active_connections.load(Ordering::Relaxed),
+                "interval_packets": self.packets,
+                "interval_bytes": self.bytes,
+                "packets_per_second": self.packets as f64 / elapsed,
+                "bytes_per_second": self.bytes as f64 / elapsed,
+            })));
+        }
+        self.packets = 0;
+        self.bytes = 0;
+
+        if self.subscription.wants(Channel::Flows) && !self.flows.is_empty() {
+            let mut busiest: Vec<_> = self.flows.drain().collect();
+            busiest.sort_by_key(|(_, counters)| std::cmp::Reverse(counters.bytes));
+            // Flows that ended during the interval are reported on `flow_end` instead
+            let flows: Vec<_> = busiest
+                .into_iter()
+                .filter_map(|(key, counters)| {
+                    let stats = traffic.connections.get(&key)?;
+                    Some(json!({
+                        "flow": key,
+                        "client": stats.client,
+                        "server": stats.server,
+                        "protocol": stats.protocol,
+                        "state": stats.state,
+                        "interval_packets": counters.packets,
+                        "interval_bytes": counters.bytes,
+                        "bytes_sent": stats.bytes_sent,
+                        "bytes_received": stats.bytes_received,
+                        "packets_sent": stats.packets_sent,
+                        "packets_received": stats.packets_received,
+                    }))
+                })
+                .take(MAX_FLOW_UPDATES)
+                .collect();
+            messages.push(Outgoing::new(json!({ "type": "flows", "flows": flows })));
+        }
+
+        if self.dropped > 0 {
+            messages.push(Outgoing::new(json!({ "type": "dropped", "events": self.dropped })));
+            self.dropped = 0;
+        }
+        messages
+    }
+}
+
+fn global_totals(traffic: &TrafficState) -> (u64, u64) {
+    (
+        traffic.total_packets.load(Ordering::Relaxed),
+        traffic.total_bytes.load(Ordering::Relaxed),
+    )
+}
+
+/// Receive from a channel the subscription may not need; waits forever without one
+async fn recv<T: Clone>(receiver: &mut Option<broadcast::Receiver<T>>) -> Result<T, broadcast::error::RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The channels a subscriber reads and the session turning their events into messages.
+/// Events come from broadcast channels, so a subscriber that reads too slowly misses
+/// events (reported in a `dropped` message) instead of holding up capture.
+pub struct Feed {
+    traffic: Arc<TrafficState>,
+    log: Arc<EventLog>,
+    session: Session,
+    packets: Option<broadcast::Receiver<PacketEvent>>,
+    flow_events: Option<broadcast::Receiver<Sequenced>>,
+    ticker: Interval,
+}
+
+fn ticker(period: Duration) -> Interval {
+    interval_at(tokio::time::Instant::now() + period, period)
+}
+
+impl Feed {
+    /// A feed with the default subscription
+    pub fn new(traffic: Arc<TrafficState>, log: Arc<EventLog>) -> Self {
+        let session = Session::new(&traffic);
+        let ticker = ticker(session.subscription.interval);
+        Self {
+            traffic,
+            log,
+            session,
+            packets: None,
+            flow_events: None,
+            ticker,
+        }
+    }
+
+    /// Replace the subscription. With `resume_after`, flow events after that ID that are
+    /// still in the event log are replayed first.
+    pub fn subscribe(&mut self, subscription: Subscription, resume_after: Option<u64>) -> Vec<Outgoing> {
+        if !subscription.needs_packets() {
+            self.packets = None;
+        } else if self.packets.is_none() {
+            self.packets = Some(self.traffic.subscribe_packets());
+        }
+
+        let mut replayed = Vec::new();
+        let mut missed = 0;
+        if !subscription.needs_flow_events() {
+            self.flow_events = None;
+        } else if let Some(last_id) = resume_after {
+            let (receiver, replay) = self.log.subscribe_after(last_id);
+            self.flow_events = Some(receiver);
+            replayed = replay.events;
+            missed = replay.missed;
+        } else if self.flow_events.is_none() {
+            self.flow_events = Some(self.log.subscribe());
+        }
+
+        self.ticker = ticker(subscription.interval);
+        let mut messages = vec![self.session.subscribe(subscription, &self.traffic)];
+        if missed > 0 {
+            messages.push(Outgoing::new(json!({ "type": "dropped", "events": missed })));
+        }
+        messages.extend(replayed.iter().filter_map(|event| self.session.on_flow_event(event)));
+        messages
+    }
+
+    /// Wait for the next messages. Returns `None` when the traffic state is gone.
+    pub async fn next(&mut self) -> Option<Vec<Outgoing>> {
+        loop {
+            let messages = tokio::select! {
+                _ = self.ticker.tick() => self.session.update(&self.traffic),
+                event = recv(&mut self.packets) => match event {
+                    Ok(event) => self.session.on_packet(&event).into_iter().collect(),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        self.session.dropped += missed;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+                event = recv(&mut self.flow_events) => match event {
+                    Ok(event) => self.session.on_flow_event(&event).into_iter().collect(),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        self.session.dropped += missed;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            };
+            if !messages.is_empty() {
+                return Some(messages);
+            }
+        }
+    }
+}
+
+enum Input {
+    Client(Option<Result<Message, axum::Error>>),
+    Feed(Option<Vec<Outgoing>>),
+}
+
+/// Serve one stream connection. `scope` is that of the client's token, if any.
+pub async fn run(mut socket: WebSocket, traffic: Arc<TrafficState>, log: Arc<EventLog>, scope: Option<TokenScope>) {
+    let mut feed = Feed::new(traffic, log);
+
+    loop {
+        let input = tokio::select! {
+            received = socket.recv() => Input::Client(received),
+            messages = feed.next() => Input::Feed(messages),
+        };
+        let messages = match input {
+            Input::Client(Some(Ok(Message::Text(text)))) => match Subscription::parse(&text)
+                .and_then(|subscription| subscription.authorize(scope).map(|()| subscription))
+            {
+                Ok(subscription) => feed.subscribe(subscription, None),
+                // Keep the previous subscription
+                Err(e) => vec![Outgoing::new(json!({ "type": "error", "message": e }))],
+            },
+            Input::Client(Some(Ok(Message::Close(_))) | Some(Err(_)) | None) | Input::Feed(None) => break,
+            Input::Client(Some(Ok(_))) => continue,
+            Input::Feed(Some(messages)) => messages,
+        };
+
+        for outgoing in messages {
+            if socket.send(Message::Text(outgoing.message.to_string())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn udp_event(src: &str, dst: &str, dst_port: u16, length: usize) -> PacketEvent {
+        PacketEvent {
+            packet: testing::packet(Protocol::Udp, src, 40000, dst, dst_port, length),
+            packets: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_subscription() {
+        let subscription = Subscription::parse(
+            r#"{"channels": ["flows", "flow_end"], "filter": {"ip": "10.0.0.0/8", "protocol": "udp"}, "interval_ms": 10}"#,
+        )
+        .unwrap();
+        assert!(subscription.wants(Channel::Flows) && !subscription.wants(Channel::Totals));
+        assert_eq!(subscription.filter.protocol, Some(Protocol::Udp));
+        assert_eq!(subscription.interval, Duration::from_millis(MIN_INTERVAL_MS));
+        assert!(subscription.needs_packets() && subscription.needs_flow_events());
+
+        // The default subscription reads the global counters only
+        assert!(!Subscription::default().needs_packets());
+
+        assert!(Subscription::parse(r#"{"channels": ["everything"]}"#).is_err());
+        assert!(Subscription::parse(r#"{"channels": [], "filter": {"protocol": "icmp"}}"#).is_err());
+        assert!(Subscription::parse(r#"{"channels": [], "filter": {"ip": "10.0.0.0/33"}}"#).is_err());
+        assert!(Subscription::parse(r#"{"channels": [], "intervall_ms": 500}"#).is_err());
+
+        let packets = Subscription::parse(r#"{"channels": ["packets"]}"#).unwrap();
+        assert!(packets.authorize(None).is_ok());
+        assert!(packets.authorize(Some(TokenScope::Admin)).is_ok());
+        assert!(packets.authorize(Some(TokenScope::Read)).is_err());
+        assert!(subscription.authorize(Some(TokenScope::Read)).is_ok());
+    }
+
+    #[test]
+    fn test_filtered_updates() {
+        let traffic = TrafficState::new();
+        let mut session = Session::new(&traffic);
+        let subscription = Subscription::parse(
+            r#"{"channels": ["totals", "flows", "packets"], "filter": {"ip": "10.0.0.0/8", "port": 53}, "packet_sample": 2}"#,
+        )
+        .unwrap();
+        session.subscribe(subscription, &traffic);
+
+        let events = [
+            udp_event("10.0.0.1", "10.0.0.2", 53, 100),
+            udp_event("10.0.0.1", "10.0.0.2", 53, 200),
+            udp_event("10.0.0.1", "10.0.0.2", 53, 300),
+            udp_event("192.168.0.1", "192.168.0.2", 53, 1000),
+            udp_event("10.0.0.1", "10.0.0.2", 123, 1000),
+        ];
+        let mut sampled = Vec::new();
+        for event in &events {
+            traffic.update(&event.packet);
+            sampled.extend(session.on_packet(event));
+        }
+        // Every second matching packet
+        assert_eq!(sampled.len(), 2);
+        assert_eq!(sampled[1].message["length"], 300);
+
+        let messages: Vec<_> = session.update(&traffic).into_iter().map(|m| m.message).collect();
+        assert_eq!(messages[0]["type"], "totals");
+        assert_eq!(messages[0]["interval_bytes"], 600);
+        assert_eq!(messages[0]["total_packets"], 5);
+        assert_eq!(messages[1]["type"], "flows");
+        assert_eq!(messages[1]["flows"].as_array().unwrap().len(), 1);
+        assert_eq!(messages[1]["flows"][0]["interval_packets"], 3);
+
+        // The window starts over
+        let messages = session.update(&traffic);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message["interval_bytes"], 0);
+    }
+
+    #[test]
+    fn test_flow_events_and_drops() {
+        let traffic = TrafficState::new();
+        let mut events = traffic.subscribe();
+        let mut session = Session::new(&traffic);
+        session.subscribe(Subscription::parse(r#"{"channels": ["flow_start"], "filter": {"port": 53}}"#).unwrap(), &traffic);
+
+        traffic.update(&udp_event("10.0.0.1", "10.0.0.2", 53, 100).packet);
+        traffic.update(&udp_event("10.0.0.1", "10.0.0.2", 80, 100).packet);
+        let mut next = |id| Sequenced {
+            id,
+            event: events.try_recv().unwrap(),
+        };
+        let start = session.on_flow_event(&next(1)).unwrap();
+        assert_eq!((start.kind(), start.id), ("flow_start", Some(1)));
+        assert!(session.on_flow_event(&next(2)).is_none());
+
+        session.dropped = 7;
+        let messages = session.update(&traffic);
+        assert_eq!(messages[0].message, json!({ "type": "dropped", "events": 7 }));
+        assert!(session.update(&traffic).is_empty());
+    }
+}
@@ -1,13 +1,123 @@
-use serde::Deserialize;
+use crate::rollups::Resolution;
+use serde::{Deserialize, Deserializer};
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
 
+/// How fast an offline capture file is fed through the pipeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplaySpeed {
+    /// Read packets as fast as possible
+    #[default]
+    Fast,
+    /// Preserve the original spacing between packets
+    Realtime,
+}
+
+/// What a capture thread does when the storage writer's queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackpressurePolicy {
+    /// Wait for the writer, stalling the capture (the kernel may drop packets instead)
+    #[default]
+    Block,
+    /// Discard the newest packet from storage; live stats still count it
+    Drop,
+}
+
+/// Resolution of the packet timestamps requested from libpcap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampPrecision {
+    /// Microsecond timestamps (supported everywhere)
+    #[default]
+    Micro,
+    /// Nanosecond timestamps, where the platform and capture file support them
+    Nano,
+}
+
+/// Which records the Elasticsearch exporter sends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportDocuments {
+    /// Every sampled packet
+    Packets,
+    /// Ended flows
+    #[default]
+    Flows,
+    /// Packets and ended flows
+    Both,
+}
+
+impl ExportDocuments {
+    pub fn packets(&self) -> bool {
+        matches!(self, ExportDocuments::Packets | ExportDocuments::Both)
+    }
+
+    pub fn flows(&self) -> bool {
+        matches!(self, ExportDocuments::Flows | ExportDocuments::Both)
+    }
+}
+
+/// Wire format of the NetFlow exporter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NetflowVersion {
+    /// NetFlow v5: fixed IPv4-only records
+    V5,
+    /// NetFlow v9: template based, IPv4 and IPv6
+    #[default]
+    V9,
+    /// IPFIX (NetFlow v10)
+    Ipfix,
+}
+
+/// What an API token may do. Admin includes everything read allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Read-only requests
+    #[default]
+    Read,
+    /// Any request
+    Admin,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Admin => "admin",
+        }
+    }
+}
+
+/// A static API token. The secret is given inline, or read at startup from an
+/// environment variable or a file (e.g. a mounted Kubernetes secret); set exactly one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiToken {
+    /// Label used in logs (default: position in the list)
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub token_env: Option<String>,
+    #[serde(default)]
+    pub token_file: Option<String>,
+    #[serde(default)]
+    pub scope: TokenScope,
+}
+
 /// Application configuration, loadable from CLI or YAML file.
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
-    /// Network interface to capture on
-    #[serde(default)]
-    pub interface: Option<String>,
+    /// Network interfaces to capture on, one capture thread each ("any" captures on all).
+    /// Accepts a single name (`interface: eth0`) or a list (`interfaces: [eth0, docker0]`).
+    /// Empty = auto-detect the default device.
+    #[serde(default, alias = "interface", deserialize_with = "one_or_many")]
+    pub interfaces: Vec<String>,
 
     /// API server port
     #[serde(default = "default_port")]
@@ -23,12 +133,17 @@ pub struct Config {
 
     /// Filter by IP (only capture traffic to/from this IP)
     #[serde(default)]
-    pub filter_ip: Option<String>,
+    pub filter_ip: Option<IpAddr>,
 
     /// Filter by protocol (TCP, UDP)
     #[serde(default)]
     pub filter_protocol: Option<String>,
 
+    /// Kernel-side BPF filter expression (libpcap syntax). When unset, one is derived
+    /// from `filter_port`, `filter_ip` and `filter_protocol`.
+    #[serde(default)]
+    pub bpf_filter: Option<String>,
+
     /// Connection timeout in seconds (for stale connection cleanup)
     #[serde(default = "default_connection_timeout")]
     pub connection_timeout: u64,
@@ -56,6 +171,134 @@ pub struct Config {
     /// 0 = disabled (default), store every sampled packet individually.
     #[serde(default = "default_aggregation_window")]
     pub aggregation_window_seconds: u64,
+
+    /// What to do when the storage writer falls behind (block, drop)
+    #[serde(default)]
+    pub backpressure: BackpressurePolicy,
+
+    /// Replay a saved .pcap/.pcapng file instead of capturing live
+    #[serde(default)]
+    pub read_file: Option<String>,
+
+    /// Replay speed for `read_file` (fast, realtime)
+    #[serde(default)]
+    pub replay_speed: ReplaySpeed,
+
+    /// Packet timestamp precision requested from libpcap (micro, nano)
+    #[serde(default)]
+    pub timestamp_precision: TimestampPrecision,
+
+    /// Retention of the per-minute rollups in seconds (None = keep forever)
+    #[serde(default = "default_rollup_minute_retention")]
+    pub rollup_minute_retention_seconds: Option<u64>,
+
+    /// Retention of the per-hour rollups in seconds (None = keep forever)
+    #[serde(default = "default_rollup_hour_retention")]
+    pub rollup_hour_retention_seconds: Option<u64>,
+
+    /// Retention of the per-day rollups in seconds (None = keep forever)
+    #[serde(default)]
+    pub rollup_day_retention_seconds: Option<u64>,
+
+    /// Number of largest active conversations exported on `/metrics` (0 = none).
+    /// Each one adds a label set, so keep this small.
+    #[serde(default)]
+    pub metrics_top_flows: usize,
+
+    /// Elasticsearch/OpenSearch base URL to export to (None = export disabled)
+    #[serde(default)]
+    pub elasticsearch_url: Option<String>,
+
+    /// Index name prefix; documents go to `<prefix>-packets-YYYY.MM.DD` and `<prefix>-flows-YYYY.MM.DD`
+    #[serde(default = "default_elasticsearch_index_prefix")]
+    pub elasticsearch_index_prefix: String,
+
+    /// Records to export (packets, flows, both)
+    #[serde(default)]
+    pub elasticsearch_export: ExportDocuments,
+
+    /// API key sent as `Authorization: ApiKey <key>`
+    #[serde(default)]
+    pub elasticsearch_api_key: Option<String>,
+
+    /// Documents per bulk request
+    #[serde(default = "default_elasticsearch_batch_size")]
+    pub elasticsearch_batch_size: usize,
+
+    /// Seconds between bulk requests when batches do not fill up
+    #[serde(default = "default_elasticsearch_flush_interval")]
+    pub elasticsearch_flush_interval_seconds: u64,
+
+    /// Directory for batches that could not be delivered (None = drop them)
+    #[serde(default)]
+    pub elasticsearch_spool_dir: Option<String>,
+
+    /// Size limit of the spool directory; the oldest batches are deleted beyond it
+    #[serde(default = "default_elasticsearch_spool_max_bytes")]
+    pub elasticsearch_spool_max_bytes: u64,
+
+    /// NetFlow/IPFIX collectors (`host:port`) to export flows to (empty = export disabled)
+    #[serde(default, alias = "netflow_collector", deserialize_with = "one_or_many")]
+    pub netflow_collectors: Vec<String>,
+
+    /// NetFlow export format (v5, v9, ipfix)
+    #[serde(default)]
+    pub netflow_version: NetflowVersion,
+
+    /// Long-lived flows are exported every this many seconds while they last
+    #[serde(default = "default_netflow_active_timeout")]
+    pub netflow_active_timeout_seconds: u64,
+
+    /// Source ID (v9) or observation domain ID (IPFIX) in exported packets
+    #[serde(default)]
+    pub netflow_source_id: u32,
+
+    /// Receive NetFlow/IPFIX/sFlow on this address (`udp://host:port`) instead of capturing packets
+    #[serde(default)]
+    pub collector: Option<String>,
+
+    /// IP addresses and CIDR blocks allowed to use the API (empty = everyone)
+    #[serde(default)]
+    pub api_allowlist: Vec<String>,
+
+    /// Proxies (addresses or CIDR blocks) whose `X-Forwarded-For` header is believed
+    /// when checking `api_allowlist`
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// Tokens accepted by the API (empty = no authentication)
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+
+    /// PEM certificate chain to serve the API over HTTPS with (requires `tls_key`)
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key for `tls_cert`
+    #[serde(default)]
+    pub tls_key: Option<String>,
+
+    /// PEM CA certificates; when set, clients must present a certificate signed by one (mTLS)
+    #[serde(default)]
+    pub tls_client_ca: Option<String>,
+}
+
+/// Accept either a single string or a list of strings
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    })
 }
 
 fn default_port() -> u16 {
@@ -82,26 +325,91 @@ fn default_aggregation_window() -> u64 {
     0
 }
 
+fn default_rollup_minute_retention() -> Option<u64> {
+    Some(2 * 86_400)
+}
+
+fn default_rollup_hour_retention() -> Option<u64> {
+    Some(60 * 86_400)
+}
+
+fn default_elasticsearch_index_prefix() -> String {
+    "lightshark".to_string()
+}
+
+fn default_elasticsearch_batch_size() -> usize {
+    1000
+}
+
+fn default_elasticsearch_flush_interval() -> u64 {
+    5
+}
+
+fn default_elasticsearch_spool_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_netflow_active_timeout() -> u64 {
+    60
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
-            interface: None,
+            interfaces: Vec::new(),
             port: default_port(),
             db_path: default_db_path(),
             filter_port: None,
             filter_ip: None,
             filter_protocol: None,
+            bpf_filter: None,
             connection_timeout: default_connection_timeout(),
             resolve_dns: false,
             quiet: false,
             data_retention_seconds: default_data_retention(),
             sample_rate: default_sample_rate(),
             aggregation_window_seconds: default_aggregation_window(),
+            backpressure: BackpressurePolicy::default(),
+            read_file: None,
+            replay_speed: ReplaySpeed::default(),
+            timestamp_precision: TimestampPrecision::default(),
+            rollup_minute_retention_seconds: default_rollup_minute_retention(),
+            rollup_hour_retention_seconds: default_rollup_hour_retention(),
+            rollup_day_retention_seconds: None,
+            metrics_top_flows: 0,
+            elasticsearch_url: None,
+            elasticsearch_index_prefix: default_elasticsearch_index_prefix(),
+            elasticsearch_export: ExportDocuments::default(),
+            elasticsearch_api_key: None,
+            elasticsearch_batch_size: default_elasticsearch_batch_size(),
+            elasticsearch_flush_interval_seconds: default_elasticsearch_flush_interval(),
+            elasticsearch_spool_dir: None,
+            elasticsearch_spool_max_bytes: default_elasticsearch_spool_max_bytes(),
+            netflow_collectors: Vec::new(),
+            netflow_version: NetflowVersion::default(),
+            netflow_active_timeout_seconds: default_netflow_active_timeout(),
+            netflow_source_id: 0,
+            collector: None,
+            api_allowlist: Vec::new(),
+            trusted_proxies: Vec::new(),
+            api_tokens: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
         }
     }
 }
 
 impl Config {
+    /// Retention configured for a rollup resolution
+    pub fn rollup_retention(&self, resolution: Resolution) -> Option<u64> {
+        match resolution {
+            Resolution::Minute => self.rollup_minute_retention_seconds,
+            Resolution::Hour => self.rollup_hour_retention_seconds,
+            Resolution::Day => self.rollup_day_retention_seconds,
+        }
+    }
+
     /// Load config from a YAML file
     pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
         let content = fs::read_to_string(path)?;
@@ -111,8 +419,8 @@ impl Config {
 
     /// Merge CLI args into config (CLI takes precedence)
     pub fn merge_cli(&mut self, cli: &CliArgs) {
-        if cli.interface.is_some() {
-            self.interface = cli.interface.clone();
+        if !cli.interface.is_empty() {
+            self.interfaces = cli.interface.clone();
         }
         if cli.port != 3000 {
             self.port = cli.port;
@@ -124,11 +432,14 @@ impl Config {
             self.filter_port = cli.filter_port;
         }
         if cli.filter_ip.is_some() {
-            self.filter_ip = cli.filter_ip.clone();
+            self.filter_ip = cli.filter_ip;
         }
         if cli.filter_protocol.is_some() {
             self.filter_protocol = cli.filter_protocol.clone();
         }
+        if cli.bpf.is_some() {
+            self.bpf_filter = cli.bpf.clone();
+        }
         if cli.connection_timeout != 60 {
             self.connection_timeout = cli.connection_timeout;
         }
@@ -147,6 +458,60 @@ impl Config {
         if cli.aggregation_window != 0 {
             self.aggregation_window_seconds = cli.aggregation_window;
         }
+        if let Some(policy) = cli.backpressure {
+            self.backpressure = policy;
+        }
+        if cli.read_file.is_some() {
+            self.read_file = cli.read_file.clone();
+        }
+        if let Some(speed) = cli.replay_speed {
+            self.replay_speed = speed;
+        }
+        if let Some(precision) = cli.timestamp_precision {
+            self.timestamp_precision = precision;
+        }
+        if cli.metrics_top_flows != 0 {
+            self.metrics_top_flows = cli.metrics_top_flows;
+        }
+        if cli.elasticsearch_url.is_some() {
+            self.elasticsearch_url = cli.elasticsearch_url.clone();
+        }
+        if let Some(ref prefix) = cli.elasticsearch_index_prefix {
+            self.elasticsearch_index_prefix = prefix.clone();
+        }
+        if let Some(documents) = cli.elasticsearch_export {
+            self.elasticsearch_export = documents;
+        }
+        if cli.elasticsearch_spool_dir.is_some() {
+            self.elasticsearch_spool_dir = cli.elasticsearch_spool_dir.clone();
+        }
+        if !cli.netflow_collector.is_empty() {
+            self.netflow_collectors = cli.netflow_collector.clone();
+        }
+        if let Some(version) = cli.netflow_version {
+            self.netflow_version = version;
+        }
+        if let Some(timeout) = cli.netflow_active_timeout {
+            self.netflow_active_timeout_seconds = timeout;
+        }
+        if cli.collector.is_some() {
+            self.collector = cli.collector.clone();
+        }
+        if !cli.api_allowlist.is_empty() {
+            self.api_allowlist = cli.api_allowlist.clone();
+        }
+        if !cli.trusted_proxy.is_empty() {
+            self.trusted_proxies = cli.trusted_proxy.clone();
+        }
+        if cli.tls_cert.is_some() {
+            self.tls_cert = cli.tls_cert.clone();
+        }
+        if cli.tls_key.is_some() {
+            self.tls_key = cli.tls_key.clone();
+        }
+        if cli.tls_client_ca.is_some() {
+            self.tls_client_ca = cli.tls_client_ca.clone();
+        }
     }
 }
 
@@ -156,9 +521,10 @@ use clap::Parser;
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 pub struct CliArgs {
-    /// Network interface to capture on (e.g., eth0). Auto-detects if not provided.
-    #[arg(short, long)]
-    pub interface: Option<String>,
+    /// Network interface to capture on (e.g., eth0). Repeat or comma-separate for several,
+    /// "any" captures on all. Auto-detects if not provided.
+    #[arg(short, long, value_delimiter = ',')]
+    pub interface: Vec<String>,
 
     /// Port to serve the API on
     #[arg(short, long, default_value_t = 3000)]
@@ -178,12 +544,16 @@ pub struct CliArgs {
 
     /// Filter: only capture traffic to/from this IP
     #[arg(long)]
-    pub filter_ip: Option<String>,
+    pub filter_ip: Option<IpAddr>,
 
     /// Filter: only capture this protocol (TCP, UDP)
     #[arg(long)]
     pub filter_protocol: Option<String>,
 
+    /// Kernel-side BPF filter expression, e.g. "tcp port 443"
+    #[arg(long)]
+    pub bpf: Option<String>,
+
     /// Connection timeout in seconds for stale cleanup
     #[arg(long, default_value_t = 60)]
     pub connection_timeout: u64,
@@ -207,4 +577,81 @@ pub struct CliArgs {
     /// Aggregation window in seconds (0 = disabled, store raw packets)
     #[arg(long, default_value_t = 0)]
     pub aggregation_window: u64,
+
+    /// When the storage writer falls behind: block the capture or drop packets (block, drop)
+    #[arg(long, value_enum)]
+    pub backpressure: Option<BackpressurePolicy>,
+
+    /// Replay a .pcap/.pcapng file instead of capturing from an interface
+    #[arg(long)]
+    pub read_file: Option<String>,
+
+    /// Replay speed for --read-file (fast, realtime)
+    #[arg(long, value_enum)]
+    pub replay_speed: Option<ReplaySpeed>,
+
+    /// Packet timestamp precision (micro, nano)
+    #[arg(long, value_enum)]
+    pub timestamp_precision: Option<TimestampPrecision>,
+
+    /// Export the N largest active conversations on /metrics (0 = disabled)
+    #[arg(long, default_value_t = 0)]
+    pub metrics_top_flows: usize,
+
+    /// Export to this Elasticsearch/OpenSearch URL, e.g. http://localhost:9200
+    #[arg(long)]
+    pub elasticsearch_url: Option<String>,
+
+    /// Index name prefix for the Elasticsearch exporter (default: lightshark)
+    #[arg(long)]
+    pub elasticsearch_index_prefix: Option<String>,
+
+    /// Records to export to Elasticsearch (packets, flows, both)
+    #[arg(long, value_enum)]
+    pub elasticsearch_export: Option<ExportDocuments>,
+
+    /// Keep undeliverable Elasticsearch batches in this directory until the cluster is back
+    #[arg(long)]
+    pub elasticsearch_spool_dir: Option<String>,
+
+    /// Export flows to this NetFlow/IPFIX collector (host:port). Repeat or comma-separate for several.
+    #[arg(long, value_delimiter = ',')]
+    pub netflow_collector: Vec<String>,
+
+    /// NetFlow export format (v5, v9, ipfix)
+    #[arg(long, value_enum)]
+    pub netflow_version: Option<NetflowVersion>,
+
+    /// Export long-lived flows every N seconds (default: 60)
+    #[arg(long)]
+    pub netflow_active_timeout: Option<u64>,
+
+    /// Collect NetFlow v5/v9, IPFIX and sFlow v5 on this address instead of capturing,
+    /// e.g. udp://0.0.0.0:2055
+    #[arg(long)]
+    pub collector: Option<String>,
+
+    /// Only serve the API to these IPs/CIDR blocks. Repeat or comma-separate for several.
+    #[arg(long, value_delimiter = ',')]
+    pub api_allowlist: Vec<String>,
+
+    /// Trust X-Forwarded-For from these proxy IPs/CIDR blocks. Repeat or comma-separate for several.
+    #[arg(long, value_delimiter = ',')]
+    pub trusted_proxy: Vec<String>,
+
+    /// Serve the API over HTTPS with this PEM certificate chain (reloaded when it changes)
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<String>,
+
+    /// Require client certificates signed by this PEM CA (mutual TLS)
+    #[arg(long)]
+    pub tls_client_ca: Option<String>,
+
+    /// Migrate the database schema to the current version and exit without capturing
+    #[arg(long)]
+    pub migrate_only: bool,
 }
@@ -0,0 +1,690 @@
+mod spool;
+
+use crate::config::{Config, ExportDocuments};
+use crate::health::ExportStats;
+use crate::state::{FlowEvent, PacketMetadata};
+use crate::storage::FlowRecord;
+use serde::Serialize;
+use spool::{Pushed, Spool};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{interval, Duration, Instant};
+
+/// How long the cluster is left alone after a failed request; doubles with every further failure
+const RETRY_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Spooled batches resent per flush, so a long outage does not starve new data
+const SPOOL_DRAIN_BATCHES: usize = 10;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ships packets and ended flows to Elasticsearch or OpenSearch through the `_bulk` API.
+/// Documents go to one index per kind and day, e.g. `lightshark-flows-2024.03.15`, and
+/// index templates installed before the first write map addresses as `ip` and times as
+/// `date`. While the cluster is unavailable, batches are spooled right away and delivery is
+/// retried with backoff, so the export loop keeps draining its channels.
+pub struct Exporter {
+    client: reqwest::Client,
+    url: String,
+    index_prefix: String,
+    documents: ExportDocuments,
+    api_key: Option<String>,
+    batch_size: usize,
+    flush_interval: Duration,
+    spool: Option<Spool>,
+    stats: Arc<ExportStats>,
+    templates_installed: bool,
+    backoff_min: Duration,
+    /// Wait after the next failure
+    backoff: Duration,
+    /// No requests are made before this time after a failure
+    down_until: Option<Instant>,
+    /// NDJSON body of the next bulk request and the number of documents in it
+    batch: String,
+    batch_docs: usize,
+}
+
+/// Source document with the `@timestamp` field Kibana and OpenSearch Dashboards expect
+#[derive(Serialize)]
+struct Document<'a, T: Serialize> {
+    #[serde(rename = "@timestamp")]
+    timestamp_ms: i64,
+    #[serde(flatten)]
+    source: &'a T,
+}
+
+/// Outcome of one bulk request that reached the cluster
+struct BulkResult {
+    accepted: u64,
+    rejected: u64,
+    /// Documents refused with a retryable status (429 or 5xx), as a new bulk body
+    retry: Option<String>,
+}
+
+/// Why a bulk request failed as a whole
+enum BulkError {
+    /// The cluster could not be reached or cannot take requests right now
+    Unavailable(String),
+    /// The cluster refused this request (e.g. `400`, `413`) and would refuse it again
+    Refused(String),
+}
+
+/// Outcome of sending one bulk body
+enum Delivery {
+    /// Every document was indexed or rejected
+    Done,
+    /// Documents refused with a retryable status, as a new bulk body
+    Retry(String),
+    /// Nothing was delivered; keep the whole body
+    Unavailable,
+}
+
+impl Exporter {
+    pub fn new(url: &str, config: &Config) -> std::io::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(std::io::Error::other)?;
+        let stats = Arc::new(ExportStats::default());
+        let spool = match config.elasticsearch_spool_dir {
+            Some(ref dir) => {
+                let spool = Spool::open(Path::new(dir), config.elasticsearch_spool_max_bytes)?;
+                stats.spool_bytes.store(spool.size_bytes()?, Ordering::Relaxed);
+                Some(spool)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            index_prefix: config.elasticsearch_index_prefix.clone(),
+            documents: config.elasticsearch_export,
+            api_key: config.elasticsearch_api_key.clone(),
+            batch_size: config.elasticsearch_batch_size.max(1),
+            flush_interval: Duration::from_secs(config.elasticsearch_flush_interval_seconds.max(1)),
+            spool,
+            stats,
+            templates_installed: false,
+            backoff_min: RETRY_BACKOFF_MIN,
+            backoff: RETRY_BACKOFF_MIN,
+            down_until: None,
+            batch: String::new(),
+            batch_docs: 0,
+        })
+    }
+
+    pub fn stats(&self) -> Arc<ExportStats> {
+        self.stats.clone()
+    }
+
+    /// Which documents this exporter wants; the caller only wires up those channels
+    pub fn documents(&self) -> ExportDocuments {
+        self.documents
+    }
+
+    /// Export loop. `packets` carries the sampled packets from the capture threads and
+    /// `flows` the flow events; either may be `None` when that kind is not exported.
+    pub async fn run(
+        mut self,
+        mut packets: Option<Receiver<PacketMetadata>>,
+        mut flows: Option<broadcast::Receiver<FlowEvent>>,
+    ) {
+        let mut ticker = interval(self.flush_interval);
+
+        loop {
+            tokio::select! {
+                Some(packet) = recv_packet(&mut packets) => {
+                    self.push_packet(&packet);
+                    if self.batch_docs >= self.batch_size {
+                        self.flush().await;
+                    }
+                }
+                event = recv_flow_event(&mut flows) => match event {
+                    Ok(FlowEvent::End(summary)) => {
+                        self.push_flow(&FlowRecord::from(&summary));
+                        if self.batch_docs >= self.batch_size {
+                            self.flush().await;
+                        }
+                    }
+                    Ok(FlowEvent::Start { .. }) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        eprintln!("Elasticsearch exporter fell behind, {} flow records lost", missed);
+                        self.stats.dropped.fetch_add(missed, Ordering::Relaxed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => flows = None,
+                },
+                _ = ticker.tick() => self.flush().await,
+            }
+        }
+    }
+
+    fn push_packet(&mut self, packet: &PacketMetadata) {
+        self.push("packets", packet.timestamp, packet);
+    }
+
+    fn push_flow(&mut self, record: &FlowRecord) {
+        self.push("flows", record.end_ns / 1_000_000, record);
+    }
+
+    /// Append an index action and its document to the pending bulk body
+    fn push<T: Serialize>(&mut self, kind: &str, timestamp_ms: i64, source: &T) {
+        let day = chrono::DateTime::from_timestamp_millis(timestamp_ms)
+            .unwrap_or_default()
+            .format("%Y.%m.%d");
+        let document = Document { timestamp_ms, source };
+        match serde_json::to_string(&document) {
+            Ok(document) => {
+                let _ = writeln!(
+                    self.batch,
+                    "{{\"index\":{{\"_index\":\"{}-{}-{}\"}}}}",
+                    self.index_prefix, kind, day
+                );
+                self.batch.push_str(&document);
+                self.batch.push('\n');
+                self.batch_docs += 1;
+            }
+            Err(e) => eprintln!("Failed to encode {} document: {}", kind, e),
+        }
+    }
+
+    /// Send the pending batch, spooling it if the cluster is unavailable, then resend
+    /// spooled batches while the cluster is up
+    async fn flush(&mut self) {
+        if self.batch_docs > 0 {
+            let body = std::mem::take(&mut self.batch);
+            self.batch_docs = 0;
+            let delivery = if self.is_down() {
+                Delivery::Unavailable
+            } else {
+                self.deliver(&body).await
+            };
+            match delivery {
+                Delivery::Done => {}
+                Delivery::Retry(rest) => self.spool_batch(&rest),
+                Delivery::Unavailable => self.spool_batch(&body),
+            }
+        }
+        if !self.is_down() {
+            self.drain_spool().await;
+        }
+    }
+
+    fn is_down(&self) -> bool {
+        self.down_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Leave the cluster alone for a while, waiting longer after each consecutive failure
+    fn mark_down(&mut self) {
+        self.down_until = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(RETRY_BACKOFF_MAX);
+    }
+
+    fn mark_up(&mut self) {
+        self.down_until = None;
+        self.backoff = self.backoff_min;
+    }
+
+    fn spool_batch(&mut self, body: &str) {
+        let Some(spool) = self.spool.as_mut() else {
+            eprintln!("Elasticsearch unreachable, dropping {} documents", count_documents(body));
+            self.stats.dropped.fetch_add(count_documents(body), Ordering::Relaxed);
+            return;
+        };
+        match spool.push(body) {
+            Ok(Pushed::Stored(evicted)) => {
+                self.stats.spooled_batches.fetch_add(1, Ordering::Relaxed);
+                for batch in evicted {
+                    self.stats.dropped.fetch_add(count_documents(&batch), Ordering::Relaxed);
+                }
+            }
+            Ok(Pushed::TooLarge) => {
+                eprintln!("Elasticsearch batch larger than the spool, dropping {} documents", count_documents(body));
+                self.stats.dropped.fetch_add(count_documents(body), Ordering::Relaxed);
+            }
+            Err(e) => {
+                eprintln!("Failed to spool Elasticsearch batch: {}", e);
+                self.stats.dropped.fetch_add(count_documents(body), Ordering::Relaxed);
+            }
+        }
+        self.update_spool_size();
+    }
+
+    async fn drain_spool(&mut self) {
+        for _ in 0..SPOOL_DRAIN_BATCHES {
+            let batch = match self.spool.as_ref().map(Spool::oldest) {
+                Some(Ok(Some(batch))) => batch,
+                Some(Err(e)) => {
+                    eprintln!("Failed to read Elasticsearch spool: {}", e);
+                    break;
+                }
+                _ => break,
+            };
+            let delivery = self.deliver(&batch.body).await;
+            if let Delivery::Unavailable = delivery {
+                break;
+            }
+            if let Some(Err(e)) = self.spool.as_ref().map(|spool| spool.remove(batch)) {
+                eprintln!("Failed to remove delivered Elasticsearch batch: {}", e);
+                break;
+            }
+            // Documents refused with a retryable status go back to the end of the spool
+            match delivery {
+                Delivery::Retry(rest) => self.spool_batch(&rest),
+                _ => self.update_spool_size(),
+            }
+            if self.is_down() {
+                break;
+            }
+        }
+    }
+
+    fn update_spool_size(&self) {
+        if let Some(Ok(size)) = self.spool.as_ref().map(Spool::size_bytes) {
+            self.stats.spool_bytes.store(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Send a bulk body once. Failures and retryable document statuses put the cluster
+    /// in backoff; a request refused as a whole counts its documents as rejected.
+    async fn deliver(&mut self, body: &str) -> Delivery {
+        match self.send_bulk(body).await {
+            Ok(result) => {
+                self.stats.exported.fetch_add(result.accepted, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(result.rejected, Ordering::Relaxed);
+                match result.retry {
+                    // The cluster is overloaded (429) or partly failing (5xx)
+                    Some(retry) => {
+                        self.mark_down();
+                        Delivery::Retry(retry)
+                    }
+                    None => {
+                        self.mark_up();
+                        Delivery::Done
+                    }
+                }
+            }
+            Err(BulkError::Unavailable(e)) => {
+                self.stats.failed_requests.fetch_add(1, Ordering::Relaxed);
+                self.mark_down();
+                eprintln!("Elasticsearch export failed, retrying in {:?}: {}", self.backoff, e);
+                Delivery::Unavailable
+            }
+            Err(BulkError::Refused(e)) => {
+                let documents = count_documents(body);
+                self.stats.failed_requests.fetch_add(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(documents, Ordering::Relaxed);
+                eprintln!("Elasticsearch refused a batch of {} documents: {}", documents, e);
+                Delivery::Done
+            }
+        }
+    }
+
+    async fn send_bulk(&mut self, body: &str) -> Result<BulkResult, BulkError> {
+        if !self.templates_installed {
+            self.install_templates().await.map_err(BulkError::Unavailable)?;
+            self.templates_installed = true;
+        }
+
+        let response = self
+            .request(reqwest::Method::POST, "_bulk")
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| BulkError::Unavailable(e.to_string()))?;
+        let status = response.status();
+        let bytes = response.bytes().await.map_err(|e| BulkError::Unavailable(e.to_string()))?;
+        if !status.is_success() {
+            let error = format!("bulk request returned {}", status);
+            // Credentials can be fixed without touching the data, so those batches are kept
+            return Err(match status.as_u16() {
+                401 | 403 | 408 | 429 => BulkError::Unavailable(error),
+                400..=499 => BulkError::Refused(error),
+                _ => BulkError::Unavailable(error),
+            });
+        }
+        let response: serde_json::Value = serde_json::from_slice(&bytes)
+            .map_err(|e| BulkError::Unavailable(format!("invalid bulk response: {}", e)))?;
+        Ok(bulk_result(body, &response))
+    }
+
+    /// Create or update the index templates for packet and flow indices
+    async fn install_templates(&self) -> Result<(), String> {
+        for (kind, properties) in [("packets", packet_mappings()), ("flows", flow_mappings())] {
+            let name = format!("{}-{}", self.index_prefix, kind);
+            let template = serde_json::json!({
+                "index_patterns": [format!("{}-*", name)],
+                "priority": 100,
+                "template": {
+                    "mappings": { "properties": properties }
+                }
+            });
+            let response = self
+                .request(reqwest::Method::PUT, &format!("_index_template/{}", name))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(template.to_string())
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("index template {} returned {}", name, response.status()));
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}/{}", self.url, path));
+        match self.api_key {
+            Some(ref key) => request.header(reqwest::header::AUTHORIZATION, format!("ApiKey {}", key)),
+            None => request,
+        }
+    }
+}
+
+async fn recv_packet(rx: &mut Option<Receiver<PacketMetadata>>) -> Option<PacketMetadata> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv_flow_event(
+    rx: &mut Option<broadcast::Receiver<FlowEvent>>,
+) -> Result<FlowEvent, broadcast::error::RecvError> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Documents in a bulk body: every document follows its action line
+fn count_documents(body: &str) -> u64 {
+    body.lines().count() as u64 / 2
+}
+
+/// Match the per-item statuses of a bulk response to the request's lines
+fn bulk_result(body: &str, response: &serde_json::Value) -> BulkResult {
+    let mut result = BulkResult {
+        accepted: 0,
+        rejected: 0,
+        retry: None,
+    };
+    let items = response["items"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let lines: Vec<&str> = body.lines().collect();
+    let mut retry = String::new();
+
+    for (item, pair) in items.iter().zip(lines.chunks(2)) {
+        let status = item["index"]["status"].as_u64().unwrap_or(0);
+        match status {
+            200..=299 => result.accepted += 1,
+            429 | 500..=599 => {
+                for line in pair {
+                    retry.push_str(line);
+                    retry.push('\n');
+                }
+            }
+            _ => {
+                eprintln!("Elasticsearch rejected document: {}", item["index"]["error"]);
+                result.rejected += 1;
+            }
+        }
+    }
+    if !retry.is_empty() {
+        result.retry = Some(retry);
+    }
+    result
+}
+
+fn packet_mappings() -> serde_json::Value {
+    serde_json::json!({
+        "@timestamp": { "type": "date", "format": "epoch_millis" },
+        "timestamp": { "type": "date", "format": "epoch_millis" },
+        "timestamp_ns": { "type": "long" },
+        "interface": { "type": "keyword" },
+        "src_ip": { "type": "ip" },
+        "dst_ip": { "type": "ip" },
+        "src_port": { "type": "integer" },
+        "dst_port": { "type": "integer" },
+        "protocol": { "type": "keyword" },
+        "ip_protocol": { "type": "short" },
+        "vlan": { "type": "integer" },
+        "length": { "type": "long" },
+        "tcp_flags": { "type": "short" }
+    })
+}
+
+fn flow_mappings() -> serde_json::Value {
+    serde_json::json!({
+        "@timestamp": { "type": "date", "format": "epoch_millis" },
+        "kind": { "type": "keyword" },
+        "start_ns": { "type": "long" },
+        "end_ns": { "type": "long" },
+        "duration_ms": { "type": "long" },
+        "interface": { "type": "keyword" },
+        "vlan": { "type": "integer" },
+        "src_ip": { "type": "ip" },
+        "dst_ip": { "type": "ip" },
+        "src_port": { "type": "integer" },
+        "dst_port": { "type": "integer" },
+        "protocol": { "type": "keyword" },
+        "ip_protocol": { "type": "short" },
+        "bytes_sent": { "type": "long" },
+        "bytes_received": { "type": "long" },
+        "packets_sent": { "type": "long" },
+        "packets_received": { "type": "long" },
+        "state": { "type": "keyword" },
+        "close_reason": { "type": "keyword" }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Protocol, IPPROTO_TCP};
+    use crate::storage::FlowKind;
+    use crate::testing;
+    use axum::extract::{Path as UrlPath, State};
+    use axum::http::StatusCode;
+    use axum::routing::{post, put};
+    use axum::{Json, Router};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Mutex;
+
+    /// Stand-in for a cluster: records requests, fails the first `unavailable` bulk
+    /// requests, refuses whole requests containing a document with source port 13 and
+    /// rejects documents with source port 9
+    #[derive(Default)]
+    struct MockCluster {
+        templates: Mutex<Vec<String>>,
+        bulk_bodies: Mutex<Vec<String>>,
+        bulk_requests: AtomicU64,
+        unavailable: AtomicU64,
+    }
+
+    async fn mock_cluster() -> (String, Arc<MockCluster>) {
+        let cluster = Arc::new(MockCluster::default());
+        let app = Router::new()
+            .route(
+                "/_index_template/:name",
+                put(|State(cluster): State<Arc<MockCluster>>, UrlPath(name): UrlPath<String>| async move {
+                    cluster.templates.lock().unwrap().push(name);
+                    Json(serde_json::json!({ "acknowledged": true }))
+                }),
+            )
+            .route(
+                "/_bulk",
+                post(|State(cluster): State<Arc<MockCluster>>, body: String| async move {
+                    cluster.bulk_requests.fetch_add(1, Ordering::Relaxed);
+                    let unavailable = cluster.unavailable.load(Ordering::Relaxed);
+                    if unavailable > 0 {
+                        cluster.unavailable.store(unavailable - 1, Ordering::Relaxed);
+                        return Err(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                    if body.contains(r#""src_port":13,"#) {
+                        return Err(StatusCode::BAD_REQUEST);
+                    }
+                    let items: Vec<_> = body
+                        .lines()
+                        .skip(1)
+                        .step_by(2)
+                        .map(|doc| {
+                            let doc: serde_json::Value = serde_json::from_str(doc).unwrap();
+                            let status = if doc["src_port"] == 9 { 400 } else { 201 };
+                            serde_json::json!({ "index": { "status": status } })
+                        })
+                        .collect();
+                    cluster.bulk_bodies.lock().unwrap().push(body);
+                    Ok(Json(serde_json::json!({ "errors": true, "items": items })))
+                }),
+            )
+            .with_state(cluster.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, cluster)
+    }
+
+    fn packet(src_port: u16) -> PacketMetadata {
+        PacketMetadata {
+            // 2024-03-15T12:00:00Z
+            timestamp: 1_710_504_000_000,
+            timestamp_ns: 1_710_504_000_000_000_000,
+            ..testing::packet(Protocol::Tcp, "10.0.0.1", src_port, "10.0.0.2", 443, 60)
+        }
+    }
+
+    const BACKOFF: Duration = Duration::from_millis(50);
+
+    fn spool_config(name: &str) -> (Config, std::path::PathBuf) {
+        let dir = testing::temp_path(&format!("es-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config {
+            elasticsearch_spool_dir: Some(dir.to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        (config, dir)
+    }
+
+    fn exporter(url: &str, config: &Config) -> Exporter {
+        let mut exporter = Exporter::new(url, config).unwrap();
+        exporter.backoff_min = BACKOFF;
+        exporter.backoff = BACKOFF;
+        exporter
+    }
+
+    #[tokio::test]
+    async fn test_bulk_export() {
+        let (url, cluster) = mock_cluster().await;
+        let config = Config {
+            elasticsearch_export: ExportDocuments::Both,
+            ..Config::default()
+        };
+        let mut exporter = exporter(&url, &config);
+
+        exporter.push_packet(&packet(40000));
+        exporter.push_packet(&packet(9));
+        exporter.push_flow(&FlowRecord {
+            kind: FlowKind::Flow,
+            // 2024-03-16T00:00:01Z
+            start_ns: 1_710_547_200_000_000_000,
+            end_ns: 1_710_547_201_000_000_000,
+            duration_ms: 1000,
+            interface: Arc::from("eth0"),
+            vlan: Some(10),
+            src_ip: "10.0.0.1".parse().unwrap(),
+            dst_ip: "10.0.0.2".parse().unwrap(),
+            src_port: 40000,
+            dst_port: 443,
+            protocol: Protocol::Tcp,
+            ip_protocol: IPPROTO_TCP,
+            bytes_sent: 100,
+            bytes_received: 200,
+            packets_sent: 2,
+            packets_received: 3,
+            state: Some("closed".into()),
+            close_reason: Some("fin".into()),
+        });
+        exporter.flush().await;
+
+        assert_eq!(
+            *cluster.templates.lock().unwrap(),
+            vec!["lightshark-packets", "lightshark-flows"]
+        );
+        let bodies = cluster.bulk_bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 1);
+        let lines: Vec<&str> = bodies[0].lines().collect();
+        assert_eq!(lines[0], r#"{"index":{"_index":"lightshark-packets-2024.03.15"}}"#);
+        assert_eq!(lines[4], r#"{"index":{"_index":"lightshark-flows-2024.03.16"}}"#);
+        let doc: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(doc["@timestamp"], 1_710_504_000_000i64);
+        assert_eq!(doc["src_ip"], "10.0.0.1");
+
+        // The rejected document is counted, not retried
+        assert_eq!(exporter.stats.exported.load(Ordering::Relaxed), 2);
+        assert_eq!(exporter.stats.rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_spool_while_unreachable() {
+        let (url, cluster) = mock_cluster().await;
+        let (config, dir) = spool_config("outage");
+        let mut exporter = exporter(&url, &config);
+
+        cluster.unavailable.store(u64::MAX, Ordering::Relaxed);
+        exporter.push_packet(&packet(40000));
+        exporter.flush().await;
+        assert_eq!(exporter.stats.failed_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(exporter.stats.spooled_batches.load(Ordering::Relaxed), 1);
+        assert!(exporter.stats.spool_bytes.load(Ordering::Relaxed) > 0);
+
+        // During the backoff new batches go straight to the spool without a request
+        exporter.push_packet(&packet(40001));
+        exporter.flush().await;
+        assert_eq!(cluster.bulk_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(exporter.stats.spooled_batches.load(Ordering::Relaxed), 2);
+
+        // Once the cluster is back the spooled batches are delivered on the next flush
+        cluster.unavailable.store(0, Ordering::Relaxed);
+        tokio::time::sleep(BACKOFF).await;
+        exporter.flush().await;
+        assert_eq!(exporter.stats.exported.load(Ordering::Relaxed), 2);
+        assert_eq!(exporter.stats.spool_bytes.load(Ordering::Relaxed), 0);
+        assert_eq!(cluster.bulk_bodies.lock().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_batch_leaves_spool() {
+        let (url, cluster) = mock_cluster().await;
+        let (config, dir) = spool_config("refused");
+        let mut exporter = exporter(&url, &config);
+
+        cluster.unavailable.store(1, Ordering::Relaxed);
+        exporter.push_packet(&packet(13));
+        exporter.push_packet(&packet(40000));
+        exporter.flush().await;
+        exporter.push_packet(&packet(40001));
+        exporter.flush().await;
+        assert_eq!(exporter.stats.spooled_batches.load(Ordering::Relaxed), 2);
+
+        // The refused batch is counted as rejected and deleted, so the next one goes through
+        tokio::time::sleep(BACKOFF).await;
+        exporter.flush().await;
+        assert_eq!(exporter.stats.rejected.load(Ordering::Relaxed), 2);
+        assert_eq!(exporter.stats.exported.load(Ordering::Relaxed), 1);
+        assert_eq!(exporter.stats.spool_bytes.load(Ordering::Relaxed), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const EXTENSION: &str = "ndjson";
+
+/// Bulk request bodies that could not be delivered, kept on disk until the cluster is
+/// reachable again. One file per batch, named by a sequence number so they are resent
+/// in order. When the directory grows past `max_bytes` the oldest batches are deleted.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    next_seq: u64,
+}
+
+/// What became of a batch passed to `Spool::push`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pushed {
+    /// The batch was stored. Holds the bodies of older batches deleted to stay under the
+    /// size limit.
+    Stored(Vec<String>),
+    /// The batch alone exceeds the size limit and was not stored
+    TooLarge,
+}
+
+/// A spooled batch, oldest first
+pub struct SpooledBatch {
+    path: PathBuf,
+    pub body: String,
+}
+
+impl Spool {
+    /// Open (and create if needed) a spool directory. Batches left by a previous run are kept.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut spool = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            next_seq: 0,
+        };
+        spool.next_seq = spool.files()?.last().map_or(0, |(seq, _, _)| seq + 1);
+        Ok(spool)
+    }
+
+    /// Store a batch, deleting the oldest ones if the directory grows past the limit
+    pub fn push(&mut self, body: &str) -> io::Result<Pushed> {
+        if body.len() as u64 > self.max_bytes {
+            return Ok(Pushed::TooLarge);
+        }
+
+        // Write under a temporary name first so a crash never leaves a truncated batch
+        let path = self.dir.join(format!("{:020}.{}", self.next_seq, EXTENSION));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, body)?;
+        fs::rename(&tmp, &path)?;
+        self.next_seq += 1;
+
+        let mut files = self.files()?;
+        let mut total: u64 = files.iter().map(|(_, _, len)| len).sum();
+        let mut evicted = Vec::new();
+        while total > self.max_bytes && files.len() > 1 {
+            let (_, oldest, len) = files.remove(0);
+            evicted.push(fs::read_to_string(&oldest)?);
+            fs::remove_file(&oldest)?;
+            total -= len;
+        }
+        Ok(Pushed::Stored(evicted))
+    }
+
+    pub fn oldest(&self) -> io::Result<Option<SpooledBatch>> {
+        match self.files()?.into_iter().next() {
+            Some((_, path, _)) => {
+                let body = fs::read_to_string(&path)?;
+                Ok(Some(SpooledBatch { path, body }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Delete a batch once it has been delivered
+    pub fn remove(&self, batch: SpooledBatch) -> io::Result<()> {
+        fs::remove_file(batch.path)
+    }
+
+    /// Total size of the spooled batches in bytes
+    pub fn size_bytes(&self) -> io::Result<u64> {
+        Ok(self.files()?.iter().map(|(_, _, len)| len).sum())
+    }
+
+    /// Spooled batch files as (sequence number, path, size), oldest first
+    fn files(&self) -> io::Result<Vec<(u64, PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let seq = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            if let Some(seq) = seq {
+                files.push((seq, path.clone(), fs::metadata(&path)?.len()));
+            }
+        }
+        files.sort_by_key(|(seq, _, _)| *seq);
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_spool_order_and_limit() {
+        let dir = testing::temp_path("spool");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut spool = Spool::open(&dir, 25).unwrap();
+        assert_eq!(spool.push("first batch\n").unwrap(), Pushed::Stored(vec![]));
+        assert_eq!(spool.push("second batch\n").unwrap(), Pushed::Stored(vec![]));
+        // 12 + 13 + 12 bytes is over the limit, so the oldest batch goes
+        assert_eq!(spool.push("third batch\n").unwrap(), Pushed::Stored(vec!["first batch\n".to_string()]));
+        // A batch over the limit on its own is refused without touching the others
+        assert_eq!(spool.push(&"x".repeat(26)).unwrap(), Pushed::TooLarge);
+        assert_eq!(spool.size_bytes().unwrap(), 25);
+
+        // Batches survive a restart and come back oldest first
+        let spool = Spool::open(&dir, 25).unwrap();
+        let batch = spool.oldest().unwrap().unwrap();
+        assert_eq!(batch.body, "second batch\n");
+        spool.remove(batch).unwrap();
+        assert_eq!(spool.oldest().unwrap().unwrap().body, "third batch\n");
+        assert_eq!(spool.size_bytes().unwrap(), 12);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::AtomicU64;
+
+/// Lifecycle of a single capture (live interface or replayed file)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureStatus {
+    /// Opening the device
+    Starting,
+    /// Packets are being captured
+    Running,
+    /// The capture failed and is waiting to be reopened
+    Restarting,
+    /// The capture stopped with an error that retrying cannot fix
+    Failed,
+    /// An offline replay reached the end of its file
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceHealth {
+    pub status: CaptureStatus,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+    pub pcap: PcapStats,
+}
+
+/// Kernel capture counters reported by libpcap, accumulated across capture restarts
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PcapStats {
+    /// Packets received by the capture
+    pub received: u64,
+    /// Packets dropped because the capture buffer was full
+    pub dropped: u64,
+    /// Packets dropped by the network interface or its driver
+    pub if_dropped: u64,
+}
+
+/// Counters for packets lost between the capture threads (or the collector) and the
+/// storage writer
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    /// Times a capture thread found the writer queue full
+    pub channel_full: AtomicU64,
+    /// Packets not stored because the queue was full and the policy is `drop`
+    pub dropped_packets: AtomicU64,
+    /// Exporters the collector forgot to stay within its limit
+    pub evicted_exporters: AtomicU64,
+    /// NetFlow v9/IPFIX templates the collector evicted to stay within its limit
+    pub evicted_templates: AtomicU64,
+    /// Collected flow records not stored because too many were pending or writes kept failing
+    pub dropped_records: AtomicU64,
+}
+
+/// Counters of one exporter (Elasticsearch, NetFlow)
+#[derive(Debug, Default)]
+pub struct ExportStats {
+    /// Records the destination accepted (or, for UDP exports, that were sent)
+    pub exported: AtomicU64,
+    /// Records the destination refused or the export format cannot represent
+    pub rejected: AtomicU64,
+    /// Records lost because the exporter fell behind or its spool was full
+    pub dropped: AtomicU64,
+    /// Requests or datagrams that failed to send
+    pub failed_requests: AtomicU64,
+    /// Batches written to the spool
+    pub spooled_batches: AtomicU64,
+    /// Current size of the spool
+    pub spool_bytes: AtomicU64,
+}
+
+/// Health of every capture thread, keyed by interface name (or capture file name for replays)
+#[derive(Default)]
+pub struct CaptureHealth {
+    pub interfaces: DashMap<String, InterfaceHealth>,
+}
+
+impl CaptureHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a capture before its thread starts
+    pub fn register(&self, name: &str) {
+        self.interfaces.insert(
+            name.to_string(),
+            InterfaceHealth {
+                status: CaptureStatus::Starting,
+                restarts: 0,
+                last_error: None,
+                pcap: PcapStats::default(),
+            },
+        );
+    }
+
+    pub fn set_status(&self, name: &str, status: CaptureStatus) {
+        if let Some(mut entry) = self.interfaces.get_mut(name) {
+            entry.status = status;
+        }
+    }
+
+    /// Add counter increments read from libpcap
+    pub fn add_pcap_stats(&self, name: &str, received: u64, dropped: u64, if_dropped: u64) {
+        if let Some(mut entry) = self.interfaces.get_mut(name) {
+            entry.pcap.received += received;
+            entry.pcap.dropped += dropped;
+            entry.pcap.if_dropped += if_dropped;
+        }
+    }
+
+    /// Record a capture error. `restarting` tells whether the capture will be reopened.
+    pub fn record_error(&self, name: &str, error: &str, restarting: bool) {
+        if let Some(mut entry) = self.interfaces.get_mut(name) {
+            entry.last_error = Some(error.to_string());
+            if restarting {
+                entry.status = CaptureStatus::Restarting;
+                entry.restarts += 1;
+            } else {
+                entry.status = CaptureStatus::Failed;
+            }
+        }
+    }
+
+    /// Overall status: "ok" when every capture is healthy, "degraded" when some are,
+    /// "down" when none are.
+    pub fn overall_status(&self) -> &'static str {
+        let total = self.interfaces.len();
+        let healthy = self
+            .interfaces
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    entry.status,
+                    CaptureStatus::Running | CaptureStatus::Finished
+                )
+            })
+            .count();
+
+        if total > 0 && healthy == total {
+            "ok"
+        } else if healthy > 0 {
+            "degraded"
+        } else {
+            "down"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overall_status() {
+        let health = CaptureHealth::new();
+        health.register("eth0");
+        health.register("docker0");
+        assert_eq!(health.overall_status(), "down");
+
+        health.set_status("eth0", CaptureStatus::Running);
+        health.record_error("docker0", "interface went down", true);
+        assert_eq!(health.overall_status(), "degraded");
+        assert_eq!(health.interfaces.get("docker0").unwrap().restarts, 1);
+
+        health.set_status("docker0", CaptureStatus::Running);
+        assert_eq!(health.overall_status(), "ok");
+    }
+}
@@ -0,0 +1,181 @@
+use etherparse::{EtherType, SlicedPacket};
+use pcap::Linktype;
+
+/// DLT_RAW as numbered on OpenBSD (12) and some older BSDs (14)
+const RAW_BSD: Linktype = Linktype(12);
+const RAW_BSD_ALT: Linktype = Linktype(14);
+
+/// Length of the Linux cooked capture headers
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+
+/// BSD loopback encapsulation: a 4-byte address family precedes the IP header
+const LOOPBACK_HEADER_LEN: usize = 4;
+
+/// 802.11 frame control values
+const IEEE80211_TYPE_DATA: u8 = 2;
+const IEEE80211_HEADER_LEN: usize = 24;
+const LLC_SNAP_HEADER: [u8; 6] = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00];
+
+/// Check whether frames of this link type can be decoded
+pub fn is_supported(linktype: Linktype) -> bool {
+    matches!(
+        linktype,
+        Linktype::ETHERNET
+            | Linktype::LINUX_SLL
+            | Linktype::LINUX_SLL2
+            | Linktype::RAW
+            | Linktype::IPV4
+            | Linktype::IPV6
+            | Linktype::NULL
+            | Linktype::LOOP
+            | Linktype::IEEE802_11
+            | Linktype::IEEE802_11_RADIOTAP
+            | RAW_BSD
+            | RAW_BSD_ALT
+    )
+}
+
+/// Slice a captured frame according to the link type of the capture handle it came from.
+/// Returns `None` for unsupported link types and frames that cannot be decoded.
+pub fn slice_packet(linktype: Linktype, data: &[u8]) -> Option<SlicedPacket<'_>> {
+    match linktype {
+        Linktype::ETHERNET => SlicedPacket::from_ethernet(data).ok(),
+        Linktype::LINUX_SLL => {
+            // Protocol type is the last field of the 16-byte header
+            let header = data.get(..SLL_HEADER_LEN)?;
+            let ether_type = u16::from_be_bytes([header[14], header[15]]);
+            SlicedPacket::from_ether_type(EtherType(ether_type), &data[SLL_HEADER_LEN..]).ok()
+        }
+        Linktype::LINUX_SLL2 => {
+            // Protocol type is the first field of the 20-byte header
+            let header = data.get(..SLL2_HEADER_LEN)?;
+            let ether_type = u16::from_be_bytes([header[0], header[1]]);
+            SlicedPacket::from_ether_type(EtherType(ether_type), &data[SLL2_HEADER_LEN..]).ok()
+        }
+        Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 | RAW_BSD | RAW_BSD_ALT => {
+            SlicedPacket::from_ip(data).ok()
+        }
+        Linktype::NULL | Linktype::LOOP => {
+            // The address family is in host (NULL) or network (LOOP) byte order and its
+            // values differ between platforms, so let the IP version nibble decide instead.
+            SlicedPacket::from_ip(data.get(LOOPBACK_HEADER_LEN..)?).ok()
+        }
+        Linktype::IEEE802_11_RADIOTAP => {
+            // Radiotap header length is a little-endian u16 at offset 2
+            let len_bytes = data.get(2..4)?;
+            let radiotap_len = u16::from_le_bytes([len_bytes[0], len_bytes[1]]) as usize;
+            slice_ieee80211(data.get(radiotap_len..)?)
+        }
+        Linktype::IEEE802_11 => slice_ieee80211(data),
+        _ => None,
+    }
+}
+
+/// Decode an unencrypted 802.11 data frame carrying an LLC/SNAP encapsulated payload
+fn slice_ieee80211(frame: &[u8]) -> Option<SlicedPacket<'_>> {
+    let frame_control = frame.get(..2)?;
+    let frame_type = (frame_control[0] >> 2) & 0x3;
+    let subtype = frame_control[0] >> 4;
+    let flags = frame_control[1];
+
+    let to_ds = flags & 0x01 != 0;
+    let from_ds = flags & 0x02 != 0;
+    let protected = flags & 0x40 != 0;
+    let order = flags & 0x80 != 0;
+
+    if frame_type != IEEE80211_TYPE_DATA || protected {
+        return None;
+    }
+
+    let mut header_len = IEEE80211_HEADER_LEN;
+    if to_ds && from_ds {
+        // Fourth address field
+        header_len += 6;
+    }
+    let qos = subtype & 0x8 != 0;
+    if qos {
+        header_len += 2;
+        if order {
+            // HT control field
+            header_len += 4;
+        }
+    }
+    // Null-function subtypes carry no payload
+    if subtype & 0x4 != 0 {
+        return None;
+    }
+
+    let llc = frame.get(header_len..header_len + 8)?;
+    if llc[..6] != LLC_SNAP_HEADER {
+        return None;
+    }
+    let ether_type = u16::from_be_bytes([llc[6], llc[7]]);
+    SlicedPacket::from_ether_type(EtherType(ether_type), &frame[header_len + 8..]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::{NetSlice, PacketBuilder, TransportSlice};
+
+    fn ipv4_udp_packet() -> Vec<u8> {
+        let builder = PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).udp(5353, 53);
+        let mut packet = Vec::with_capacity(builder.size(4));
+        builder.write(&mut packet, &[1, 2, 3, 4]).unwrap();
+        packet
+    }
+
+    fn assert_udp(sliced: Option<SlicedPacket<'_>>) {
+        let sliced = sliced.expect("frame should decode");
+        assert!(matches!(sliced.net, Some(NetSlice::Ipv4(_))));
+        match sliced.transport {
+            Some(TransportSlice::Udp(udp)) => assert_eq!(udp.destination_port(), 53),
+            other => panic!("expected UDP, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_linux_cooked_captures() {
+        let packet = ipv4_udp_packet();
+
+        let mut sll = vec![0u8; SLL_HEADER_LEN];
+        sll[14..16].copy_from_slice(&0x0800u16.to_be_bytes());
+        sll.extend_from_slice(&packet);
+        assert_udp(slice_packet(Linktype::LINUX_SLL, &sll));
+
+        let mut sll2 = vec![0u8; SLL2_HEADER_LEN];
+        sll2[0..2].copy_from_slice(&0x0800u16.to_be_bytes());
+        sll2.extend_from_slice(&packet);
+        assert_udp(slice_packet(Linktype::LINUX_SLL2, &sll2));
+    }
+
+    #[test]
+    fn test_raw_and_loopback() {
+        let packet = ipv4_udp_packet();
+        assert_udp(slice_packet(Linktype::RAW, &packet));
+
+        let mut null = 2u32.to_ne_bytes().to_vec();
+        null.extend_from_slice(&packet);
+        assert_udp(slice_packet(Linktype::NULL, &null));
+    }
+
+    #[test]
+    fn test_radiotap_data_frame() {
+        let packet = ipv4_udp_packet();
+
+        // Minimal radiotap header: version, pad, length = 8, empty present bitmap
+        let mut frame = vec![0, 0, 8, 0, 0, 0, 0, 0];
+        // 802.11 data frame, FromDS, followed by addresses, sequence control and LLC/SNAP
+        frame.extend_from_slice(&[0x08, 0x02]);
+        frame.extend_from_slice(&[0u8; IEEE80211_HEADER_LEN - 2]);
+        frame.extend_from_slice(&LLC_SNAP_HEADER);
+        frame.extend_from_slice(&0x0800u16.to_be_bytes());
+        frame.extend_from_slice(&packet);
+        assert_udp(slice_packet(Linktype::IEEE802_11_RADIOTAP, &frame));
+
+        // Protected frames cannot be decoded
+        frame[9] |= 0x40;
+        assert!(slice_packet(Linktype::IEEE802_11_RADIOTAP, &frame).is_none());
+    }
+}
@@ -1,19 +1,31 @@
 use clap::Parser;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::mpsc;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, Duration, MissedTickBehavior};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod api;
 mod config;
+mod elasticsearch;
+mod health;
+mod linklayer;
+mod metrics;
+mod migrations;
+mod netflow;
+mod rates;
+mod rollups;
 mod sniffer;
 mod state;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod tls;
 
 use config::{CliArgs, Config};
-use sniffer::FilterConfig;
+use sniffer::{CaptureContext, CaptureOptions};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -52,7 +64,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // State & Storage
     let traffic_state = Arc::new(state::TrafficState::new());
-    let storage = Arc::new(storage::Storage::new(&config.db_path)?);
+    let storage = match storage::Storage::new(&config.db_path) {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            eprintln!("Error: {}: {}", config.db_path, e);
+            std::process::exit(1);
+        }
+    };
+    if cli.migrate_only {
+        println!(
+            "Database {} is at schema version {}",
+            config.db_path,
+            migrations::SCHEMA_VERSION
+        );
+        return Ok(());
+    }
 
     // Spawn Writer Task
     let storage_clone = storage.clone();
@@ -61,6 +87,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         storage_clone.run_writer(rx, aggregation_window).await;
     });
 
+    // Spawn Flow Writer Task
+    let storage_flows = storage.clone();
+    let flow_events = traffic_state.subscribe();
+    tokio::spawn(async move {
+        storage_flows.run_flow_writer(flow_events).await;
+    });
+
+    // Spawn Elasticsearch Exporter Task (if enabled)
+    let mut exports = Vec::new();
+    let mut export_packets = None;
+    if let Some(ref url) = config.elasticsearch_url {
+        let exporter = elasticsearch::Exporter::new(url, &config).unwrap_or_else(|e| {
+            eprintln!("Error: Elasticsearch exporter: {}", e);
+            std::process::exit(1);
+        });
+        let documents = exporter.documents();
+        let stats = exporter.stats();
+        let packets = documents.packets().then(|| {
+            let (export_tx, export_rx) = mpsc::channel(10000);
+            export_packets = Some(sniffer::PacketExport {
+                tx: export_tx,
+                stats: stats.clone(),
+            });
+            export_rx
+        });
+        let flows = documents.flows().then(|| traffic_state.subscribe());
+        tokio::spawn(exporter.run(packets, flows));
+        tracing::info!("Exporting {:?} to Elasticsearch at {}", documents, url);
+        exports.push(("elasticsearch", stats));
+    }
+
+    // Spawn NetFlow Exporter Task (if collectors are configured)
+    if !config.netflow_collectors.is_empty() {
+        let exporter = netflow::Exporter::new(&config).await.unwrap_or_else(|e| {
+            eprintln!("Error: NetFlow exporter: {}", e);
+            std::process::exit(1);
+        });
+        let stats = exporter.stats();
+        tokio::spawn(exporter.run(traffic_state.clone(), traffic_state.subscribe()));
+        tracing::info!(
+            "Exporting flows as NetFlow {:?} to {}",
+            config.netflow_version,
+            config.netflow_collectors.join(", ")
+        );
+        exports.push(("netflow", stats));
+    }
+
+    // Spawn Rate Sampling Task
+    let traffic_state_rates = traffic_state.clone();
+    tokio::spawn(async move {
+        let mut rate_interval = interval(Duration::from_secs(1));
+        rate_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            rate_interval.tick().await;
+            traffic_state_rates.sample_rates();
+        }
+    });
+
     // Spawn Connection Cleanup Task
     let traffic_state_cleanup = traffic_state.clone();
     let connection_timeout = config.connection_timeout;
@@ -81,7 +165,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 retention_interval.tick().await;
                 match storage_retention.delete_old_data(retention_seconds) {
                     Ok(deleted) if deleted > 0 => {
-                        tracing::info!("Data retention: deleted {} old packets", deleted);
+                        tracing::info!("Data retention: deleted {} old packets and flows", deleted);
                     }
                     Err(e) => {
                         tracing::error!("Data retention cleanup failed: {}", e);
@@ -92,6 +176,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         });
     }
 
+    // Spawn Rollup Task: downsample stored traffic, then apply per-resolution retention.
+    // Rows can be written late (flows ending by timeout, aggregation windows), so buckets
+    // are only rolled up once that can no longer happen.
+    let storage_rollups = storage.clone();
+    let rollup_grace_ms = (config.connection_timeout + config.aggregation_window_seconds + 30) as i64 * 1000;
+    // Received flows are not sampled by --sample-rate
+    let rollup_sample_rate = if config.collector.is_some() { 1 } else { config.sample_rate };
+    let rollup_retention: Vec<_> = rollups::Resolution::ALL
+        .into_iter()
+        .filter_map(|resolution| config.rollup_retention(resolution).map(|secs| (resolution, secs)))
+        .collect();
+    tokio::spawn(async move {
+        let mut rollup_interval = interval(Duration::from_secs(60));
+        loop {
+            rollup_interval.tick().await;
+            if let Err(e) = storage_rollups.run_rollups(rollup_grace_ms, rollup_sample_rate) {
+                tracing::error!("Rollup failed: {}", e);
+            }
+            for (resolution, retention_seconds) in &rollup_retention {
+                if let Err(e) = storage_rollups.delete_old_rollups(*resolution, *retention_seconds) {
+                    tracing::error!("Rollup retention cleanup failed: {}", e);
+                }
+            }
+        }
+    });
+
     // Signal handler for graceful shutdown
     let _storage_for_shutdown = storage.clone();
     ctrlc::set_handler(move || {
@@ -102,31 +212,117 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     })
     .expect("Error setting Ctrl-C handler");
 
-    // Start Sniffer Thread
-    let tx_clone = tx.clone();
-    let interface = config.interface.clone();
-    let running_sniffer = running.clone();
-    let traffic_state_clone = traffic_state.clone();
-    let filter = FilterConfig::from(&config);
-    let quiet = config.quiet;
-    let sample_rate = config.sample_rate;
-
-    std::thread::spawn(move || {
-        sniffer::start_sniffer(interface, tx_clone, running_sniffer, traffic_state_clone, filter, quiet, sample_rate);
-    });
+    // Start Sniffer Threads (one per interface, all feeding the same state and channel),
+    // or in collector mode receive flows from exporters instead
+    let options = CaptureOptions::from(&config);
+    let capture_health = Arc::new(health::CaptureHealth::new());
+    let pipeline_stats = Arc::new(health::PipelineStats::default());
+    let capture_ctx = CaptureContext {
+        tx: tx.clone(),
+        running: running.clone(),
+        traffic_state: traffic_state.clone(),
+        health: capture_health.clone(),
+        pipeline_stats: pipeline_stats.clone(),
+        export: export_packets,
+    };
+
+    if let Some(url) = config.collector.clone() {
+        capture_health.register(&url);
+        let collector = netflow::Collector::bind(
+            &url,
+            options.filter.clone(),
+            traffic_state.clone(),
+            storage.clone(),
+            capture_health.clone(),
+            pipeline_stats.clone(),
+        )
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Error: collector {}: {}", url, e);
+            std::process::exit(1);
+        });
+        tracing::info!("Collecting NetFlow/IPFIX/sFlow on {}", url);
+        tokio::spawn(collector.run());
+    } else if let Some(path) = config.read_file.clone() {
+        let speed = config.replay_speed;
+        capture_health.register(&sniffer::replay_source_name(&path));
+        std::thread::spawn(move || {
+            sniffer::start_replay(path, speed, capture_ctx, options);
+        });
+    } else {
+        // Validate interface names up front so typos fail fast instead of in a background thread
+        let resolved = if config.interfaces.is_empty() {
+            sniffer::resolve_interface(None).map(|name| vec![name])
+        } else {
+            config
+                .interfaces
+                .iter()
+                .map(|name| sniffer::resolve_interface(Some(name)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let interfaces = resolved.unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
+
+        for interface in interfaces {
+            let ctx = capture_ctx.clone();
+            let options = options.clone();
+            capture_health.register(&interface);
+            std::thread::spawn(move || {
+                sniffer::supervise_sniffer(interface, ctx, options);
+            });
+        }
+    }
 
     // API
+    let allowlist = api::Allowlist::new(&config.api_allowlist, &config.trusted_proxies).unwrap_or_else(|e| {
+        eprintln!("Error: api_allowlist: {}", e);
+        std::process::exit(1);
+    });
+    let tls_files = tls::TlsFiles::from_config(&config).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let tokens = api::Tokens::load(&config.api_tokens).unwrap_or_else(|e| {
+        eprintln!("Error: api_tokens: {}", e);
+        std::process::exit(1);
+    });
+    let event_log = Arc::new(api::EventLog::new());
+    tokio::spawn(event_log.clone().run(traffic_state.subscribe()));
     let app_state = Arc::new(api::AppState {
         traffic: traffic_state.clone(),
         storage: storage.clone(),
+        health: capture_health.clone(),
+        pipeline: pipeline_stats.clone(),
+        writer_queue: tx.clone(),
+        exports,
         start_time: std::time::Instant::now(),
+        config: Arc::new(config.clone()),
+        allowlist: allowlist.map(Arc::new),
+        tokens: tokens.map(Arc::new),
+        events: event_log,
     });
 
     let app = api::router(app_state);
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
-    tracing::info!("Server running on http://0.0.0.0:{}", config.port);
-    axum::serve(listener, app).await?;
+    // Peer addresses are needed for the allowlist
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Some(files) = tls_files {
+        let rustls = files.load().unwrap_or_else(|e| {
+            eprintln!("Error: TLS: {}", e);
+            std::process::exit(1);
+        });
+        tokio::spawn(files.watch(rustls.clone()));
+        tracing::info!("Server running on https://0.0.0.0:{}", config.port);
+        axum_server::bind_rustls(SocketAddr::from(([0, 0, 0, 0], config.port)), rustls)
+            .serve(service)
+            .await?;
+    } else {
+        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
+        tracing::info!("Server running on http://0.0.0.0:{}", config.port);
+        axum::serve(listener, service).await?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,435 @@
+use crate::api::AppState;
+use crate::health::ExportStats;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Latency histogram that can be updated from any thread without locking
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations per bucket; the last slot counts those above every bound
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+/// Writes metric families in the Prometheus text format
+struct Encoder {
+    out: String,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Self { out: String::new() }
+    }
+
+    /// Start a metric family; its samples must follow before the next family starts
+    fn family(&mut self, name: &str, kind: Kind, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind.as_str());
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", label, escape_label(value));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    /// Family with a single unlabeled sample
+    fn single(&mut self, name: &str, kind: Kind, help: &str, value: impl Display) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    /// The `_bucket`, `_sum` and `_count` samples of one histogram
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (i, count) in histogram.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = match LATENCY_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&bucket_name, &bucket_labels, cumulative);
+        }
+        let sum = histogram.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        self.sample(&format!("{}_sum", name), labels, sum);
+        self.sample(&format!("{}_count", name), labels, cumulative);
+    }
+
+    fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Escape a label value: backslash, double quote and newline must be escaped
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render every metric for a `/metrics` scrape
+pub fn render(state: &AppState) -> String {
+    let mut enc = Encoder::new();
+    let traffic = &state.traffic;
+
+    enc.single(
+        "lightshark_uptime_seconds",
+        Kind::Gauge,
+        "Seconds since LightShark-mini started",
+        state.start_time.elapsed().as_secs(),
+    );
+    enc.single(
+        "lightshark_packets_total",
+        Kind::Counter,
+        "Packets seen on all captures",
+        traffic.total_packets.load(Ordering::Relaxed),
+    );
+    enc.single(
+        "lightshark_bytes_total",
+        Kind::Counter,
+        "Bytes seen on all captures",
+        traffic.total_bytes.load(Ordering::Relaxed),
+    );
+    enc.single(
+        "lightshark_active_connections",
+        Kind::Gauge,
+        "Conversations currently tracked",
+        traffic.active_connections.load(Ordering::Relaxed),
+    );
+
+    // Sorted so the scrape output is stable between requests
+    let mut protocols: Vec<_> = traffic
+        .protocols
+        .iter()
+        .map(|entry| (entry.key().as_str(), entry.value().clone()))
+        .collect();
+    protocols.sort_by_key(|(protocol, _)| *protocol);
+    enc.family("lightshark_protocol_packets_total", Kind::Counter, "Packets seen per protocol");
+    for (protocol, counters) in &protocols {
+        enc.sample("lightshark_protocol_packets_total", &[("protocol", protocol)], counters.packets);
+    }
+    enc.family("lightshark_protocol_bytes_total", Kind::Counter, "Bytes seen per protocol");
+    for (protocol, counters) in &protocols {
+        enc.sample("lightshark_protocol_bytes_total", &[("protocol", protocol)], counters.bytes);
+    }
+
+    let mut interfaces: Vec<_> = traffic
+        .interfaces
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    interfaces.sort_by(|a, b| a.0.cmp(&b.0));
+    enc.family("lightshark_interface_packets_total", Kind::Counter, "Packets seen per capture interface");
+    for (interface, counters) in &interfaces {
+        enc.sample("lightshark_interface_packets_total", &[("interface", interface)], counters.packets);
+    }
+    enc.family("lightshark_interface_bytes_total", Kind::Counter, "Bytes seen per capture interface");
+    for (interface, counters) in &interfaces {
+        enc.sample("lightshark_interface_bytes_total", &[("interface", interface)], counters.bytes);
+    }
+
+    let mut captures: Vec<_> = state
+        .health
+        .interfaces
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    captures.sort_by(|a, b| a.0.cmp(&b.0));
+    enc.family("lightshark_pcap_received_total", Kind::Counter, "Packets received by libpcap");
+    for (interface, health) in &captures {
+        enc.sample("lightshark_pcap_received_total", &[("interface", interface)], health.pcap.received);
+    }
+    enc.family("lightshark_pcap_dropped_total", Kind::Counter, "Packets dropped because the capture buffer was full");
+    for (interface, health) in &captures {
+        enc.sample("lightshark_pcap_dropped_total", &[("interface", interface)], health.pcap.dropped);
+    }
+    enc.family("lightshark_pcap_if_dropped_total", Kind::Counter, "Packets dropped by the network interface or its driver");
+    for (interface, health) in &captures {
+        enc.sample("lightshark_pcap_if_dropped_total", &[("interface", interface)], health.pcap.if_dropped);
+    }
+    enc.family("lightshark_capture_restarts_total", Kind::Counter, "Times a capture was reopened after an error");
+    for (interface, health) in &captures {
+        enc.sample("lightshark_capture_restarts_total", &[("interface", interface)], health.restarts);
+    }
+
+    let queue = &state.writer_queue;
+    enc.single(
+        "lightshark_writer_queue_depth",
+        Kind::Gauge,
+        "Packets waiting for the storage writer",
+        queue.max_capacity() - queue.capacity(),
+    );
+    enc.single(
+        "lightshark_writer_queue_capacity",
+        Kind::Gauge,
+        "Size of the storage writer queue",
+        queue.max_capacity(),
+    );
+    enc.single(
+        "lightshark_writer_queue_full_total",
+        Kind::Counter,
+        "Times a capture thread found the writer queue full",
+        state.pipeline.channel_full.load(Ordering::Relaxed),
+    );
+    enc.single(
+        "lightshark_dropped_packets_total",
+        Kind::Counter,
+        "Packets not stored because the writer queue was full",
+        state.pipeline.dropped_packets.load(Ordering::Relaxed),
+    );
+    if state.config.collector.is_some() {
+        enc.single(
+            "lightshark_collector_evicted_exporters_total",
+            Kind::Counter,
+            "Exporters the collector forgot because it tracked too many",
+            state.pipeline.evicted_exporters.load(Ordering::Relaxed),
+        );
+        enc.single(
+            "lightshark_collector_evicted_templates_total",
+            Kind::Counter,
+            "NetFlow v9/IPFIX templates evicted because the template cache was full",
+            state.pipeline.evicted_templates.load(Ordering::Relaxed),
+        );
+        enc.single(
+            "lightshark_collector_dropped_records_total",
+            Kind::Counter,
+            "Collected flow records not stored because too many were pending or writes kept failing",
+            state.pipeline.dropped_records.load(Ordering::Relaxed),
+        );
+    }
+    enc.single(
+        "lightshark_storage_write_failures_total",
+        Kind::Counter,
+        "Failed database transactions, statements and inserts",
+        state.storage.write_failures(),
+    );
+    enc.single(
+        "lightshark_lost_flow_records_total",
+        Kind::Counter,
+        "Ended flows the flow writer fell too far behind to store",
+        state.storage.lost_flow_records(),
+    );
+    match state.storage.size_bytes() {
+        Ok(size) => enc.single(
+            "lightshark_database_size_bytes",
+            Kind::Gauge,
+            "Size of the SQLite database content, excluding the write-ahead log file",
+            size,
+        ),
+        Err(e) => tracing::warn!("Failed to read database size: {}", e),
+    }
+
+    let flush = "lightshark_storage_flush_duration_seconds";
+    enc.family(flush, Kind::Histogram, "Time taken to write one batch to the database, not counting waits for the connection");
+    enc.histogram(flush, &[("table", "packets")], state.storage.packet_flush_latency());
+    enc.histogram(flush, &[("table", "flows")], state.storage.flow_flush_latency());
+
+    if !state.exports.is_empty() {
+        render_exports(&mut enc, &state.exports);
+    }
+
+    let top_flows = state.config.metrics_top_flows;
+    if top_flows > 0 {
+        render_top_flows(&mut enc, state, top_flows);
+    }
+
+    enc.finish()
+}
+
+fn render_exports(enc: &mut Encoder, exports: &[(&str, Arc<ExportStats>)]) {
+    let documents = "lightshark_export_documents_total";
+    enc.family(documents, Kind::Counter, "Records handled by each exporter");
+    for (exporter, stats) in exports {
+        for (result, counter) in [
+            ("exported", &stats.exported),
+            ("rejected", &stats.rejected),
+            ("dropped", &stats.dropped),
+        ] {
+            enc.sample(documents, &[("exporter", exporter), ("result", result)], counter.load(Ordering::Relaxed));
+        }
+    }
+
+    let failed = "lightshark_export_failed_requests_total";
+    enc.family(failed, Kind::Counter, "Export requests or datagrams that failed");
+    for (exporter, stats) in exports {
+        enc.sample(failed, &[("exporter", exporter)], stats.failed_requests.load(Ordering::Relaxed));
+    }
+    let spooled = "lightshark_export_spooled_batches_total";
+    enc.family(spooled, Kind::Counter, "Batches written to an export spool");
+    for (exporter, stats) in exports {
+        enc.sample(spooled, &[("exporter", exporter)], stats.spooled_batches.load(Ordering::Relaxed));
+    }
+    let spool_bytes = "lightshark_export_spool_bytes";
+    enc.family(spool_bytes, Kind::Gauge, "Size of an export spool");
+    for (exporter, stats) in exports {
+        enc.sample(spool_bytes, &[("exporter", exporter)], stats.spool_bytes.load(Ordering::Relaxed));
+    }
+}
+
+/// The `limit` active conversations with the most bytes, one label set each
+fn render_top_flows(enc: &mut Encoder, state: &AppState, limit: usize) {
+    let mut flows: Vec<_> = state
+        .traffic
+        .connections
+        .iter()
+        .map(|entry| {
+            let stats = entry.value();
+            let labels = [
+                ("client", stats.client.to_string()),
+                ("server", stats.server.to_string()),
+                ("protocol", stats.protocol.to_string()),
+                ("interface", entry.key().interface.to_string()),
+            ];
+            (stats.bytes_sent + stats.bytes_received, stats.packets_count, labels)
+        })
+        .collect();
+    flows.sort_by_key(|(bytes, _, _)| std::cmp::Reverse(*bytes));
+    flows.truncate(limit);
+
+    enc.family("lightshark_top_flow_bytes", Kind::Gauge, "Bytes in both directions of the largest active conversations");
+    for (bytes, _, labels) in &flows {
+        enc.sample("lightshark_top_flow_bytes", &label_refs(labels), bytes);
+    }
+    enc.family("lightshark_top_flow_packets", Kind::Gauge, "Packets in both directions of the largest active conversations");
+    for (_, packets, labels) in &flows {
+        enc.sample("lightshark_top_flow_packets", &label_refs(labels), packets);
+    }
+}
+
+fn label_refs<'a>(labels: &'a [(&'a str, String)]) -> Vec<(&'a str, &'a str)> {
+    labels.iter().map(|(name, value)| (*name, value.as_str())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::health::{CaptureHealth, PipelineStats};
+    use crate::state::{Protocol, TrafficState};
+    use crate::storage::Storage;
+    use crate::testing;
+
+    #[test]
+    fn test_render() {
+        let traffic = Arc::new(TrafficState::new());
+        for (src_port, length) in [(5000, 100), (5001, 300)] {
+            traffic.update(&testing::packet(Protocol::Udp, "10.0.0.1", src_port, "10.0.0.2", 53, length));
+        }
+        let health = Arc::new(CaptureHealth::new());
+        health.register("eth0");
+        health.add_pcap_stats("eth0", 2, 1, 0);
+
+        let state = AppState {
+            traffic,
+            storage: Arc::new(Storage::new(":memory:").unwrap()),
+            health,
+            pipeline: Arc::new(PipelineStats::default()),
+            writer_queue: tokio::sync::mpsc::channel(16).0,
+            exports: Vec::new(),
+            allowlist: None,
+            tokens: None,
+            events: Arc::new(crate::api::EventLog::new()),
+            start_time: std::time::Instant::now(),
+            config: Arc::new(Config {
+                metrics_top_flows: 1,
+                ..Config::default()
+            }),
+        };
+        let out = render(&state);
+
+        assert!(out.contains("# TYPE lightshark_packets_total counter\nlightshark_packets_total 2\n"));
+        assert!(out.contains("lightshark_protocol_bytes_total{protocol=\"UDP\"} 400\n"));
+        assert!(out.contains("lightshark_interface_packets_total{interface=\"eth0\"} 2\n"));
+        assert!(out.contains("lightshark_pcap_dropped_total{interface=\"eth0\"} 1\n"));
+        assert!(out.contains("lightshark_writer_queue_capacity 16\n"));
+        assert!(out.contains("lightshark_storage_flush_duration_seconds_count{table=\"flows\"} 0\n"));
+        // Only the larger of the two flows is exported
+        assert!(out.contains(
+            "lightshark_top_flow_bytes{client=\"10.0.0.1:5001\",server=\"10.0.0.2:53\",protocol=\"UDP\",interface=\"eth0\"} 300\n"
+        ));
+        assert_eq!(out.matches("lightshark_top_flow_bytes{").count(), 1);
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut enc = Encoder::new();
+        enc.histogram("flush_seconds", &[("table", "packets")], &histogram);
+        let out = enc.finish();
+
+        assert!(out.contains("flush_seconds_bucket{table=\"packets\",le=\"0.0005\"} 1\n"));
+        assert!(out.contains("flush_seconds_bucket{table=\"packets\",le=\"0.025\"} 2\n"));
+        assert!(out.contains("flush_seconds_bucket{table=\"packets\",le=\"2.5\"} 2\n"));
+        assert!(out.contains("flush_seconds_bucket{table=\"packets\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("flush_seconds_sum{table=\"packets\"} 10.0203\n"));
+        assert!(out.contains("flush_seconds_count{table=\"packets\"} 3\n"));
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        let mut enc = Encoder::new();
+        enc.family("x", Kind::Gauge, "Test");
+        enc.sample("x", &[("interface", "a\"b\\c\n")], 1);
+        assert_eq!(
+            enc.finish(),
+            "# HELP x Test\n# TYPE x gauge\nx{interface=\"a\\\"b\\\\c\\n\"} 1\n"
+        );
+    }
+}
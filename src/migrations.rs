@@ -0,0 +1,292 @@
+use rusqlite::{Connection, Transaction};
+use std::fmt;
+
+/// One schema change. Steps are applied in order inside their own transaction and the
+/// database's `PRAGMA user_version` records the last one applied.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// Every schema change, oldest first. Never edit or reorder a released step; append a new one.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create packets table",
+        apply: create_packets,
+    },
+    Migration {
+        version: 2,
+        description: "add packets.timestamp_ns",
+        apply: |tx| add_column(tx, "packets", "timestamp_ns", "INTEGER"),
+    },
+    Migration {
+        version: 3,
+        description: "add packets.interface",
+        apply: |tx| add_column(tx, "packets", "interface", "TEXT"),
+    },
+    Migration {
+        version: 4,
+        description: "add packets.tcp_flags",
+        apply: |tx| add_column(tx, "packets", "tcp_flags", "INTEGER"),
+    },
+    Migration {
+        version: 5,
+        description: "create flows table",
+        apply: create_flows,
+    },
+    Migration {
+        version: 6,
+        description: "create rollup tables",
+        apply: create_rollups,
+    },
+    Migration {
+        version: 7,
+        description: "index packets by address and port",
+        apply: index_packets,
+    },
+    Migration {
+        version: 8,
+        description: "track rows rolled up",
+        apply: track_rolled_up_rows,
+    },
+];
+
+/// Schema version this binary writes
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database was written by a newer LightShark-mini
+    DatabaseTooNew { found: u32, supported: u32 },
+    /// A migration step or the version bookkeeping failed
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::DatabaseTooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than this binary supports ({}); upgrade LightShark-mini or use a different --db-path",
+                found, supported
+            ),
+            MigrationError::Sqlite(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MigrationError::Sqlite(e) => Some(e),
+            MigrationError::DatabaseTooNew { .. } => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(e)
+    }
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Bring the database up to `SCHEMA_VERSION`. Returns the number of steps applied.
+pub fn migrate(conn: &mut Connection) -> Result<usize, MigrationError> {
+    let current = schema_version(conn)?;
+    if current > SCHEMA_VERSION {
+        return Err(MigrationError::DatabaseTooNew {
+            found: current,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    let mut applied = 0;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        // PRAGMA does not accept bound parameters
+        tx.execute_batch(&format!("PRAGMA user_version = {}", migration.version))?;
+        tx.commit()?;
+        tracing::info!(
+            "Applied database migration {}: {}",
+            migration.version,
+            migration.description
+        );
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+// Databases created before versioning report version 0 but may already contain any of
+// the tables and columns below, so every step must tolerate its change being present.
+
+fn create_packets(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS packets (
+            id INTEGER PRIMARY KEY,
+            timestamp INTEGER NOT NULL,
+            src_ip TEXT NOT NULL,
+            dst_ip TEXT NOT NULL,
+            src_port INTEGER,
+            dst_port INTEGER,
+            protocol TEXT,
+            length INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS idx_timestamp ON packets(timestamp);",
+    )
+}
+
+fn create_flows(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS flows (
+            id INTEGER PRIMARY KEY,
+            kind TEXT NOT NULL,
+            start_ns INTEGER NOT NULL,
+            end_ns INTEGER NOT NULL,
+            duration_ms INTEGER NOT NULL,
+            interface TEXT,
+            vlan INTEGER,
+            src_ip TEXT NOT NULL,
+            dst_ip TEXT NOT NULL,
+            src_port INTEGER,
+            dst_port INTEGER,
+            protocol TEXT,
+            ip_protocol INTEGER,
+            bytes_sent INTEGER NOT NULL,
+            bytes_received INTEGER NOT NULL,
+            packets_sent INTEGER NOT NULL,
+            packets_received INTEGER NOT NULL,
+            state TEXT,
+            close_reason TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_flows_end ON flows(end_ns);",
+    )
+}
+
+fn create_rollups(tx: &Transaction) -> rusqlite::Result<()> {
+    for table in ["rollup_minute", "rollup_hour", "rollup_day"] {
+        tx.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                bucket_start INTEGER NOT NULL,
+                dimension TEXT NOT NULL,
+                key TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                packets INTEGER NOT NULL,
+                flows INTEGER NOT NULL,
+                PRIMARY KEY (bucket_start, dimension, key)
+            ) WITHOUT ROWID",
+            table
+        ))?;
+    }
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS rollup_state (
+            resolution TEXT PRIMARY KEY,
+            completed_until INTEGER NOT NULL
+        )",
+    )
+}
+
+/// Address and port lookups for history queries. `timestamp` is included so matches come
+/// out in history order; the `id` tie-breaker is implicit as the rowid.
+fn index_packets(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_packets_src_ip ON packets(src_ip, timestamp);
+        CREATE INDEX IF NOT EXISTS idx_packets_dst_ip ON packets(dst_ip, timestamp);
+        CREATE INDEX IF NOT EXISTS idx_packets_src_port ON packets(src_port, timestamp);
+        CREATE INDEX IF NOT EXISTS idx_packets_dst_port ON packets(dst_port, timestamp);",
+    )
+}
+
+/// The last packet and flow IDs the rollups have seen, to find rows written after their
+/// bucket was rolled up. Windows are rolled up by start time, hence the index.
+fn track_rolled_up_rows(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS rollup_sources (
+            source TEXT PRIMARY KEY,
+            last_id INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_flows_start ON flows(start_ns);",
+    )
+}
+
+fn add_column(tx: &Transaction, table: &str, column: &str, column_type: &str) -> rusqlite::Result<()> {
+    if !has_column(tx, table, column)? {
+        tx.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, column_type))?;
+    }
+    Ok(())
+}
+
+/// Check whether `table` already has a column named `column`
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn test_fresh_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert!(has_column(&conn, "packets", "tcp_flags").unwrap());
+        assert!(has_column(&conn, "flows", "close_reason").unwrap());
+
+        // Running again is a no-op
+        assert_eq!(migrate(&mut conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_partially_upgraded_unversioned_database() {
+        // A database from before versioning that already gained some columns ad hoc
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE packets (
+                id INTEGER PRIMARY KEY, timestamp INTEGER NOT NULL, timestamp_ns INTEGER,
+                interface TEXT, src_ip TEXT NOT NULL, dst_ip TEXT NOT NULL, src_port INTEGER,
+                dst_port INTEGER, protocol TEXT, length INTEGER
+            );",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert!(has_column(&conn, "packets", "tcp_flags").unwrap());
+    }
+
+    #[test]
+    fn test_refuses_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1))
+            .unwrap();
+
+        match migrate(&mut conn) {
+            Err(MigrationError::DatabaseTooNew { found, supported }) => {
+                assert_eq!(found, SCHEMA_VERSION + 1);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            other => panic!("expected DatabaseTooNew, got {:?}", other),
+        }
+    }
+}
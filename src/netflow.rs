@@ -0,0 +1,582 @@
+mod collector;
+mod ipfix;
+mod reader;
+mod sflow;
+mod v5;
+
+pub use collector::Collector;
+
+use crate::config::{Config, NetflowVersion};
+use crate::health::ExportStats;
+use crate::state::{ConnectionStats, FlowEvent, FlowKey, FlowSummary, TrafficState};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration, Instant};
+
+/// Largest datagram sent, leaving room for IP and UDP headers within a 1500-byte MTU
+const MAX_DATAGRAM: usize = 1400;
+
+/// How often active timeouts are checked and pending records sent
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pending records that trigger a send before the next interval
+const MAX_PENDING: usize = 256;
+
+/// v9 and IPFIX templates are repeated after this many datagrams or milliseconds, so a
+/// collector that restarts (or missed a datagram) can decode again quickly
+const TEMPLATE_REFRESH_DATAGRAMS: u32 = 20;
+const TEMPLATE_REFRESH_MS: i64 = 60_000;
+
+/// One direction of a conversation over an export interval. NetFlow records are
+/// unidirectional, so a conversation is exported as up to two records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetflowRecord {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// IP protocol number
+    pub protocol: u8,
+    /// Union of the TCP flags seen, 0 when unknown
+    pub tcp_flags: u8,
+    pub vlan: Option<u16>,
+    pub bytes: u64,
+    pub packets: u64,
+    /// Times of the first and last packet in ms since the Unix epoch
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+/// Milliseconds since `boot_ms`, the 32-bit "sysUptime" clock of NetFlow v5 and v9
+fn uptime_ms(time_ms: i64, boot_ms: i64) -> u32 {
+    (time_ms - boot_ms).max(0) as u32
+}
+
+/// Wall-clock time of an uptime timestamp in a received datagram, given the datagram's
+/// export time and uptime. The uptime clock wraps every 49.7 days.
+fn absolute_ms(export_ms: i64, export_uptime_ms: u32, time_uptime_ms: u32) -> i64 {
+    export_ms - export_uptime_ms.wrapping_sub(time_uptime_ms) as i64
+}
+
+/// Counters and timestamps of a conversation, from a live entry or its final summary
+struct Totals {
+    client: SocketAddr,
+    server: SocketAddr,
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    tcp_flags_sent: u8,
+    tcp_flags_received: u8,
+    start_ns: i64,
+    end_ns: i64,
+}
+
+impl From<&ConnectionStats> for Totals {
+    fn from(stats: &ConnectionStats) -> Self {
+        Self {
+            client: stats.client,
+            server: stats.server,
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            packets_sent: stats.packets_sent,
+            packets_received: stats.packets_received,
+            tcp_flags_sent: stats.tcp_flags_sent,
+            tcp_flags_received: stats.tcp_flags_received,
+            start_ns: stats.first_seen_ns,
+            end_ns: stats.last_seen_ns,
+        }
+    }
+}
+
+impl From<&FlowSummary> for Totals {
+    fn from(summary: &FlowSummary) -> Self {
+        Self {
+            client: summary.client,
+            server: summary.server,
+            bytes_sent: summary.bytes_sent,
+            bytes_received: summary.bytes_received,
+            packets_sent: summary.packets_sent,
+            packets_received: summary.packets_received,
+            tcp_flags_sent: summary.tcp_flags_sent,
+            tcp_flags_received: summary.tcp_flags_received,
+            start_ns: summary.start_ns,
+            end_ns: summary.end_ns,
+        }
+    }
+}
+
+/// What has already been exported of a conversation that is still active
+#[derive(Default)]
+struct Exported {
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    /// Capture time of the last packet covered by the previous export
+    until_ns: Option<i64>,
+    /// When the conversation was last exported (or first noticed)
+    at: Option<Instant>,
+}
+
+impl Exported {
+    /// Records for the traffic since the previous export, one per direction that saw packets.
+    /// TCP flags cannot be split between exports, so they cover the flow so far.
+    fn delta(&mut self, key: &FlowKey, totals: &Totals) -> Vec<NetflowRecord> {
+        let start_ms = self.until_ns.unwrap_or(totals.start_ns) / 1_000_000;
+        let end_ms = totals.end_ns / 1_000_000;
+        let record = |src, dst, tcp_flags, bytes, packets| NetflowRecord {
+            src,
+            dst,
+            protocol: key.protocol,
+            tcp_flags,
+            vlan: key.vlan,
+            bytes,
+            packets,
+            start_ms,
+            end_ms,
+        };
+
+        let mut records = Vec::with_capacity(2);
+        let packets = totals.packets_sent.saturating_sub(self.packets_sent);
+        if packets > 0 {
+            let bytes = totals.bytes_sent.saturating_sub(self.bytes_sent);
+            records.push(record(totals.client, totals.server, totals.tcp_flags_sent, bytes, packets));
+        }
+        let packets = totals.packets_received.saturating_sub(self.packets_received);
+        if packets > 0 {
+            let bytes = totals.bytes_received.saturating_sub(self.bytes_received);
+            records.push(record(totals.server, totals.client, totals.tcp_flags_received, bytes, packets));
+        }
+
+        self.bytes_sent = totals.bytes_sent;
+        self.bytes_received = totals.bytes_received;
+        self.packets_sent = totals.packets_sent;
+        self.packets_received = totals.packets_received;
+        self.until_ns = Some(totals.end_ns);
+        records
+    }
+}
+
+/// Turns records into datagrams, keeping sequence numbers and the template schedule
+struct Encoder {
+    version: NetflowVersion,
+    source_id: u32,
+    boot_ms: i64,
+    /// v5: records sent, v9: datagrams sent, IPFIX: data records sent
+    sequence: u32,
+    /// Datagrams sent and time since the templates were last included (None = never)
+    templates_sent: Option<(u32, i64)>,
+}
+
+impl Encoder {
+    fn new(version: NetflowVersion, source_id: u32, boot_ms: i64) -> Self {
+        Self {
+            version,
+            source_id,
+            boot_ms,
+            sequence: 0,
+            templates_sent: None,
+        }
+    }
+
+    /// Encode records into datagrams. Returns the datagrams and the number of records
+    /// the format cannot carry (IPv6 in v5).
+    fn encode(&mut self, records: &[NetflowRecord], now_ms: i64) -> (Vec<Vec<u8>>, u64) {
+        match self.version {
+            NetflowVersion::V5 => self.encode_v5(records, now_ms),
+            NetflowVersion::V9 => (self.encode_templated(ipfix::Flavor::V9, records, now_ms), 0),
+            NetflowVersion::Ipfix => (self.encode_templated(ipfix::Flavor::Ipfix, records, now_ms), 0),
+        }
+    }
+
+    fn encode_v5(&mut self, records: &[NetflowRecord], now_ms: i64) -> (Vec<Vec<u8>>, u64) {
+        let ipv4: Vec<&NetflowRecord> = records.iter().filter(|r| r.src.is_ipv4()).collect();
+        let datagrams = ipv4
+            .chunks(v5::MAX_RECORDS)
+            .map(|chunk| {
+                let datagram = v5::encode(chunk, self.sequence, now_ms, self.boot_ms);
+                self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+                datagram
+            })
+            .collect();
+        (datagrams, (records.len() - ipv4.len()) as u64)
+    }
+
+    fn encode_templated(&mut self, flavor: ipfix::Flavor, records: &[NetflowRecord], now_ms: i64) -> Vec<Vec<u8>> {
+        // Group by address family so each datagram needs at most two data sets
+        let mut sorted: Vec<NetflowRecord> = records.to_vec();
+        sorted.sort_by_key(|record| record.src.is_ipv6());
+
+        let mut datagrams = Vec::new();
+        let mut remaining = &sorted[..];
+        while !remaining.is_empty() {
+            let with_templates = self.templates_sent.is_none_or(|(datagrams, at)| {
+                datagrams >= TEMPLATE_REFRESH_DATAGRAMS || now_ms - at >= TEMPLATE_REFRESH_MS
+            });
+            let header = ipfix::Header {
+                sequence: self.sequence,
+                source_id: self.source_id,
+                now_ms,
+                boot_ms: self.boot_ms,
+            };
+            let (datagram, used) = ipfix::encode(flavor, remaining, with_templates, &header, MAX_DATAGRAM);
+            remaining = &remaining[used..];
+            datagrams.push(datagram);
+
+            self.sequence = match flavor {
+                ipfix::Flavor::V9 => self.sequence.wrapping_add(1),
+                ipfix::Flavor::Ipfix => self.sequence.wrapping_add(used as u32),
+            };
+            self.templates_sent = match self.templates_sent {
+                Some((sent, at)) if !with_templates => Some((sent + 1, at)),
+                _ => Some((1, now_ms)),
+            };
+        }
+        datagrams
+    }
+}
+
+/// Exports flows to NetFlow/IPFIX collectors over UDP. Ended flows (FIN, RST or the
+/// `connection_timeout` inactive timeout) are exported when `TrafficState` reports them;
+/// long-lived flows are additionally exported every active timeout, each time covering
+/// the traffic since the previous export.
+pub struct Exporter {
+    collectors: Vec<(UdpSocket, SocketAddr)>,
+    encoder: Encoder,
+    active_timeout: Duration,
+    exported: HashMap<FlowKey, Exported>,
+    pending: Vec<NetflowRecord>,
+    /// Set when flow events were missed, so cursors of vanished flows must be pruned
+    lagged: bool,
+    /// Replaying a capture file: packet times come from the file rather than the wall
+    /// clock, so the uptime clock starts at the first flow and datagrams are stamped with
+    /// the time of their latest record
+    replay: bool,
+    /// Whether the uptime clock has been started from a flow (replay only)
+    booted: bool,
+    stats: Arc<ExportStats>,
+}
+
+impl Exporter {
+    /// Resolve the collectors and open a socket for each
+    pub async fn new(config: &Config) -> io::Result<Self> {
+        let mut collectors = Vec::new();
+        for collector in &config.netflow_collectors {
+            let addr = tokio::net::lookup_host(collector.as_str())
+                .await?
+                .next()
+                .ok_or_else(|| io::Error::other(format!("cannot resolve collector '{}'", collector)))?;
+            let bind: SocketAddr = match addr.ip() {
+                IpAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                IpAddr::V6(_) => ([0u16; 8], 0).into(),
+            };
+            collectors.push((UdpSocket::bind(bind).await?, addr));
+        }
+
+        Ok(Self {
+            collectors,
+            encoder: Encoder::new(
+                config.netflow_version,
+                config.netflow_source_id,
+                chrono::Utc::now().timestamp_millis(),
+            ),
+            active_timeout: Duration::from_secs(config.netflow_active_timeout_seconds),
+            exported: HashMap::new(),
+            pending: Vec::new(),
+            lagged: false,
+            replay: config.read_file.is_some(),
+            booted: false,
+            stats: Arc::new(ExportStats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Arc<ExportStats> {
+        self.stats.clone()
+    }
+
+    pub async fn run(mut self, traffic: Arc<TrafficState>, mut events: broadcast::Receiver<FlowEvent>) {
+        let mut ticker = interval(EXPORT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(FlowEvent::End(summary)) => {
+                        self.flow_ended(&summary);
+                        if self.pending.len() >= MAX_PENDING {
+                            self.send_pending().await;
+                        }
+                    }
+                    Ok(FlowEvent::Start { timestamp_ns, .. }) => self.flow_started(timestamp_ns),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        eprintln!("NetFlow exporter fell behind, {} flow records lost", missed);
+                        self.stats.dropped.fetch_add(missed, Ordering::Relaxed);
+                        self.lagged = true;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => {
+                    self.check_active_timeouts(&traffic);
+                    self.send_pending().await;
+                }
+            }
+        }
+    }
+
+    /// When replaying, start the uptime clock at the first flow
+    fn flow_started(&mut self, timestamp_ns: i64) {
+        if self.replay && !self.booted {
+            self.encoder.boot_ms = timestamp_ns / 1_000_000;
+            self.booted = true;
+        }
+    }
+
+    /// Export whatever the flow carried since its last active timeout export
+    fn flow_ended(&mut self, summary: &FlowSummary) {
+        let mut exported = self.exported.remove(&summary.key).unwrap_or_default();
+        let records = exported.delta(&summary.key, &Totals::from(summary));
+        self.pending.extend(records);
+    }
+
+    fn check_active_timeouts(&mut self, traffic: &TrafficState) {
+        let now = Instant::now();
+        for entry in traffic.connections.iter() {
+            let exported = self.exported.entry(entry.key().clone()).or_default();
+            match exported.at {
+                // Newly noticed: its first export is one active timeout from now
+                None => exported.at = Some(now),
+                Some(at) if now.duration_since(at) >= self.active_timeout => {
+                    let records = exported.delta(entry.key(), &Totals::from(entry.value()));
+                    exported.at = Some(now);
+                    self.pending.extend(records);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if self.lagged {
+            self.exported.retain(|key, _| traffic.connections.contains_key(key));
+            self.lagged = false;
+        }
+    }
+
+    async fn send_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let records = std::mem::take(&mut self.pending);
+        let now_ms = if self.replay {
+            records.iter().map(|record| record.end_ms).max().unwrap_or_default().max(self.encoder.boot_ms)
+        } else {
+            chrono::Utc::now().timestamp_millis()
+        };
+        let (datagrams, unsupported) = self.encoder.encode(&records, now_ms);
+        self.stats.rejected.fetch_add(unsupported, Ordering::Relaxed);
+        self.stats
+            .exported
+            .fetch_add(records.len() as u64 - unsupported, Ordering::Relaxed);
+
+        for datagram in &datagrams {
+            for (socket, addr) in &self.collectors {
+                if let Err(e) = socket.send_to(datagram, addr).await {
+                    eprintln!("Failed to send NetFlow datagram to {}: {}", addr, e);
+                    self.stats.failed_requests.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{PacketMetadata, Protocol, IPPROTO_TCP, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+    use crate::testing;
+
+    fn record(src: &str, dst: &str) -> NetflowRecord {
+        NetflowRecord {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            protocol: IPPROTO_TCP,
+            tcp_flags: TCP_SYN | TCP_ACK,
+            vlan: None,
+            bytes: 1500,
+            packets: 3,
+            start_ms: 1_000_500,
+            end_ms: 1_002_000,
+        }
+    }
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_be_bytes([buf[at], buf[at + 1]])
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_v5_datagram() {
+        let mut encoder = Encoder::new(NetflowVersion::V5, 0, 1_000_000);
+        let records = [record("10.0.0.1:40000", "10.0.0.2:443"), record("[::1]:40000", "[::2]:443")];
+
+        let (datagrams, unsupported) = encoder.encode(&records, 1_003_000);
+        assert_eq!(unsupported, 1);
+        let d = &datagrams[0];
+        assert_eq!(d.len(), 24 + 48);
+        assert_eq!(u16_at(d, 0), 5);
+        assert_eq!(u16_at(d, 2), 1);
+        assert_eq!(u32_at(d, 4), 3000);
+        assert_eq!(u32_at(d, 16), 0);
+        assert_eq!(&d[24..28], &[10, 0, 0, 1]);
+        assert_eq!(u32_at(d, 40), 3);
+        assert_eq!(u32_at(d, 44), 1500);
+        // First and last switched, relative to the exporter's start
+        assert_eq!(u32_at(d, 48), 500);
+        assert_eq!(u32_at(d, 52), 2000);
+        assert_eq!(u16_at(d, 56), 40000);
+        assert_eq!(d[61], TCP_SYN | TCP_ACK);
+        assert_eq!(d[62], IPPROTO_TCP);
+
+        // The sequence counts records sent before the datagram
+        let (datagrams, _) = encoder.encode(&records[..1], 1_003_000);
+        assert_eq!(u32_at(&datagrams[0], 16), 1);
+    }
+
+    #[test]
+    fn test_templated_datagrams() {
+        let records: Vec<_> = (0..100)
+            .map(|i| if i % 2 == 0 { record("10.0.0.1:1", "10.0.0.2:2") } else { record("[::1]:1", "[::2]:2") })
+            .collect();
+
+        let mut v9 = Encoder::new(NetflowVersion::V9, 7, 1_000_000);
+        let (datagrams, _) = v9.encode(&records, 1_003_000);
+        assert!(datagrams.len() > 1);
+        for (i, d) in datagrams.iter().enumerate() {
+            assert!(d.len() <= MAX_DATAGRAM);
+            assert_eq!(u16_at(d, 0), 9);
+            assert_eq!(u32_at(d, 12), i as u32);
+            assert_eq!(u32_at(d, 16), 7);
+        }
+        // Templates lead the first datagram only; data sets follow in family order
+        assert_eq!(u16_at(&datagrams[0], 20), 0);
+        assert_eq!(u16_at(&datagrams[1], 20), 256);
+
+        let mut ipfix = Encoder::new(NetflowVersion::Ipfix, 0, 1_000_000);
+        let (datagrams, _) = ipfix.encode(&records, 1_003_000);
+        assert_eq!(u16_at(&datagrams[0], 0), 10);
+        assert_eq!(u16_at(&datagrams[0], 2) as usize, datagrams[0].len());
+        assert_eq!(u16_at(&datagrams[0], 16), 2);
+        // IPFIX sequence numbers count data records
+        assert_eq!(u32_at(&datagrams[0], 8), 0);
+        assert!(u32_at(&datagrams[1], 8) > 1);
+    }
+
+    #[tokio::test]
+    async fn test_active_timeout_and_end() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            netflow_collectors: vec![collector.local_addr().unwrap().to_string()],
+            netflow_version: NetflowVersion::Ipfix,
+            netflow_active_timeout_seconds: 0,
+            ..Config::default()
+        };
+        let mut exporter = Exporter::new(&config).await.unwrap();
+        let traffic = TrafficState::new();
+        let mut events = traffic.subscribe();
+
+        let packet = |from_client: bool, tcp_flags, length| {
+            let (src_port, dst_port) = if from_client { (40000, 443) } else { (443, 40000) };
+            let (src, dst) = if from_client { ("10.0.0.1", "10.0.0.2") } else { ("10.0.0.2", "10.0.0.1") };
+            PacketMetadata {
+                tcp_flags,
+                ..testing::packet(Protocol::Tcp, src, src_port, dst, dst_port, length)
+            }
+        };
+        traffic.update(&packet(true, TCP_SYN, 60));
+        traffic.update(&packet(false, TCP_SYN | TCP_ACK, 60));
+
+        // The first check only notices the flow, the next one exports both directions
+        exporter.check_active_timeouts(&traffic);
+        assert!(exporter.pending.is_empty());
+        exporter.check_active_timeouts(&traffic);
+        assert_eq!(exporter.pending.len(), 2);
+        assert_eq!(exporter.pending[0].src, "10.0.0.1:40000".parse().unwrap());
+        assert_eq!(exporter.pending[1].bytes, 60);
+        assert_eq!(exporter.pending[0].tcp_flags, TCP_SYN);
+        assert_eq!(exporter.pending[1].tcp_flags, TCP_SYN | TCP_ACK);
+        exporter.send_pending().await;
+
+        let mut buf = [0u8; 2048];
+        let (len, _) = collector.recv_from(&mut buf).await.unwrap();
+        assert_eq!(u16_at(&buf, 0), 10);
+        assert_eq!(u16_at(&buf, 2) as usize, len);
+        assert_eq!(exporter.stats.exported.load(Ordering::Relaxed), 2);
+
+        // Only the traffic after the active export is left for the end record
+        traffic.update(&packet(true, TCP_ACK, 500));
+        traffic.update(&packet(true, TCP_FIN | TCP_ACK, 40));
+        traffic.update(&packet(false, TCP_FIN | TCP_ACK, 40));
+        let summary = loop {
+            if let FlowEvent::End(summary) = events.recv().await.unwrap() {
+                break summary;
+            }
+        };
+        exporter.flow_ended(&summary);
+        assert_eq!(exporter.pending.len(), 2);
+        assert_eq!((exporter.pending[0].packets, exporter.pending[0].bytes), (2, 540));
+        assert_eq!((exporter.pending[1].packets, exporter.pending[1].bytes), (1, 40));
+        assert!(exporter.exported.is_empty());
+
+        // Each direction carries the union of the flags it sent
+        let (datagrams, _) = Encoder::new(NetflowVersion::V5, 0, 0).encode(&exporter.pending, 0);
+        assert_eq!(datagrams[0][24 + 37], TCP_SYN | TCP_ACK | TCP_FIN);
+        assert_eq!(exporter.pending[1].tcp_flags, TCP_SYN | TCP_ACK | TCP_FIN);
+    }
+
+    #[tokio::test]
+    async fn test_replay_uptime() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            netflow_collectors: vec![collector.local_addr().unwrap().to_string()],
+            netflow_version: NetflowVersion::V5,
+            read_file: Some("capture.pcap".to_string()),
+            ..Config::default()
+        };
+        let mut exporter = Exporter::new(&config).await.unwrap();
+        let traffic = TrafficState::new();
+        let mut events = traffic.subscribe();
+
+        // Packets of a capture taken long before the replay
+        let packet = |from_client: bool, tcp_flags, timestamp: i64| {
+            let (src, dst) = if from_client { ("10.0.0.1", "10.0.0.2") } else { ("10.0.0.2", "10.0.0.1") };
+            let (src_port, dst_port) = if from_client { (40000, 443) } else { (443, 40000) };
+            PacketMetadata {
+                timestamp,
+                timestamp_ns: timestamp * 1_000_000,
+                tcp_flags,
+                ..testing::packet(Protocol::Tcp, src, src_port, dst, dst_port, 60)
+            }
+        };
+        traffic.update(&packet(true, TCP_SYN, 1_600_000_000_000));
+        traffic.update(&packet(false, TCP_RST | TCP_ACK, 1_600_000_002_500));
+        while let Ok(event) = events.try_recv() {
+            match event {
+                FlowEvent::Start { timestamp_ns, .. } => exporter.flow_started(timestamp_ns),
+                FlowEvent::End(summary) => exporter.flow_ended(&summary),
+            }
+        }
+        exporter.send_pending().await;
+
+        // Uptimes count from the first flow, and the datagram is stamped on the file's clock
+        let mut buf = [0u8; 2048];
+        collector.recv_from(&mut buf).await.unwrap();
+        assert_eq!(u16_at(&buf, 2), 2);
+        assert_eq!(u32_at(&buf, 4), 2500);
+        assert_eq!(u32_at(&buf, 8), 1_600_000_002);
+        assert_eq!((u32_at(&buf, 48), u32_at(&buf, 52)), (0, 2500));
+    }
+}
@@ -0,0 +1,516 @@
+use super::ipfix::{Flavor, TemplateCache};
+use super::{sflow, v5, NetflowRecord};
+use crate::health::{CaptureHealth, CaptureStatus, PipelineStats};
+use crate::sniffer::FilterConfig;
+use crate::state::{FlowKey, PacketMetadata, Protocol, TrafficState, TCP_FIN, TCP_RST};
+use crate::storage::{FlowKind, FlowRecord, Storage};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{interval, Duration};
+
+/// How often collected traffic is written to the `flows` table
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest UDP payload
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Most exporters tracked at once. Exporters are told apart by their UDP source address,
+/// which can be spoofed, so when full the one heard from longest ago is forgotten.
+const MAX_EXPORTERS: usize = 1024;
+
+/// Most NetFlow v9/IPFIX templates kept across all exporters
+const MAX_TEMPLATES: usize = 8192;
+
+/// Most flow directions waiting for the next flush. Traffic of further ones still shows
+/// up live but is not stored.
+const MAX_PENDING: usize = 100_000;
+
+/// Failed flushes in a row after which the pending records are dropped
+const MAX_FAILED_FLUSHES: u32 = 3;
+
+/// Traffic of one direction of a flow as reported by an exporter: `packet` describes the
+/// flow, with `length` the total size of its `packets` packets and `timestamp_ns` the time
+/// of the last one.
+#[derive(Debug, Clone)]
+struct Collected {
+    packet: PacketMetadata,
+    packets: u64,
+    first_ns: i64,
+}
+
+impl Collected {
+    fn from_record(record: &NetflowRecord, interface: &Arc<str>) -> Self {
+        Self {
+            packet: PacketMetadata {
+                timestamp: record.end_ms,
+                timestamp_ns: record.end_ms * 1_000_000,
+                interface: interface.clone(),
+                src_ip: record.src.ip(),
+                dst_ip: record.dst.ip(),
+                src_port: record.src.port(),
+                dst_port: record.dst.port(),
+                protocol: Protocol::from_ip_number(record.protocol, &record.src.ip()),
+                ip_protocol: record.protocol,
+                vlan: record.vlan,
+                length: record.bytes as usize,
+                // Flags are the union over the whole record, so SYN and ACK say nothing
+                // about who opened the connection; only keep the ones that end it
+                tcp_flags: record.tcp_flags & (TCP_FIN | TCP_RST),
+            },
+            packets: record.packets,
+            first_ns: record.start_ms * 1_000_000,
+        }
+    }
+
+    fn from_sample(sample: sflow::Sample) -> Self {
+        let rate = sample.sampling_rate as u64;
+        let first_ns = sample.packet.timestamp_ns;
+        let mut packet = sample.packet;
+        packet.length = (packet.length as u64 * rate) as usize;
+        Self {
+            packet,
+            packets: rate,
+            first_ns,
+        }
+    }
+
+    fn merge(&mut self, other: &Collected) {
+        self.packet.length += other.packet.length;
+        self.packet.tcp_flags |= other.packet.tcp_flags;
+        self.packet.timestamp = self.packet.timestamp.max(other.packet.timestamp);
+        self.packet.timestamp_ns = self.packet.timestamp_ns.max(other.packet.timestamp_ns);
+        self.packets += other.packets;
+        self.first_ns = self.first_ns.min(other.first_ns);
+    }
+}
+
+impl From<&Collected> for FlowRecord {
+    fn from(collected: &Collected) -> Self {
+        let packet = &collected.packet;
+        Self {
+            kind: FlowKind::Window,
+            start_ns: collected.first_ns,
+            end_ns: packet.timestamp_ns,
+            duration_ms: (packet.timestamp_ns - collected.first_ns) / 1_000_000,
+            interface: packet.interface.clone(),
+            vlan: packet.vlan,
+            src_ip: packet.src_ip,
+            dst_ip: packet.dst_ip,
+            src_port: packet.src_port,
+            dst_port: packet.dst_port,
+            protocol: packet.protocol,
+            ip_protocol: packet.ip_protocol,
+            bytes_sent: packet.length as u64,
+            bytes_received: 0,
+            packets_sent: collected.packets,
+            packets_received: 0,
+            state: None,
+            close_reason: None,
+        }
+    }
+}
+
+/// An exporter and when a datagram from it was last decoded
+struct Exporter {
+    interface: Arc<str>,
+    last_seen_ns: i64,
+}
+
+/// Address to listen on from a `udp://host:port` URL (the scheme may be left out)
+fn listen_address(url: &str) -> Result<&str, String> {
+    match url.split_once("://") {
+        None => Ok(url),
+        Some(("udp", address)) => Ok(address),
+        Some((scheme, _)) => Err(format!("unsupported collector scheme '{}', expected udp://", scheme)),
+    }
+}
+
+/// Receives NetFlow v5/v9, IPFIX and sFlow v5 from exporters in place of a packet capture.
+/// Decoded traffic feeds `TrafficState` like captured packets and is stored as `window`
+/// rows of the `flows` table, one per flow direction and flush interval. Each exporter
+/// shows up as an interface named after its address.
+pub struct Collector {
+    socket: UdpSocket,
+    /// Health entry of the collector, its URL
+    name: String,
+    filter: FilterConfig,
+    traffic: Arc<TrafficState>,
+    storage: Arc<Storage>,
+    health: Arc<CaptureHealth>,
+    stats: Arc<PipelineStats>,
+    templates: TemplateCache,
+    exporters: HashMap<IpAddr, Exporter>,
+    max_exporters: usize,
+    pending: HashMap<FlowKey, Collected>,
+    max_pending: usize,
+    failed_flushes: u32,
+}
+
+impl Collector {
+    /// Bind the listening socket. The collector must already be registered with `health` under `url`.
+    pub async fn bind(
+        url: &str,
+        filter: FilterConfig,
+        traffic: Arc<TrafficState>,
+        storage: Arc<Storage>,
+        health: Arc<CaptureHealth>,
+        stats: Arc<PipelineStats>,
+    ) -> io::Result<Self> {
+        let address = listen_address(url).map_err(io::Error::other)?;
+        Ok(Self {
+            socket: UdpSocket::bind(address).await?,
+            name: url.to_string(),
+            filter,
+            traffic,
+            storage,
+            health,
+            stats,
+            templates: TemplateCache::new(MAX_TEMPLATES),
+            exporters: HashMap::new(),
+            max_exporters: MAX_EXPORTERS,
+            pending: HashMap::new(),
+            max_pending: MAX_PENDING,
+            failed_flushes: 0,
+        })
+    }
+
+    pub async fn run(mut self) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut ticker = interval(FLUSH_INTERVAL);
+        self.health.set_status(&self.name, CaptureStatus::Running);
+
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => {
+                        let now_ns = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+                        self.receive(&buf[..len], from, now_ns);
+                    }
+                    Err(e) => tracing::warn!("Collector receive failed: {}", e),
+                },
+                _ = ticker.tick() => self.flush(),
+            }
+        }
+    }
+
+    /// Decode a datagram and account for the traffic it reports
+    fn receive(&mut self, datagram: &[u8], from: SocketAddr, now_ns: i64) {
+        let interface = match self.exporters.get(&from.ip()) {
+            Some(exporter) => exporter.interface.clone(),
+            None => Arc::from(from.ip().to_string()),
+        };
+        let collected = self.decode(datagram, from.ip(), now_ns, &interface);
+        self.stats.evicted_templates.store(self.templates.evicted(), Ordering::Relaxed);
+        let Some(collected) = collected else {
+            tracing::debug!("Ignoring malformed or unknown datagram from {}", from);
+            return;
+        };
+        self.seen(from.ip(), interface, now_ns);
+
+        for collected in collected {
+            let packet = &collected.packet;
+            if packet.protocol == Protocol::Unknown || collected.packets == 0 || !self.filter.matches(packet) {
+                continue;
+            }
+            self.traffic.update_many(packet, collected.packets, collected.first_ns);
+            let key = FlowKey::from_packet(packet);
+            let full = self.pending.len() >= self.max_pending;
+            match self.pending.get_mut(&key) {
+                Some(pending) => pending.merge(&collected),
+                None if full => {
+                    self.stats.dropped_records.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    self.pending.insert(key, collected);
+                }
+            }
+        }
+    }
+
+    /// Track an exporter a datagram was decoded from, forgetting the one heard from
+    /// longest ago (with its templates and interface counters) if there are too many
+    fn seen(&mut self, address: IpAddr, interface: Arc<str>, now_ns: i64) {
+        if !self.exporters.contains_key(&address) && self.exporters.len() >= self.max_exporters {
+            let stalest = self
+                .exporters
+                .iter()
+                .min_by_key(|(_, exporter)| exporter.last_seen_ns)
+                .map(|(address, _)| *address);
+            if let Some(evicted) = stalest.and_then(|stalest| self.exporters.remove_entry(&stalest)) {
+                tracing::debug!("Too many exporters, forgetting {}", evicted.0);
+                self.templates.forget(evicted.0);
+                self.traffic.interfaces.remove(&evicted.1.interface);
+                self.stats.evicted_exporters.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.exporters
+            .entry(address)
+            .or_insert(Exporter { interface, last_seen_ns: now_ns })
+            .last_seen_ns = now_ns;
+    }
+
+    /// Tell the formats apart by their version field: a 16-bit 5, 9 or 10 for NetFlow and
+    /// IPFIX, a 32-bit 5 for sFlow
+    fn decode(&mut self, datagram: &[u8], exporter: IpAddr, now_ns: i64, interface: &Arc<str>) -> Option<Vec<Collected>> {
+        let version = u16::from_be_bytes([*datagram.first()?, *datagram.get(1)?]);
+        let records = match version {
+            v5::VERSION => v5::decode(datagram)?,
+            0 => {
+                let samples = sflow::decode(datagram, now_ns, interface)?;
+                return Some(samples.into_iter().map(Collected::from_sample).collect());
+            }
+            version => self.templates.decode(Flavor::from_version(version)?, exporter, datagram)?,
+        };
+        Some(records.iter().map(|record| Collected::from_record(record, interface)).collect())
+    }
+
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let records: Vec<FlowRecord> = self.pending.values().map(FlowRecord::from).collect();
+        if self.storage.insert_flows(&records) {
+            self.pending.clear();
+            self.failed_flushes = 0;
+            return;
+        }
+        // Retried with the next flush, but not forever
+        self.failed_flushes += 1;
+        if self.failed_flushes >= MAX_FAILED_FLUSHES {
+            tracing::error!(
+                "Dropping {} collected flow records after {} failed writes",
+                records.len(),
+                self.failed_flushes
+            );
+            self.stats.dropped_records.fetch_add(records.len() as u64, Ordering::Relaxed);
+            self.pending.clear();
+            self.failed_flushes = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetflowVersion;
+    use crate::netflow::Encoder;
+    use crate::state::{CloseReason, IPPROTO_TCP, TCP_ACK, TCP_SYN};
+    use crate::testing;
+    use etherparse::PacketBuilder;
+
+    const EXPORTER: &str = "192.0.2.1:2055";
+    const NOW_MS: i64 = 1_700_000_003_000;
+
+    async fn test_collector(name: &str) -> (Collector, Arc<Storage>, std::path::PathBuf) {
+        let path = testing::temp_path(&format!("collector-{}.db", name));
+        let _ = std::fs::remove_file(&path);
+        let storage = Arc::new(Storage::new(path.to_str().unwrap()).unwrap());
+        let collector = Collector::bind(
+            "udp://127.0.0.1:0",
+            FilterConfig::default(),
+            Arc::new(TrafficState::new()),
+            storage.clone(),
+            Arc::new(CaptureHealth::new()),
+            Arc::new(PipelineStats::default()),
+        )
+        .await
+        .unwrap();
+        (collector, storage, path)
+    }
+
+    fn record(src: &str, dst: &str, packets: u64, bytes: u64) -> NetflowRecord {
+        NetflowRecord {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            protocol: IPPROTO_TCP,
+            tcp_flags: TCP_SYN | TCP_ACK | TCP_FIN,
+            vlan: None,
+            bytes,
+            packets,
+            start_ms: NOW_MS - 5000,
+            end_ms: NOW_MS - 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collects_netflow_and_ipfix() {
+        let records = [
+            record("10.0.0.1:40000", "10.0.0.2:443", 3, 1500),
+            record("10.0.0.2:443", "10.0.0.1:40000", 2, 800),
+        ];
+        for version in [NetflowVersion::V5, NetflowVersion::V9, NetflowVersion::Ipfix] {
+            let (mut collector, storage, path) = test_collector(&format!("{:?}", version)).await;
+            let mut encoder = Encoder::new(version, 1, NOW_MS - 60_000);
+            let (datagrams, _) = encoder.encode(&records, NOW_MS);
+            for datagram in &datagrams {
+                collector.receive(datagram, EXPORTER.parse().unwrap(), 0);
+            }
+
+            let traffic = &collector.traffic;
+            assert_eq!(traffic.total_packets.load(Ordering::Relaxed), 5, "{:?}", version);
+            assert_eq!(traffic.total_bytes.load(Ordering::Relaxed), 2300);
+            assert_eq!(traffic.interfaces.get("192.0.2.1").unwrap().packets, 5);
+            // FIN in both directions ends the conversation
+            let closed = traffic.recently_closed.lock().unwrap()[0].clone();
+            assert_eq!(closed.close_reason, CloseReason::Fin);
+            assert_eq!(closed.client, records[0].src);
+            assert_eq!((closed.packets_sent, closed.packets_received), (3, 2));
+            assert_eq!(closed.start_ns, (NOW_MS - 5000) * 1_000_000);
+
+            collector.flush();
+            let mut windows = storage.query_flows(10, Some(FlowKind::Window)).unwrap();
+            windows.sort_by_key(|window| window.src_port);
+            assert_eq!(windows.len(), 2);
+            assert_eq!((windows[0].src_port, windows[0].bytes_sent, windows[0].packets_sent), (443, 800, 2));
+            assert_eq!(windows[1].start_ns, (NOW_MS - 5000) * 1_000_000);
+            assert_eq!(windows[1].end_ns, (NOW_MS - 1000) * 1_000_000);
+
+            drop(storage);
+            drop(collector);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_templates() {
+        let (mut collector, storage, path) = test_collector("templates").await;
+        let records: Vec<_> = (0..60)
+            .map(|i| record(&format!("10.0.0.1:{}", 1000 + i), "10.0.0.2:53", 1, 100))
+            .collect();
+        let mut encoder = Encoder::new(NetflowVersion::Ipfix, 1, NOW_MS - 60_000);
+        let (datagrams, _) = encoder.encode(&records, NOW_MS);
+        assert!(datagrams.len() > 1);
+
+        let total = |collector: &Collector| collector.traffic.total_packets.load(Ordering::Relaxed);
+
+        // Data sets ahead of their template are skipped, later ones decode
+        collector.receive(&datagrams[1], EXPORTER.parse().unwrap(), 0);
+        assert_eq!(total(&collector), 0);
+        collector.receive(&datagrams[0], EXPORTER.parse().unwrap(), 0);
+        let first = total(&collector);
+        assert!(first > 0);
+        collector.receive(&datagrams[1], EXPORTER.parse().unwrap(), 0);
+        let second = total(&collector);
+        assert!(second > first);
+
+        // Templates are per exporter
+        collector.receive(&datagrams[1], "192.0.2.2:2055".parse().unwrap(), 0);
+        assert_eq!(total(&collector), second);
+
+        drop(storage);
+        drop(collector);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bounded_state() {
+        let (mut collector, storage, path) = test_collector("bounded").await;
+        collector.max_exporters = 2;
+        collector.templates = TemplateCache::new(2);
+        let records = [
+            record("10.0.0.1:40000", "10.0.0.2:443", 3, 1500),
+            record("10.0.0.2:443", "10.0.0.1:40000", 2, 800),
+        ];
+
+        // Each exporter announces two templates, evicting those of the previous one; the
+        // third exporter replaces the one heard from longest ago
+        for (i, exporter) in ["192.0.2.1:2055", "192.0.2.2:2055", "192.0.2.3:2055"].into_iter().enumerate() {
+            let mut encoder = Encoder::new(NetflowVersion::Ipfix, 1, NOW_MS - 60_000);
+            let (datagrams, _) = encoder.encode(&records, NOW_MS);
+            collector.receive(&datagrams[0], exporter.parse().unwrap(), i as i64);
+        }
+        assert_eq!(collector.stats.evicted_templates.load(Ordering::Relaxed), 4);
+        assert_eq!(collector.stats.evicted_exporters.load(Ordering::Relaxed), 1);
+        assert!(!collector.exporters.contains_key(&"192.0.2.1".parse::<IpAddr>().unwrap()));
+        assert!(collector.traffic.interfaces.get("192.0.2.1").is_none());
+        assert_eq!(collector.traffic.interfaces.get("192.0.2.3").unwrap().packets, 5);
+
+        // Flow directions beyond the limit still count live but are not stored
+        collector.pending.clear();
+        collector.max_pending = 1;
+        let mut encoder = Encoder::new(NetflowVersion::V5, 1, NOW_MS - 60_000);
+        let (datagrams, _) = encoder.encode(&records, NOW_MS);
+        collector.receive(&datagrams[0], EXPORTER.parse().unwrap(), 3);
+        assert_eq!(collector.pending.len(), 1);
+        assert_eq!(collector.stats.dropped_records.load(Ordering::Relaxed), 1);
+        assert_eq!(collector.traffic.interfaces.get("192.0.2.1").unwrap().packets, 5);
+
+        drop(storage);
+        drop(collector);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// An sFlow v5 datagram with one flow sample holding an Ethernet frame
+    fn sflow_datagram(sampling_rate: u32, frame: &[u8]) -> Vec<u8> {
+        let mut header = Vec::new();
+        for value in [1, frame.len() as u32 + 4, 4, frame.len() as u32] {
+            header.extend_from_slice(&value.to_be_bytes());
+        }
+        header.extend_from_slice(frame);
+        while header.len() % 4 != 0 {
+            header.push(0);
+        }
+
+        let mut sample = Vec::new();
+        // sequence, source ID, rate, pool, drops, input, output, one record
+        for value in [1, 3, sampling_rate, 4000, 0, 3, 4, 1, 1, header.len() as u32] {
+            sample.extend_from_slice(&value.to_be_bytes());
+        }
+        sample.extend_from_slice(&header);
+
+        let mut datagram = Vec::new();
+        // version, IPv4 agent 192.0.2.1, sub-agent, sequence, uptime, one sample
+        for value in [5, 1, 0xc000_0201, 0, 7, 1000, 1, 1, sample.len() as u32] {
+            datagram.extend_from_slice(&value.to_be_bytes());
+        }
+        datagram.extend_from_slice(&sample);
+        datagram
+    }
+
+    #[tokio::test]
+    async fn test_collects_sflow() {
+        let (mut collector, storage, path) = test_collector("sflow").await;
+        let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [7, 8, 9, 10, 11, 12])
+            .ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(40000, 443, 1, 1024);
+        let mut frame = Vec::new();
+        builder.write(&mut frame, &[0; 46]).unwrap();
+
+        let datagram = sflow_datagram(100, &frame);
+        collector.receive(&datagram, EXPORTER.parse().unwrap(), NOW_MS * 1_000_000);
+        collector.receive(&datagram, EXPORTER.parse().unwrap(), (NOW_MS + 1000) * 1_000_000);
+
+        // Each sample stands for `sampling_rate` packets
+        let traffic = &collector.traffic;
+        assert_eq!(traffic.total_packets.load(Ordering::Relaxed), 200);
+        // frame_length on the wire includes the FCS
+        assert_eq!(traffic.total_bytes.load(Ordering::Relaxed), 200 * (frame.len() as u64 + 4));
+        let connection = traffic.connections.iter().next().unwrap();
+        assert_eq!(connection.client, "10.0.0.1:40000".parse().unwrap());
+        drop(connection);
+
+        collector.flush();
+        let windows = storage.query_flows(10, None).unwrap();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].packets_sent, 200);
+        assert_eq!(windows[0].duration_ms, 1000);
+
+        // Other sFlow versions are not understood
+        assert!(collector.decode(&[0, 0, 0, 4], "192.0.2.1".parse().unwrap(), 0, &Arc::from("x")).is_none());
+
+        drop(storage);
+        drop(collector);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_listen_address() {
+        assert_eq!(listen_address("udp://0.0.0.0:2055"), Ok("0.0.0.0:2055"));
+        assert_eq!(listen_address("[::]:6343"), Ok("[::]:6343"));
+        assert!(listen_address("tcp://0.0.0.0:2055").is_err());
+    }
+}
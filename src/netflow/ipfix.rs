@@ -0,0 +1,513 @@
+// Template-based formats: NetFlow v9 (RFC 3954) and its successor IPFIX (RFC 7011).
+// Both describe records with templates of (information element, length) pairs and
+// share element numbers; they differ in headers, set IDs and how times are encoded.
+
+use super::reader::{uint, Reader};
+use super::NetflowRecord;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Information elements, numbered as in the IANA IPFIX registry (same in NetFlow v9)
+const IN_BYTES: u16 = 1;
+const IN_PKTS: u16 = 2;
+const PROTOCOL: u16 = 4;
+const TCP_FLAGS: u16 = 6;
+const L4_SRC_PORT: u16 = 7;
+const IPV4_SRC_ADDR: u16 = 8;
+const L4_DST_PORT: u16 = 11;
+const IPV4_DST_ADDR: u16 = 12;
+const LAST_SWITCHED: u16 = 21;
+const FIRST_SWITCHED: u16 = 22;
+const IPV6_SRC_ADDR: u16 = 27;
+const IPV6_DST_ADDR: u16 = 28;
+const SRC_VLAN: u16 = 58;
+const OCTET_TOTAL_COUNT: u16 = 85;
+const PACKET_TOTAL_COUNT: u16 = 86;
+const FLOW_START_SECONDS: u16 = 150;
+const FLOW_END_SECONDS: u16 = 151;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+const SYSTEM_INIT_TIME_MILLISECONDS: u16 = 160;
+const DOT1Q_VLAN_ID: u16 = 243;
+
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+
+/// Lowest set ID of a data set; lower IDs are template, options template or reserved sets
+const MIN_DATA_SET_ID: u16 = 256;
+
+/// Set header: set ID and length
+const SET_HEADER_LEN: usize = 4;
+
+/// Template length of an IPFIX variable-length field; each record carries the actual length
+const VARIABLE_LENGTH: u16 = 65535;
+
+/// Set in an IPFIX element ID when an enterprise number follows
+const ENTERPRISE_BIT: u16 = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    V9,
+    Ipfix,
+}
+
+impl Flavor {
+    pub fn from_version(version: u16) -> Option<Self> {
+        match version {
+            9 => Some(Flavor::V9),
+            10 => Some(Flavor::Ipfix),
+            _ => None,
+        }
+    }
+
+    fn version(&self) -> u16 {
+        match self {
+            Flavor::V9 => 9,
+            Flavor::Ipfix => 10,
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        match self {
+            Flavor::V9 => 20,
+            Flavor::Ipfix => 16,
+        }
+    }
+
+    fn template_set_id(&self) -> u16 {
+        match self {
+            Flavor::V9 => 0,
+            Flavor::Ipfix => 2,
+        }
+    }
+}
+
+/// Message header values that change between messages
+pub struct Header {
+    /// v9: messages sent before this one; IPFIX: data records sent before this one
+    pub sequence: u32,
+    /// v9 source ID or IPFIX observation domain ID
+    pub source_id: u32,
+    pub now_ms: i64,
+    /// Exporter start, the reference for v9 uptime timestamps
+    pub boot_ms: i64,
+}
+
+struct Template {
+    id: u16,
+    fields: Vec<(u16, u16)>,
+}
+
+impl Template {
+    fn record_len(&self) -> usize {
+        self.fields.iter().map(|(_, len)| *len as usize).sum()
+    }
+}
+
+/// The IPv4 and IPv6 flow templates. v9 carries times as milliseconds of exporter
+/// uptime, IPFIX as absolute milliseconds.
+fn templates(flavor: Flavor) -> [Template; 2] {
+    let times = match flavor {
+        Flavor::V9 => [(FIRST_SWITCHED, 4), (LAST_SWITCHED, 4)],
+        Flavor::Ipfix => [(FLOW_START_MILLISECONDS, 8), (FLOW_END_MILLISECONDS, 8)],
+    };
+    let common = [
+        (L4_SRC_PORT, 2),
+        (L4_DST_PORT, 2),
+        (PROTOCOL, 1),
+        (TCP_FLAGS, 1),
+        (SRC_VLAN, 2),
+        (IN_BYTES, 8),
+        (IN_PKTS, 8),
+    ];
+    let template = |id, src, dst, addr_len| {
+        let mut fields = vec![(src, addr_len), (dst, addr_len)];
+        fields.extend_from_slice(&common);
+        fields.extend_from_slice(&times);
+        Template { id, fields }
+    };
+    [
+        template(IPV4_TEMPLATE_ID, IPV4_SRC_ADDR, IPV4_DST_ADDR, 4),
+        template(IPV6_TEMPLATE_ID, IPV6_SRC_ADDR, IPV6_DST_ADDR, 16),
+    ]
+}
+
+/// Encode as many `records` as fit in `max_len` bytes into one message, preceded by the
+/// templates when `with_templates` is set. Records should be grouped by address family.
+/// Returns the message and the number of records it holds.
+pub fn encode(
+    flavor: Flavor,
+    records: &[NetflowRecord],
+    with_templates: bool,
+    header: &Header,
+    max_len: usize,
+) -> (Vec<u8>, usize) {
+    let templates = templates(flavor);
+    let mut buf = vec![0; flavor.header_len()];
+    // v9 counts template and data records in its header
+    let mut count = 0u16;
+
+    if with_templates {
+        let start = begin_set(&mut buf, flavor.template_set_id());
+        for template in &templates {
+            buf.extend_from_slice(&template.id.to_be_bytes());
+            buf.extend_from_slice(&(template.fields.len() as u16).to_be_bytes());
+            for (element, len) in &template.fields {
+                buf.extend_from_slice(&element.to_be_bytes());
+                buf.extend_from_slice(&len.to_be_bytes());
+            }
+            count += 1;
+        }
+        end_set(&mut buf, start);
+    }
+
+    let mut used = 0;
+    let mut open_set: Option<(usize, u16)> = None;
+    for record in records {
+        let template = match record.src.ip() {
+            IpAddr::V4(_) => &templates[0],
+            IpAddr::V6(_) => &templates[1],
+        };
+        let new_set = open_set.is_none_or(|(_, id)| id != template.id);
+        // Room for the record, a new set header and the final set's padding
+        let needed = template.record_len() + if new_set { SET_HEADER_LEN } else { 0 } + 3;
+        if used > 0 && buf.len() + needed > max_len {
+            break;
+        }
+        if new_set {
+            if let Some((start, _)) = open_set {
+                end_set(&mut buf, start);
+            }
+            open_set = Some((begin_set(&mut buf, template.id), template.id));
+        }
+        for (element, len) in &template.fields {
+            write_field(&mut buf, *element, *len, record, header.boot_ms);
+        }
+        used += 1;
+        count += 1;
+    }
+    if let Some((start, _)) = open_set {
+        end_set(&mut buf, start);
+    }
+
+    let mut head = Vec::with_capacity(flavor.header_len());
+    head.extend_from_slice(&flavor.version().to_be_bytes());
+    match flavor {
+        Flavor::V9 => {
+            head.extend_from_slice(&count.to_be_bytes());
+            head.extend_from_slice(&super::uptime_ms(header.now_ms, header.boot_ms).to_be_bytes());
+        }
+        Flavor::Ipfix => head.extend_from_slice(&(buf.len() as u16).to_be_bytes()),
+    }
+    head.extend_from_slice(&((header.now_ms / 1000) as u32).to_be_bytes());
+    head.extend_from_slice(&header.sequence.to_be_bytes());
+    head.extend_from_slice(&header.source_id.to_be_bytes());
+    buf[..head.len()].copy_from_slice(&head);
+
+    (buf, used)
+}
+
+fn begin_set(buf: &mut Vec<u8>, id: u16) -> usize {
+    let start = buf.len();
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    start
+}
+
+/// Pad the set to a 4-byte boundary and fill in its length
+fn end_set(buf: &mut Vec<u8>, start: usize) {
+    while (buf.len() - start) % 4 != 0 {
+        buf.push(0);
+    }
+    let len = (buf.len() - start) as u16;
+    buf[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+fn write_field(buf: &mut Vec<u8>, element: u16, len: u16, record: &NetflowRecord, boot_ms: i64) {
+    let start = buf.len();
+    match element {
+        IPV4_SRC_ADDR | IPV6_SRC_ADDR => write_addr(buf, record.src.ip()),
+        IPV4_DST_ADDR | IPV6_DST_ADDR => write_addr(buf, record.dst.ip()),
+        L4_SRC_PORT => buf.extend_from_slice(&record.src.port().to_be_bytes()),
+        L4_DST_PORT => buf.extend_from_slice(&record.dst.port().to_be_bytes()),
+        PROTOCOL => buf.push(record.protocol),
+        TCP_FLAGS => buf.push(record.tcp_flags),
+        SRC_VLAN => buf.extend_from_slice(&record.vlan.unwrap_or(0).to_be_bytes()),
+        IN_BYTES => buf.extend_from_slice(&record.bytes.to_be_bytes()),
+        IN_PKTS => buf.extend_from_slice(&record.packets.to_be_bytes()),
+        FIRST_SWITCHED => buf.extend_from_slice(&super::uptime_ms(record.start_ms, boot_ms).to_be_bytes()),
+        LAST_SWITCHED => buf.extend_from_slice(&super::uptime_ms(record.end_ms, boot_ms).to_be_bytes()),
+        FLOW_START_MILLISECONDS => buf.extend_from_slice(&(record.start_ms as u64).to_be_bytes()),
+        FLOW_END_MILLISECONDS => buf.extend_from_slice(&(record.end_ms as u64).to_be_bytes()),
+        _ => {}
+    }
+    debug_assert_eq!(buf.len() - start, len as usize, "element {}", element);
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(addr) => buf.extend_from_slice(&addr.octets()),
+        IpAddr::V6(addr) => buf.extend_from_slice(&addr.octets()),
+    }
+}
+
+/// Field of a template received from an exporter
+struct ReceivedField {
+    /// `None` for enterprise-specific elements, which are skipped
+    element: Option<u16>,
+    len: u16,
+}
+
+/// A template and when it was last announced
+struct Learned {
+    fields: Vec<ReceivedField>,
+    /// Position among all announcements, for evicting the stalest template
+    announced: u64,
+}
+
+/// Templates announced by exporters. Template IDs are only unique per exporter and
+/// source ID (observation domain), so both are part of the key. Exporters are told apart
+/// by their UDP source address, which can be spoofed, so the number of templates is
+/// capped; when full, the template announced longest ago is evicted.
+pub struct TemplateCache {
+    templates: HashMap<(IpAddr, u32, u16), Learned>,
+    limit: usize,
+    announcements: u64,
+    evicted: u64,
+}
+
+/// When a datagram was exported, and for v9 the exporter's uptime at that moment
+struct Clock {
+    export_ms: i64,
+    uptime_ms: Option<u32>,
+}
+
+impl TemplateCache {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            templates: HashMap::new(),
+            limit,
+            announcements: 0,
+            evicted: 0,
+        }
+    }
+
+    /// Templates evicted to stay within the limit
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Drop the templates of `exporter`
+    pub fn forget(&mut self, exporter: IpAddr) {
+        self.templates.retain(|(address, _, _), _| *address != exporter);
+    }
+
+    /// Decode a v9 or IPFIX datagram from `exporter`, learning the templates it carries.
+    /// Data sets whose template has not been received yet are skipped, as are options
+    /// templates and their data. Returns `None` if the datagram is malformed.
+    pub fn decode(&mut self, flavor: Flavor, exporter: IpAddr, datagram: &[u8]) -> Option<Vec<NetflowRecord>> {
+        let mut reader = Reader::new(datagram);
+        if reader.u16()? != flavor.version() {
+            return None;
+        }
+        let clock = match flavor {
+            Flavor::V9 => {
+                // record count
+                reader.u16()?;
+                let uptime_ms = reader.u32()?;
+                Clock {
+                    export_ms: reader.u32()? as i64 * 1000,
+                    uptime_ms: Some(uptime_ms),
+                }
+            }
+            Flavor::Ipfix => {
+                let len = reader.u16()? as usize;
+                let export_ms = reader.u32()? as i64 * 1000;
+                // Continue at the sequence number (offset 8), ignoring anything past the message length
+                reader = Reader::new(datagram.get(8..len)?);
+                Clock {
+                    export_ms,
+                    uptime_ms: None,
+                }
+            }
+        };
+        // sequence number
+        reader.u32()?;
+        let source_id = reader.u32()?;
+
+        let mut records = Vec::new();
+        while reader.remaining() >= SET_HEADER_LEN {
+            let id = reader.u16()?;
+            let len = reader.u16()? as usize;
+            let mut set = reader.sub(len.checked_sub(SET_HEADER_LEN)?)?;
+            if id == flavor.template_set_id() {
+                self.learn(flavor, exporter, source_id, &mut set)?;
+            } else if id >= MIN_DATA_SET_ID {
+                match self.templates.get(&(exporter, source_id, id)) {
+                    Some(learned) => decode_data(&mut set, &learned.fields, &clock, &mut records),
+                    None => tracing::debug!("No template {} from {} (source {}) yet", id, exporter, source_id),
+                }
+            }
+        }
+        Some(records)
+    }
+
+    fn learn(&mut self, flavor: Flavor, exporter: IpAddr, source_id: u32, set: &mut Reader) -> Option<()> {
+        // Anything shorter than a template header is padding
+        while set.remaining() >= 4 {
+            let id = set.u16()?;
+            let count = set.u16()?;
+            if count == 0 {
+                // IPFIX template withdrawal
+                self.templates.remove(&(exporter, source_id, id));
+                continue;
+            }
+            let mut fields = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let element = set.u16()?;
+                let len = set.u16()?;
+                let enterprise = flavor == Flavor::Ipfix && element & ENTERPRISE_BIT != 0;
+                if enterprise {
+                    set.u32()?;
+                }
+                fields.push(ReceivedField {
+                    element: (!enterprise).then_some(element),
+                    len,
+                });
+            }
+            let key = (exporter, source_id, id);
+            if !self.templates.contains_key(&key) && self.templates.len() >= self.limit {
+                self.evict_stalest();
+            }
+            self.announcements += 1;
+            let announced = self.announcements;
+            self.templates.insert(key, Learned { fields, announced });
+        }
+        Some(())
+    }
+
+    fn evict_stalest(&mut self) {
+        let stalest = self.templates.iter().min_by_key(|(_, learned)| learned.announced).map(|(key, _)| *key);
+        if let Some(key) = stalest {
+            self.templates.remove(&key);
+            self.evicted += 1;
+        }
+    }
+}
+
+/// Decode the records of a data set, stopping at the padding or a truncated record
+fn decode_data(set: &mut Reader, fields: &[ReceivedField], clock: &Clock, records: &mut Vec<NetflowRecord>) {
+    let min_len: usize = fields
+        .iter()
+        .map(|field| if field.len == VARIABLE_LENGTH { 1 } else { field.len as usize })
+        .sum();
+    if min_len == 0 {
+        return;
+    }
+    while set.remaining() >= min_len {
+        let Some(values) = read_values(set, fields) else {
+            break;
+        };
+        // Records without addresses (e.g. per-interface counters) are not flows
+        if let Some(record) = values.into_record(clock) {
+            records.push(record);
+        }
+    }
+}
+
+fn read_values(set: &mut Reader, fields: &[ReceivedField]) -> Option<Values> {
+    let mut values = Values::default();
+    for field in fields {
+        let len = match field.len {
+            VARIABLE_LENGTH => match set.u8()? {
+                255 => set.u16()? as usize,
+                len => len as usize,
+            },
+            len => len as usize,
+        };
+        let bytes = set.bytes(len)?;
+        if let Some(element) = field.element {
+            values.set(element, bytes);
+        }
+    }
+    Some(values)
+}
+
+/// Elements of one data record that map onto a `NetflowRecord`
+#[derive(Default)]
+struct Values {
+    src: Option<IpAddr>,
+    dst: Option<IpAddr>,
+    src_port: u16,
+    dst_port: u16,
+    protocol: u8,
+    tcp_flags: u8,
+    vlan: Option<u16>,
+    bytes: u64,
+    packets: u64,
+    start_ms: Option<i64>,
+    end_ms: Option<i64>,
+    start_uptime_ms: Option<u32>,
+    end_uptime_ms: Option<u32>,
+    init_ms: Option<i64>,
+}
+
+impl Values {
+    fn set(&mut self, element: u16, bytes: &[u8]) {
+        let value = uint(bytes);
+        match element {
+            IPV4_SRC_ADDR | IPV6_SRC_ADDR => self.src = read_addr(bytes),
+            IPV4_DST_ADDR | IPV6_DST_ADDR => self.dst = read_addr(bytes),
+            L4_SRC_PORT => self.src_port = value as u16,
+            L4_DST_PORT => self.dst_port = value as u16,
+            PROTOCOL => self.protocol = value as u8,
+            // IPFIX allows 16 bits; the classic flags are the low byte
+            TCP_FLAGS => self.tcp_flags = value as u8,
+            SRC_VLAN | DOT1Q_VLAN_ID => self.vlan = Some(value as u16 & 0x0fff).filter(|vlan| *vlan != 0),
+            IN_BYTES | OCTET_TOTAL_COUNT => self.bytes = value,
+            IN_PKTS | PACKET_TOTAL_COUNT => self.packets = value,
+            FIRST_SWITCHED => self.start_uptime_ms = Some(value as u32),
+            LAST_SWITCHED => self.end_uptime_ms = Some(value as u32),
+            FLOW_START_SECONDS => self.start_ms = Some(value as i64 * 1000),
+            FLOW_END_SECONDS => self.end_ms = Some(value as i64 * 1000),
+            FLOW_START_MILLISECONDS => self.start_ms = Some(value as i64),
+            FLOW_END_MILLISECONDS => self.end_ms = Some(value as i64),
+            SYSTEM_INIT_TIME_MILLISECONDS => self.init_ms = Some(value as i64),
+            _ => {}
+        }
+    }
+
+    /// Build the record, resolving uptime timestamps to wall-clock time. Times the
+    /// exporter did not send default to the export time.
+    fn into_record(self, clock: &Clock) -> Option<NetflowRecord> {
+        let resolve = |ms: Option<i64>, uptime_ms: Option<u32>| {
+            ms.or_else(|| match (self.init_ms, clock.uptime_ms) {
+                (Some(init_ms), _) => Some(init_ms + uptime_ms? as i64),
+                (None, Some(export_uptime_ms)) => {
+                    Some(super::absolute_ms(clock.export_ms, export_uptime_ms, uptime_ms?))
+                }
+                (None, None) => None,
+            })
+            .unwrap_or(clock.export_ms)
+        };
+        Some(NetflowRecord {
+            start_ms: resolve(self.start_ms, self.start_uptime_ms),
+            end_ms: resolve(self.end_ms, self.end_uptime_ms),
+            src: SocketAddr::new(self.src?, self.src_port),
+            dst: SocketAddr::new(self.dst?, self.dst_port),
+            protocol: self.protocol,
+            tcp_flags: self.tcp_flags,
+            vlan: self.vlan,
+            bytes: self.bytes,
+            packets: self.packets,
+        })
+    }
+}
+
+fn read_addr(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into()),
+        _ => None,
+    }
+}
@@ -0,0 +1,46 @@
+/// Big-endian cursor over a received datagram. Every read returns `None` once the
+/// data runs out, so decoders can bail out of truncated input with `?`.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    /// A nested structure of `len` bytes, read with its own cursor
+    pub fn sub(&mut self, len: usize) -> Option<Reader<'a>> {
+        self.bytes(len).map(Reader::new)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+/// Unsigned integer of any width up to 8 bytes (IPFIX reduced-size encoding)
+pub fn uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, byte| (value << 8) | *byte as u64)
+}
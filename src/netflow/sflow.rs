@@ -0,0 +1,86 @@
+// sFlow version 5 (https://sflow.org/sflow_version_5.txt). Agents send XDR-encoded
+// datagrams of samples: flow samples carry the leading bytes of one packet out of every
+// `sampling_rate`, counter samples carry interface statistics and are ignored here.
+
+use super::reader::Reader;
+use crate::sniffer;
+use crate::state::PacketMetadata;
+use pcap::Linktype;
+use std::sync::Arc;
+
+pub const VERSION: u32 = 5;
+
+// Sample formats (enterprise 0)
+const FLOW_SAMPLE: u32 = 1;
+const EXPANDED_FLOW_SAMPLE: u32 = 3;
+
+/// Flow record holding the sampled packet's headers
+const RAW_PACKET_HEADER: u32 = 1;
+
+// Header protocols of a raw packet header record
+const HEADER_ETHERNET: u32 = 1;
+const HEADER_IPV4: u32 = 11;
+const HEADER_IPV6: u32 = 12;
+
+/// A sampled packet, standing for `sampling_rate` packets like it
+pub struct Sample {
+    pub packet: PacketMetadata,
+    pub sampling_rate: u32,
+}
+
+/// Decode the sampled packets of a datagram. sFlow carries no wall-clock time, so packets
+/// are stamped with `now_ns`. Returns `None` if the datagram is malformed.
+pub fn decode(datagram: &[u8], now_ns: i64, interface: &Arc<str>) -> Option<Vec<Sample>> {
+    let mut reader = Reader::new(datagram);
+    if reader.u32()? != VERSION {
+        return None;
+    }
+    let agent_len = match reader.u32()? {
+        1 => 4,
+        2 => 16,
+        _ => return None,
+    };
+    reader.bytes(agent_len)?;
+    // sub-agent ID, sequence number, uptime
+    reader.bytes(12)?;
+
+    let mut samples = Vec::new();
+    for _ in 0..reader.u32()? {
+        let format = reader.u32()?;
+        let len = reader.u32()? as usize;
+        let mut sample = reader.sub(len)?;
+        // Skip to the sampling rate, then past the pool, drop and interface fields
+        let (before_rate, after_rate) = match format {
+            FLOW_SAMPLE => (8, 16),
+            EXPANDED_FLOW_SAMPLE => (12, 24),
+            _ => continue,
+        };
+        sample.bytes(before_rate)?;
+        let sampling_rate = sample.u32()?.max(1);
+        sample.bytes(after_rate)?;
+
+        for _ in 0..sample.u32()? {
+            let format = sample.u32()?;
+            let len = sample.u32()? as usize;
+            let mut record = sample.sub(len)?;
+            if format != RAW_PACKET_HEADER {
+                continue;
+            }
+            let linktype = match record.u32()? {
+                HEADER_ETHERNET => Linktype::ETHERNET,
+                HEADER_IPV4 => Linktype::IPV4,
+                HEADER_IPV6 => Linktype::IPV6,
+                _ => continue,
+            };
+            let frame_length = record.u32()?;
+            // bytes stripped from the header (e.g. the FCS)
+            record.u32()?;
+            let header_len = record.u32()? as usize;
+            let header = record.bytes(header_len)?;
+            if let Some(packet) = sniffer::parse_packet(linktype, header, frame_length, now_ns, interface) {
+                samples.push(Sample { packet, sampling_rate });
+            }
+        }
+    }
+    Some(samples)
+}
@@ -0,0 +1,103 @@
+use super::reader::Reader;
+use super::NetflowRecord;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+pub const VERSION: u16 = 5;
+
+/// Records per datagram allowed by the format
+pub const MAX_RECORDS: usize = 30;
+
+const HEADER_LEN: usize = 24;
+const RECORD_LEN: usize = 48;
+
+/// Encode up to `MAX_RECORDS` IPv4 records into one datagram. `flow_sequence` counts the
+/// records sent before this datagram; record times are relative to `boot_ms`.
+pub fn encode(records: &[&NetflowRecord], flow_sequence: u32, now_ms: i64, boot_ms: i64) -> Vec<u8> {
+    debug_assert!(records.len() <= MAX_RECORDS);
+    let mut buf = Vec::with_capacity(HEADER_LEN + records.len() * RECORD_LEN);
+
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&super::uptime_ms(now_ms, boot_ms).to_be_bytes());
+    buf.extend_from_slice(&((now_ms / 1000) as u32).to_be_bytes());
+    buf.extend_from_slice(&(((now_ms % 1000) * 1_000_000) as u32).to_be_bytes());
+    buf.extend_from_slice(&flow_sequence.to_be_bytes());
+    // engine type, engine ID, sampling interval (counts are unsampled)
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+
+    for record in records {
+        let (IpAddr::V4(src), IpAddr::V4(dst)) = (record.src.ip(), record.dst.ip()) else {
+            continue;
+        };
+        buf.extend_from_slice(&src.octets());
+        buf.extend_from_slice(&dst.octets());
+        // next hop, input and output interface index
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&saturate(record.packets).to_be_bytes());
+        buf.extend_from_slice(&saturate(record.bytes).to_be_bytes());
+        buf.extend_from_slice(&super::uptime_ms(record.start_ms, boot_ms).to_be_bytes());
+        buf.extend_from_slice(&super::uptime_ms(record.end_ms, boot_ms).to_be_bytes());
+        buf.extend_from_slice(&record.src.port().to_be_bytes());
+        buf.extend_from_slice(&record.dst.port().to_be_bytes());
+        buf.push(0);
+        buf.push(record.tcp_flags);
+        buf.push(record.protocol);
+        // ToS, source and destination AS, prefix masks, padding
+        buf.extend_from_slice(&[0; 9]);
+    }
+    buf
+}
+
+/// v5 counters are 32 bits wide
+fn saturate(value: u64) -> u32 {
+    value.min(u32::MAX as u64) as u32
+}
+
+/// Decode a v5 datagram. Counters are scaled up by the sampling interval in the header.
+/// Returns `None` if the datagram is truncated.
+pub fn decode(datagram: &[u8]) -> Option<Vec<NetflowRecord>> {
+    let mut reader = Reader::new(datagram);
+    if reader.u16()? != VERSION {
+        return None;
+    }
+    let count = reader.u16()? as usize;
+    let uptime = reader.u32()?;
+    let secs = reader.u32()? as i64;
+    let nsecs = reader.u32()? as i64;
+    let export_ms = secs * 1000 + nsecs / 1_000_000;
+    // flow sequence, engine type and ID
+    reader.bytes(6)?;
+    // Top two bits are the sampling mode, the rest the interval
+    let sampling = (reader.u16()? & 0x3fff).max(1) as u64;
+
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut record = reader.sub(RECORD_LEN)?;
+        let src = Ipv4Addr::from(record.u32()?);
+        let dst = Ipv4Addr::from(record.u32()?);
+        // next hop, input and output interface index
+        record.bytes(8)?;
+        let packets = record.u32()? as u64 * sampling;
+        let bytes = record.u32()? as u64 * sampling;
+        let first = record.u32()?;
+        let last = record.u32()?;
+        let src_port = record.u16()?;
+        let dst_port = record.u16()?;
+        record.u8()?;
+        let tcp_flags = record.u8()?;
+        let protocol = record.u8()?;
+
+        records.push(NetflowRecord {
+            src: SocketAddr::new(src.into(), src_port),
+            dst: SocketAddr::new(dst.into(), dst_port),
+            protocol,
+            tcp_flags,
+            vlan: None,
+            bytes,
+            packets,
+            start_ms: super::absolute_ms(export_ms, uptime, first),
+            end_ms: super::absolute_ms(export_ms, uptime, last),
+        });
+    }
+    Some(records)
+}
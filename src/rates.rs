@@ -0,0 +1,175 @@
+// Per-second traffic rates over the last hour. A task samples the traffic totals once a
+// second; the samples give the current rate, moving averages and peaks in `/api/stats`
+// and the series served by `/api/timeseries`.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Number of per-second samples kept
+pub const HISTORY_SECONDS: usize = 3600;
+
+/// Time between two samples
+const TICK_MS: i64 = 1000;
+
+/// Traffic counted during one tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RateSample {
+    /// Time of the tick, ms since Unix epoch
+    pub timestamp: i64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Rate {
+    pub packets_per_second: f64,
+    pub bytes_per_second: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateSummary {
+    /// Traffic of the latest tick
+    pub current: Rate,
+    pub avg_1m: Rate,
+    pub avg_5m: Rate,
+    pub avg_15m: Rate,
+    /// Highest per-second packet and byte counts in the history (not necessarily the same second)
+    pub peak: Rate,
+}
+
+#[derive(Debug, Default)]
+pub struct RateHistory {
+    /// Oldest first
+    samples: VecDeque<RateSample>,
+    /// Time and totals of the previous call to `record`
+    last: Option<(i64, u64, u64)>,
+}
+
+impl RateHistory {
+    /// Record the traffic totals of a tick at `timestamp_ms`, adding one sample. Ticks
+    /// jitter, so the time since the previous tick only matters when whole ticks were
+    /// missed: then the difference is spread over them, so a late tick is not a spike.
+    pub fn record(&mut self, timestamp_ms: i64, total_packets: u64, total_bytes: u64) {
+        let Some((last_ms, last_packets, last_bytes)) = self.last else {
+            self.last = Some((timestamp_ms, total_packets, total_bytes));
+            return;
+        };
+        let packets = total_packets.saturating_sub(last_packets);
+        let bytes = total_bytes.saturating_sub(last_bytes);
+        self.last = Some((timestamp_ms, total_packets, total_bytes));
+
+        let ticks = ((timestamp_ms - last_ms + TICK_MS / 2) / TICK_MS).clamp(1, HISTORY_SECONDS as i64);
+        let spread = |total: u64, i: i64| {
+            let share = total / ticks as u64;
+            // The remainder goes to the latest tick
+            if i == ticks { share + total % ticks as u64 } else { share }
+        };
+        for i in 1..=ticks {
+            self.push(RateSample {
+                timestamp: timestamp_ms - (ticks - i) * TICK_MS,
+                packets: spread(packets, i),
+                bytes: spread(bytes, i),
+            });
+        }
+    }
+
+    fn push(&mut self, sample: RateSample) {
+        if self.samples.len() == HISTORY_SECONDS {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// The latest `seconds` samples, oldest first
+    pub fn series(&self, seconds: usize) -> Vec<RateSample> {
+        let skip = self.samples.len().saturating_sub(seconds);
+        self.samples.iter().skip(skip).copied().collect()
+    }
+
+    /// Average rate over the latest `seconds` samples, or fewer when the history is shorter
+    pub fn average(&self, seconds: usize) -> Rate {
+        let skip = self.samples.len().saturating_sub(seconds);
+        let count = self.samples.len() - skip;
+        if count == 0 {
+            return Rate::default();
+        }
+        let (packets, bytes) = self
+            .samples
+            .iter()
+            .skip(skip)
+            .fold((0, 0), |(packets, bytes), sample| (packets + sample.packets, bytes + sample.bytes));
+        Rate {
+            packets_per_second: packets as f64 / count as f64,
+            bytes_per_second: bytes as f64 / count as f64,
+        }
+    }
+
+    pub fn summary(&self) -> RateSummary {
+        let peak = Rate {
+            packets_per_second: self.samples.iter().map(|s| s.packets).max().unwrap_or(0) as f64,
+            bytes_per_second: self.samples.iter().map(|s| s.bytes).max().unwrap_or(0) as f64,
+        };
+        RateSummary {
+            current: self.average(1),
+            avg_1m: self.average(60),
+            avg_5m: self.average(300),
+            avg_15m: self.average(900),
+            peak,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates() {
+        let mut history = RateHistory::default();
+        history.record(10_000, 1_000, 100_000);
+        assert_eq!(history.summary().current, Rate::default());
+
+        history.record(11_000, 1_010, 101_000);
+        history.record(12_000, 1_040, 104_000);
+        assert_eq!(
+            history.series(10),
+            vec![
+                RateSample { timestamp: 11_000, packets: 10, bytes: 1_000 },
+                RateSample { timestamp: 12_000, packets: 30, bytes: 3_000 },
+            ]
+        );
+
+        let summary = history.summary();
+        assert_eq!(summary.current.packets_per_second, 30.0);
+        assert_eq!(summary.avg_1m.packets_per_second, 20.0);
+        assert_eq!(summary.peak.bytes_per_second, 3_000.0);
+    }
+
+    #[test]
+    fn test_jittered_ticks() {
+        let mut history = RateHistory::default();
+        history.record(10_000, 0, 0);
+        history.record(11_400, 10, 1_000);
+        // A late tick followed by a punctual one, both in the same wall second
+        history.record(12_050, 30, 3_000);
+        history.record(12_950, 50, 5_000);
+        let packets: Vec<_> = history.series(10).iter().map(|s| s.packets).collect();
+        assert_eq!(packets, vec![10, 20, 20]);
+        assert_eq!(history.summary().current.packets_per_second, 20.0);
+    }
+
+    #[test]
+    fn test_late_samples_are_spread() {
+        let mut history = RateHistory::default();
+        history.record(0, 0, 0);
+        history.record(3_000, 10, 300);
+        let packets: Vec<_> = history.series(10).iter().map(|s| s.packets).collect();
+        assert_eq!(packets, vec![3, 3, 4]);
+        assert_eq!(history.series(1)[0].timestamp, 3_000);
+
+        // Only the last hour is kept
+        history.record(3_000 + 2 * HISTORY_SECONDS as i64 * 1000, 10, 300);
+        assert_eq!(history.series(usize::MAX).len(), HISTORY_SECONDS);
+        assert_eq!(history.summary().peak, Rate::default());
+    }
+}
@@ -0,0 +1,575 @@
+use rusqlite::{params, Connection, Transaction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Ports and hosts kept per bucket; the rest only count towards the totals
+pub const TOP_N: usize = 10;
+
+/// Time resolution of a rollup table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 3] = [Resolution::Minute, Resolution::Hour, Resolution::Day];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Minute => "minute",
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+
+    fn table(&self) -> &'static str {
+        match self {
+            Resolution::Minute => "rollup_minute",
+            Resolution::Hour => "rollup_hour",
+            Resolution::Day => "rollup_day",
+        }
+    }
+
+    pub fn bucket_ms(&self) -> i64 {
+        match self {
+            Resolution::Minute => 60_000,
+            Resolution::Hour => 3_600_000,
+            Resolution::Day => 86_400_000,
+        }
+    }
+
+    /// Longest range served at this resolution when the caller lets us choose
+    fn max_auto_range_ms(&self) -> i64 {
+        match self {
+            Resolution::Minute => 6 * 3_600_000,
+            Resolution::Hour => 14 * 86_400_000,
+            Resolution::Day => i64::MAX,
+        }
+    }
+
+    /// The table the buckets of this resolution are summed from (`None` = raw data)
+    fn source(&self) -> Option<Resolution> {
+        match self {
+            Resolution::Minute => None,
+            Resolution::Hour => Some(Resolution::Minute),
+            Resolution::Day => Some(Resolution::Hour),
+        }
+    }
+
+    /// Pick the finest resolution suited to the range `[from_ms, to_ms)` that still has data
+    /// for `from_ms`, given each resolution's retention (`None` = kept forever).
+    pub fn auto(from_ms: i64, to_ms: i64, now_ms: i64, retention_secs: impl Fn(Resolution) -> Option<u64>) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|resolution| {
+                let retained = retention_secs(*resolution)
+                    .map(|secs| from_ms >= now_ms - secs as i64 * 1000)
+                    .unwrap_or(true);
+                to_ms - from_ms <= resolution.max_auto_range_ms() && retained
+            })
+            .unwrap_or(Resolution::Day)
+    }
+}
+
+/// Breakdown a rollup row belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dimension {
+    /// All traffic; `key` is empty
+    Total,
+    /// Per protocol label (TCP, UDP, ...)
+    Protocol,
+    /// Per service port (the lower port of each packet or flow), top `TOP_N` by bytes
+    Port,
+    /// Per IP address, counting traffic it sent and received, top `TOP_N` by bytes
+    Host,
+}
+
+impl Dimension {
+    const ALL: [Dimension; 4] = [Dimension::Total, Dimension::Protocol, Dimension::Port, Dimension::Host];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Dimension::Total => "total",
+            Dimension::Protocol => "protocol",
+            Dimension::Port => "port",
+            Dimension::Host => "host",
+        }
+    }
+
+    fn is_top_n(&self) -> bool {
+        matches!(self, Dimension::Port | Dimension::Host)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RollupPoint {
+    /// Start of the bucket (ms since Unix epoch)
+    pub bucket_start: i64,
+    pub key: String,
+    pub bytes: u64,
+    pub packets: u64,
+    /// Flows that ended in this bucket
+    pub flows: u64,
+}
+
+/// Buckets rolled up per transaction, so a long backlog such as the first run on an old
+/// database is written in bounded steps
+const CHUNK_BUCKETS: i64 = 60;
+
+/// Stored traffic normalised to one row per packet, aggregation window or ended flow, for
+/// times in `[?1, ?2)` ms, packet IDs above `?3` and flow IDs above `?4`. Windows and
+/// packets carry bytes and packets, ended flows only count as a flow. Stored packets and
+/// windows only see the packets kept by `--sample-rate`, so they are scaled by `?5`.
+const SOURCE_CTE: &str = "WITH traffic(ts, src_ip, dst_ip, port, protocol, bytes, packets, flows) AS (
+    SELECT timestamp, src_ip, dst_ip, min(COALESCE(src_port, 0), COALESCE(dst_port, 0)), protocol, COALESCE(length, 0) * ?5, ?5, 0
+    FROM packets WHERE id > ?3 AND timestamp >= ?1 AND timestamp < ?2
+    UNION ALL
+    SELECT start_ns / 1000000, src_ip, dst_ip, min(COALESCE(src_port, 0), COALESCE(dst_port, 0)), protocol,
+           (bytes_sent + bytes_received) * ?5, (packets_sent + packets_received) * ?5, 0
+    FROM flows WHERE id > ?4 AND kind = 'window' AND start_ns >= ?1 * 1000000 AND start_ns < ?2 * 1000000
+    UNION ALL
+    SELECT end_ns / 1000000, src_ip, dst_ip, min(COALESCE(src_port, 0), COALESCE(dst_port, 0)), protocol, 0, 0, 1
+    FROM flows WHERE id > ?4 AND kind = 'flow' AND end_ns >= ?1 * 1000000 AND end_ns < ?2 * 1000000
+)";
+
+/// Highest packet and flow row IDs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct RowIds {
+    packets: i64,
+    flows: i64,
+}
+
+/// One transaction of rolling up: rows written after their bucket was rolled up are added
+/// to it, then at most `CHUNK_BUCKETS` complete buckets older than `now_ms - grace_ms` are
+/// rolled up, finest resolution first. Each resolution resumes from where it stopped and
+/// skips ahead to the first stored data. `grace_ms` should cover how late rows are
+/// normally written, e.g. flows that end by timeout. `sample_rate` is the `--sample-rate`
+/// the packets were stored with (1 for received flows).
+/// Returns the buckets written and whether more steps are needed.
+pub fn step(conn: &mut Connection, now_ms: i64, grace_ms: i64, sample_rate: u32) -> rusqlite::Result<(usize, bool)> {
+    let weight = sample_rate.max(1) as i64;
+    let tx = conn.transaction()?;
+    let mut written = merge_late_rows(&tx, weight)?;
+    let mut available_until = now_ms - grace_ms;
+
+    for resolution in Resolution::ALL {
+        let size = resolution.bucket_ms();
+        let completed = completed_until(&tx, resolution)?;
+        let to = available_until.div_euclid(size) * size;
+        if to > completed {
+            let from = match first_data(&tx, resolution, completed)? {
+                Some(first) if first < to => completed.max(first.div_euclid(size) * size),
+                _ => to,
+            };
+            let until = (from + CHUNK_BUCKETS * size).min(to);
+            if until > from {
+                written += rollup_range(&tx, resolution, from, until, weight)?;
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO rollup_state (resolution, completed_until) VALUES (?1, ?2)",
+                params![resolution.as_str(), until],
+            )?;
+            tx.commit()?;
+            return Ok((written, true));
+        }
+        // Coarser buckets are only complete once all their finer buckets are
+        available_until = completed;
+    }
+    tx.commit()?;
+    Ok((written, false))
+}
+
+fn completed_until(conn: &Connection, resolution: Resolution) -> rusqlite::Result<i64> {
+    let mut stmt = conn.prepare("SELECT completed_until FROM rollup_state WHERE resolution = ?1")?;
+    let mut rows = stmt.query([resolution.as_str()])?;
+    match rows.next()? {
+        Some(row) => row.get(0),
+        None => Ok(0),
+    }
+}
+
+/// Time of the earliest source data of `resolution` at or after `from_ms`
+fn first_data(tx: &Transaction, resolution: Resolution, from_ms: i64) -> rusqlite::Result<Option<i64>> {
+    let sql = match resolution.source() {
+        None => "SELECT min(ts) FROM (
+                SELECT min(timestamp) AS ts FROM packets WHERE timestamp >= ?1
+                UNION ALL
+                SELECT min(start_ns) / 1000000 FROM flows WHERE kind = 'window' AND start_ns >= ?1 * 1000000
+                UNION ALL
+                SELECT min(end_ns) / 1000000 FROM flows WHERE kind = 'flow' AND end_ns >= ?1 * 1000000
+            )"
+        .to_string(),
+        Some(source) => format!("SELECT min(bucket_start) FROM {} WHERE bucket_start >= ?1", source.table()),
+    };
+    tx.query_row(&sql, [from_ms], |row| row.get(0))
+}
+
+/// Add rows written after their minute was rolled up, e.g. from replaying an old capture
+/// or flows ending later than the grace period, to the buckets of every resolution that
+/// already covers them. Returns the buckets written.
+fn merge_late_rows(tx: &Transaction, weight: i64) -> rusqlite::Result<usize> {
+    let seen = last_id(tx, "packets")?
+        .zip(last_id(tx, "flows")?)
+        .map(|(packets, flows)| RowIds { packets, flows });
+    let current = RowIds {
+        packets: tx.query_row("SELECT COALESCE(max(id), 0) FROM packets", [], |row| row.get(0))?,
+        flows: tx.query_row("SELECT COALESCE(max(id), 0) FROM flows", [], |row| row.get(0))?,
+    };
+    if seen == Some(current) {
+        return Ok(0);
+    }
+
+    let mut written = 0;
+    // A new or upgraded database has no record yet; its rows are rolled up by time as usual
+    if let Some(seen) = seen {
+        let mut completed = Vec::new();
+        for resolution in Resolution::ALL {
+            completed.push((resolution, completed_until(tx, resolution)?));
+        }
+        let minute_completed = completed[0].1;
+        for dimension in Dimension::ALL {
+            let points = aggregate(tx, Resolution::Minute, dimension, 0, minute_completed, seen, weight)?;
+            for &(resolution, completed_until) in &completed {
+                let size = resolution.bucket_ms();
+                let mut buckets = Vec::new();
+                for point in &points {
+                    let bucket_start = point.bucket_start.div_euclid(size) * size;
+                    if bucket_start >= completed_until {
+                        continue;
+                    }
+                    add_point(tx, resolution, dimension, bucket_start, point)?;
+                    buckets.push(bucket_start);
+                    written += 1;
+                }
+                if dimension.is_top_n() {
+                    buckets.sort_unstable();
+                    buckets.dedup();
+                    for bucket_start in buckets {
+                        truncate_bucket(tx, resolution, dimension, bucket_start)?;
+                    }
+                }
+            }
+        }
+    }
+
+    for (source, id) in [("packets", current.packets), ("flows", current.flows)] {
+        tx.execute(
+            "INSERT OR REPLACE INTO rollup_sources (source, last_id) VALUES (?1, ?2)",
+            params![source, id],
+        )?;
+    }
+    Ok(written)
+}
+
+fn last_id(tx: &Transaction, source: &str) -> rusqlite::Result<Option<i64>> {
+    let mut stmt = tx.prepare("SELECT last_id FROM rollup_sources WHERE source = ?1")?;
+    let mut rows = stmt.query([source])?;
+    match rows.next()? {
+        Some(row) => row.get(0),
+        None => Ok(None),
+    }
+}
+
+/// Add `point` to the bucket starting at `bucket_start`
+fn add_point(
+    tx: &Transaction,
+    resolution: Resolution,
+    dimension: Dimension,
+    bucket_start: i64,
+    point: &RollupPoint,
+) -> rusqlite::Result<()> {
+    tx.execute(
+        &format!(
+            "INSERT INTO {} (bucket_start, dimension, key, bytes, packets, flows)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (bucket_start, dimension, key) DO UPDATE SET
+                bytes = bytes + excluded.bytes,
+                packets = packets + excluded.packets,
+                flows = flows + excluded.flows",
+            resolution.table()
+        ),
+        params![
+            bucket_start,
+            dimension.as_str(),
+            point.key,
+            point.bytes as i64,
+            point.packets as i64,
+            point.flows as i64
+        ],
+    )?;
+    Ok(())
+}
+
+/// Keep the top `TOP_N` keys of a bucket
+fn truncate_bucket(tx: &Transaction, resolution: Resolution, dimension: Dimension, bucket_start: i64) -> rusqlite::Result<()> {
+    tx.execute(
+        &format!(
+            "DELETE FROM {0} WHERE bucket_start = ?1 AND dimension = ?2 AND key NOT IN (
+                SELECT key FROM {0} WHERE bucket_start = ?1 AND dimension = ?2
+                ORDER BY bytes DESC, flows DESC LIMIT {1}
+            )",
+            resolution.table(),
+            TOP_N
+        ),
+        params![bucket_start, dimension.as_str()],
+    )?;
+    Ok(())
+}
+
+/// Buckets of `resolution` and `dimension` summed from the source rows in `[from, to)`,
+/// only counting raw rows with IDs above `after`
+fn aggregate(
+    tx: &Transaction,
+    resolution: Resolution,
+    dimension: Dimension,
+    from: i64,
+    to: i64,
+    after: RowIds,
+    weight: i64,
+) -> rusqlite::Result<Vec<RollupPoint>> {
+    let size = resolution.bucket_ms();
+    let sql = match resolution.source() {
+        None => {
+            let key = match dimension {
+                Dimension::Total => "''",
+                Dimension::Protocol => "COALESCE(protocol, 'Unknown')",
+                Dimension::Port => "CAST(port AS TEXT)",
+                Dimension::Host => "host",
+            };
+            let rows = if dimension == Dimension::Host {
+                "(SELECT ts, src_ip AS host, bytes, packets, flows FROM traffic
+                  UNION ALL SELECT ts, dst_ip, bytes, packets, flows FROM traffic)"
+            } else {
+                "traffic"
+            };
+            format!(
+                "{} SELECT (ts / {size}) * {size}, {key}, SUM(bytes), SUM(packets), SUM(flows)
+                 FROM {rows} GROUP BY 1, 2",
+                SOURCE_CTE
+            )
+        }
+        Some(source) => format!(
+            "SELECT (bucket_start / {size}) * {size}, key, SUM(bytes), SUM(packets), SUM(flows)
+             FROM {} WHERE bucket_start >= ?1 AND bucket_start < ?2 AND dimension = '{}'
+             GROUP BY 1, 2",
+            source.table(),
+            dimension.as_str()
+        ),
+    };
+
+    let mut stmt = tx.prepare(&sql)?;
+    let point = |row: &rusqlite::Row| {
+        Ok(RollupPoint {
+            bucket_start: row.get(0)?,
+            key: row.get(1)?,
+            bytes: row.get::<_, i64>(2)? as u64,
+            packets: row.get::<_, i64>(3)? as u64,
+            flows: row.get::<_, i64>(4)? as u64,
+        })
+    };
+    let rows = match resolution.source() {
+        None => stmt.query_map(params![from, to, after.packets, after.flows, weight], point)?,
+        Some(_) => stmt.query_map(params![from, to], point)?,
+    };
+    rows.collect()
+}
+
+fn rollup_range(tx: &Transaction, resolution: Resolution, from: i64, to: i64, weight: i64) -> rusqlite::Result<usize> {
+    let mut insert = tx.prepare(&format!(
+        "INSERT OR REPLACE INTO {} (bucket_start, dimension, key, bytes, packets, flows)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        resolution.table()
+    ))?;
+
+    let mut written = 0;
+    for dimension in Dimension::ALL {
+        let mut buckets: HashMap<i64, Vec<RollupPoint>> = HashMap::new();
+        for row in aggregate(tx, resolution, dimension, from, to, RowIds::default(), weight)? {
+            buckets.entry(row.bucket_start).or_default().push(row);
+        }
+
+        for (_, mut points) in buckets {
+            if dimension.is_top_n() {
+                points.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.flows.cmp(&a.flows)));
+                points.truncate(TOP_N);
+            }
+            for point in points {
+                insert.execute(params![
+                    point.bucket_start,
+                    dimension.as_str(),
+                    point.key,
+                    point.bytes as i64,
+                    point.packets as i64,
+                    point.flows as i64
+                ])?;
+                written += 1;
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Rollup rows of one dimension with `from_ms <= bucket_start < to_ms`, oldest first
+pub fn query(
+    conn: &Connection,
+    resolution: Resolution,
+    dimension: Dimension,
+    from_ms: i64,
+    to_ms: i64,
+) -> rusqlite::Result<Vec<RollupPoint>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT bucket_start, key, bytes, packets, flows FROM {}
+         WHERE dimension = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+         ORDER BY bucket_start, bytes DESC",
+        resolution.table()
+    ))?;
+    let rows = stmt.query_map(params![dimension.as_str(), from_ms, to_ms], |row| {
+        Ok(RollupPoint {
+            bucket_start: row.get(0)?,
+            key: row.get(1)?,
+            bytes: row.get::<_, i64>(2)? as u64,
+            packets: row.get::<_, i64>(3)? as u64,
+            flows: row.get::<_, i64>(4)? as u64,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Delete buckets of `resolution` that started before `cutoff_ms`
+pub fn prune(conn: &Connection, resolution: Resolution, cutoff_ms: i64) -> rusqlite::Result<usize> {
+    conn.execute(
+        &format!("DELETE FROM {} WHERE bucket_start < ?1", resolution.table()),
+        [cutoff_ms],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    const MINUTE: i64 = 60_000;
+
+    fn run(conn: &mut Connection, now_ms: i64, grace_ms: i64, sample_rate: u32) -> rusqlite::Result<usize> {
+        let mut written = 0;
+        loop {
+            let (step_written, more) = step(conn, now_ms, grace_ms, sample_rate)?;
+            written += step_written;
+            if !more {
+                return Ok(written);
+            }
+        }
+    }
+
+    fn insert_packet(conn: &Connection, timestamp: i64, src: &str, dst: &str, dst_port: u16, length: i64) {
+        conn.execute(
+            "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
+             VALUES (?1, ?2, ?3, 50000, ?4, 'TCP', ?5)",
+            params![timestamp, src, dst, dst_port, length],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rollups_cascade() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+
+        let day = Resolution::Day.bucket_ms();
+        insert_packet(&conn, day + 5_000, "10.0.0.1", "10.0.0.2", 443, 100);
+        insert_packet(&conn, day + 10_000, "10.0.0.1", "10.0.0.3", 53, 50);
+        insert_packet(&conn, day + 3 * MINUTE, "10.0.0.1", "10.0.0.2", 443, 200);
+
+        // Only complete minutes before now - grace are rolled up
+        run(&mut conn, day + 3 * MINUTE + 30_000, 0, 1).unwrap();
+        let totals = query(&conn, Resolution::Minute, Dimension::Total, 0, i64::MAX).unwrap();
+        assert_eq!(totals.len(), 1);
+        assert_eq!((totals[0].bytes, totals[0].packets), (150, 2));
+
+        let ports = query(&conn, Resolution::Minute, Dimension::Port, 0, i64::MAX).unwrap();
+        assert_eq!(ports[0].key, "443");
+        let hosts = query(&conn, Resolution::Minute, Dimension::Host, 0, i64::MAX).unwrap();
+        assert_eq!((hosts[0].key.as_str(), hosts[0].bytes), ("10.0.0.1", 150));
+
+        // Once the day is over, minutes roll into the hour and the hour into the day
+        run(&mut conn, 2 * day + MINUTE, 0, 1).unwrap();
+        let days = query(&conn, Resolution::Day, Dimension::Protocol, 0, i64::MAX).unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!((days[0].bucket_start, days[0].key.as_str()), (day, "TCP"));
+        assert_eq!((days[0].bytes, days[0].packets), (350, 3));
+    }
+
+    #[test]
+    fn test_first_run_is_chunked() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+
+        let start = 400 * Resolution::Day.bucket_ms() + 5_000;
+        let hour = Resolution::Hour.bucket_ms();
+        insert_packet(&conn, start, "10.0.0.1", "10.0.0.2", 443, 100);
+        insert_packet(&conn, start + 3 * hour, "10.0.0.1", "10.0.0.2", 443, 100);
+
+        // The first step starts at the oldest data and covers one chunk of minutes
+        let now = start + 4 * hour;
+        assert_eq!(step(&mut conn, now, 0, 1).unwrap(), (5, true));
+        let tx = conn.transaction().unwrap();
+        assert_eq!(completed_until(&tx, Resolution::Minute).unwrap(), start - 5_000 + CHUNK_BUCKETS * MINUTE);
+        tx.rollback().unwrap();
+
+        run(&mut conn, now, 0, 1).unwrap();
+        let totals = query(&conn, Resolution::Minute, Dimension::Total, 0, i64::MAX).unwrap();
+        assert_eq!(totals.len(), 2);
+        let hours = query(&conn, Resolution::Hour, Dimension::Total, 0, i64::MAX).unwrap();
+        assert_eq!(hours.len(), 2);
+    }
+
+    #[test]
+    fn test_late_rows_and_sampling() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+
+        let day = Resolution::Day.bucket_ms();
+        insert_packet(&conn, day + 5_000, "10.0.0.1", "10.0.0.2", 443, 100);
+        run(&mut conn, 2 * day + MINUTE, 0, 10).unwrap();
+        let days = query(&conn, Resolution::Day, Dimension::Total, 0, i64::MAX).unwrap();
+        assert_eq!((days[0].bytes, days[0].packets), (1_000, 10));
+
+        // A row written after its buckets were rolled up is added to every resolution
+        insert_packet(&conn, day + 10_000, "10.0.0.1", "10.0.0.3", 53, 50);
+        run(&mut conn, 2 * day + MINUTE, 0, 10).unwrap();
+        for resolution in Resolution::ALL {
+            let totals = query(&conn, resolution, Dimension::Total, 0, i64::MAX).unwrap();
+            assert_eq!(totals.len(), 1);
+            assert_eq!((totals[0].bytes, totals[0].packets), (1_500, 20));
+            let ports = query(&conn, resolution, Dimension::Port, 0, i64::MAX).unwrap();
+            assert_eq!(ports.iter().map(|p| p.key.as_str()).collect::<Vec<_>>(), vec!["443", "53"]);
+        }
+
+        // Nothing new, nothing added
+        run(&mut conn, 2 * day + MINUTE, 0, 10).unwrap();
+        let days = query(&conn, Resolution::Day, Dimension::Total, 0, i64::MAX).unwrap();
+        assert_eq!(days[0].bytes, 1_500);
+    }
+
+    #[test]
+    fn test_auto_resolution() {
+        let hour = Resolution::Hour.bucket_ms();
+        let day = Resolution::Day.bucket_ms();
+        let now = 100 * day;
+        let retention = |resolution| match resolution {
+            Resolution::Minute => Some(2 * 86_400),
+            Resolution::Hour => Some(60 * 86_400),
+            Resolution::Day => None,
+        };
+
+        assert_eq!(Resolution::auto(now - hour, now, now, retention), Resolution::Minute);
+        assert_eq!(Resolution::auto(now - 7 * day, now, now, retention), Resolution::Hour);
+        assert_eq!(Resolution::auto(now - 30 * day, now, now, retention), Resolution::Day);
+        // A short range that minute data no longer covers
+        assert_eq!(Resolution::auto(now - 5 * day, now - 5 * day + hour, now, retention), Resolution::Hour);
+    }
+}
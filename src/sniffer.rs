@@ -1,16 +1,116 @@
-use crate::config::Config;
-use crate::state::{PacketMetadata, TrafficState};
-use etherparse::{NetSlice, SlicedPacket, TransportSlice};
-use pcap::Device;
+use crate::config::{BackpressurePolicy, Config, ReplaySpeed, TimestampPrecision};
+use crate::health::{CaptureHealth, CaptureStatus, ExportStats, PipelineStats};
+use crate::linklayer;
+use crate::state::{
+    PacketMetadata, Protocol, TrafficState, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN,
+};
+use etherparse::{NetSlice, TransportSlice, VlanSlice};
+use pcap::{Activated, Active, Capture, Device, Linktype, PacketHeader, Precision};
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ptr::NonNull;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Sender;
 
+/// Delay before reopening a failed capture, doubled after each consecutive failure
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How often kernel drop counters are read from libpcap
+const PCAP_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Errors raised while opening or running a capture
+#[derive(Debug)]
+pub enum SnifferError {
+    /// Listing or looking up capture devices failed
+    DeviceList(pcap::Error),
+    /// No interface was given and the system has no default device
+    NoDefaultDevice,
+    /// The requested interface does not exist
+    DeviceNotFound { name: String, available: Vec<String> },
+    /// The device or capture file could not be opened. `status` is libpcap's activation
+    /// status when opening a device failed.
+    Open { device: String, source: pcap::Error, status: Option<c_int> },
+    /// The BPF expression could not be compiled or installed
+    Filter { expression: String, source: pcap::Error },
+    /// Reading packets failed, e.g. because the interface went away
+    Capture(pcap::Error),
+}
+
+impl SnifferError {
+    /// Whether reopening the capture later might succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SnifferError::DeviceList(_) | SnifferError::DeviceNotFound { .. } => true,
+            SnifferError::Capture(_) => true,
+            SnifferError::Open { .. } => !self.is_permission_denied(),
+            SnifferError::NoDefaultDevice | SnifferError::Filter { .. } => false,
+        }
+    }
+
+    /// Whether opening failed for lack of capture privileges
+    fn is_permission_denied(&self) -> bool {
+        let SnifferError::Open { source, status, .. } = self else {
+            return false;
+        };
+        match source {
+            pcap::Error::PcapError(_) => {
+                matches!(status, Some(PCAP_ERROR_PERM_DENIED | PCAP_ERROR_PROMISC_PERM_DENIED))
+            }
+            pcap::Error::IoError(kind) => *kind == io::ErrorKind::PermissionDenied,
+            pcap::Error::ErrnoError(errno) => {
+                io::Error::from_raw_os_error(errno.0).kind() == io::ErrorKind::PermissionDenied
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for SnifferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnifferError::DeviceList(e) => write!(f, "failed to list capture devices: {}", e),
+            SnifferError::NoDefaultDevice => write!(f, "no default capture device found"),
+            SnifferError::DeviceNotFound { name, available } => write!(
+                f,
+                "interface '{}' not found (available: {})",
+                name,
+                available.join(", ")
+            ),
+            SnifferError::Open { device, source, .. } => {
+                write!(f, "failed to open {}: {}", device, source)?;
+                if self.is_permission_denied() {
+                    write!(f, " (packet capture needs root or CAP_NET_RAW/CAP_NET_ADMIN)")?;
+                }
+                Ok(())
+            }
+            SnifferError::Filter { expression, source } => {
+                write!(f, "invalid BPF filter '{}': {}", expression, source)
+            }
+            SnifferError::Capture(e) => write!(f, "packet capture error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnifferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnifferError::DeviceList(e) | SnifferError::Capture(e) => Some(e),
+            SnifferError::Open { source, .. } | SnifferError::Filter { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
 /// Filter configuration for packet capture
 #[derive(Clone, Debug, Default)]
 pub struct FilterConfig {
     pub port: Option<u16>,
-    pub ip: Option<String>,
+    pub ip: Option<IpAddr>,
     pub protocol: Option<String>,
 }
 
@@ -18,7 +118,7 @@ impl From<&Config> for FilterConfig {
     fn from(config: &Config) -> Self {
         Self {
             port: config.filter_port,
-            ip: config.filter_ip.clone(),
+            ip: config.filter_ip,
             protocol: config.filter_protocol.clone(),
         }
     }
@@ -35,126 +135,786 @@ impl FilterConfig {
         }
 
         // IP filter
-        if let Some(ref ip) = self.ip {
-            if meta.src_ip != *ip && meta.dst_ip != *ip {
+        if let Some(ip) = self.ip {
+            if meta.src_ip != ip && meta.dst_ip != ip {
                 return false;
             }
         }
 
         // Protocol filter
         if let Some(ref proto) = self.protocol {
-            if !meta.protocol.eq_ignore_ascii_case(proto) {
+            if !meta.protocol.as_str().eq_ignore_ascii_case(proto) {
                 return false;
             }
         }
 
         true
     }
+
+    /// Translate the filter into an equivalent libpcap BPF expression so the kernel can
+    /// drop unwanted packets before they are copied to userspace.
+    ///
+    /// `matches` still runs afterwards, so criteria BPF cannot express exactly are widened
+    /// or left out. Plain BPF primitives only look at untagged frames, so the expression is
+    /// repeated behind `vlan` for single-tagged 802.1Q frames; double-tagged (QinQ) frames
+    /// do not pass it and need an explicit `--bpf`.
+    pub fn to_bpf(&self) -> Option<String> {
+        let mut clauses = Vec::new();
+
+        if let Some(port) = self.port {
+            clauses.push(format!("port {}", port));
+        }
+
+        if let Some(ip) = self.ip {
+            clauses.push(format!("host {}", ip));
+        }
+
+        if let Some(ref proto) = self.protocol {
+            let primitive = match proto.to_ascii_lowercase().as_str() {
+                "tcp" => Some("tcp"),
+                "udp" => Some("udp"),
+                "ipv4" => Some("ip"),
+                "ipv6" => Some("ip6"),
+                _ => None,
+            };
+            if let Some(primitive) = primitive {
+                clauses.push(primitive.to_string());
+            }
+        }
+
+        if clauses.is_empty() {
+            return None;
+        }
+        // `vlan` shifts the offsets of everything after it, so the untagged case comes first
+        let expression = clauses.join(" and ");
+        Some(format!("({0}) or (vlan and ({0}))", expression))
+    }
+}
+
+/// Settings shared by live captures and offline replays
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+    pub filter: FilterConfig,
+    pub quiet: bool,
+    pub sample_rate: u32,
+    pub precision: TimestampPrecision,
+    /// Kernel-side filter: the explicit `bpf_filter`, or one derived from `filter`
+    pub bpf_filter: Option<String>,
+    pub backpressure: BackpressurePolicy,
+}
+
+impl From<&Config> for CaptureOptions {
+    fn from(config: &Config) -> Self {
+        let filter = FilterConfig::from(config);
+        let bpf_filter = config.bpf_filter.clone().or_else(|| filter.to_bpf());
+        Self {
+            filter,
+            quiet: config.quiet,
+            sample_rate: config.sample_rate,
+            precision: config.timestamp_precision,
+            bpf_filter,
+            backpressure: config.backpressure,
+        }
+    }
+}
+
+/// Shared handles every capture thread feeds into
+#[derive(Clone)]
+pub struct CaptureContext {
+    pub tx: Sender<PacketMetadata>,
+    pub running: Arc<AtomicBool>,
+    pub traffic_state: Arc<TrafficState>,
+    pub health: Arc<CaptureHealth>,
+    pub pipeline_stats: Arc<PipelineStats>,
+    /// Queue of the Elasticsearch exporter, when it exports packets
+    pub export: Option<PacketExport>,
+}
+
+/// Where sampled packets are copied for export
+#[derive(Clone)]
+pub struct PacketExport {
+    pub tx: Sender<PacketMetadata>,
+    pub stats: Arc<ExportStats>,
+}
+
+impl CaptureOptions {
+    fn print_filters(&self) {
+        let filter = &self.filter;
+        if filter.port.is_some() || filter.ip.is_some() || filter.protocol.is_some() {
+            println!("Filters: port={:?}, ip={:?}, protocol={:?}", 
+                filter.port, filter.ip, filter.protocol);
+        }
+        if let Some(ref bpf) = self.bpf_filter {
+            println!("BPF filter: {}", bpf);
+        }
+    }
+
+    /// Install the BPF program on an opened capture handle
+    fn apply_bpf<T: Activated + ?Sized>(&self, cap: &mut Capture<T>) -> Result<(), SnifferError> {
+        match self.bpf_filter {
+            Some(ref bpf) => cap.filter(bpf, true).map_err(|source| SnifferError::Filter {
+                expression: bpf.clone(),
+                source,
+            }),
+            None => Ok(()),
+        }
+    }
 }
 
-pub fn start_sniffer(
-    interface_name: Option<String>,
+/// Forwards parsed packets into the live stats and the storage channel,
+/// applying the userspace filter and the storage sampling gate.
+struct Pipeline {
+    interface: Arc<str>,
+    linktype: Linktype,
     tx: Sender<PacketMetadata>,
-    running: Arc<AtomicBool>,
+    export: Option<PacketExport>,
     traffic_state: Arc<TrafficState>,
+    stats: Arc<PipelineStats>,
     filter: FilterConfig,
-    quiet: bool,
+    backpressure: BackpressurePolicy,
     sample_rate: u32,
-) {
-    let device = if let Some(name) = interface_name {
-        Device::list()
-            .unwrap()
-            .into_iter()
-            .find(|d| d.name == name)
-            .expect("Device not found")
+    sample_counter: u32,
+}
+
+impl Pipeline {
+    fn new(interface: &str, linktype: Linktype, ctx: &CaptureContext, options: &CaptureOptions) -> Self {
+        // Sampling: keep 1 out of every sample_rate packets for storage.
+        // A rate of 0 or 1 means keep everything.
+        let sample_rate = if options.sample_rate == 0 { 1 } else { options.sample_rate };
+        Self {
+            interface: Arc::from(interface),
+            linktype,
+            tx: ctx.tx.clone(),
+            export: ctx.export.clone(),
+            traffic_state: ctx.traffic_state.clone(),
+            stats: ctx.pipeline_stats.clone(),
+            filter: options.filter.clone(),
+            backpressure: options.backpressure,
+            sample_rate,
+            sample_counter: 0,
+        }
+    }
+
+    /// Parse a captured frame and feed it through the pipeline.
+    /// Returns false once the storage channel has been closed.
+    fn process(&mut self, data: &[u8], wire_len: u32, timestamp_ns: i64) -> bool {
+        match parse_packet(self.linktype, data, wire_len, timestamp_ns, &self.interface) {
+            Some(meta) => self.handle(meta),
+            None => true,
+        }
+    }
+
+    /// Returns false once the storage channel has been closed.
+    fn handle(&mut self, meta: PacketMetadata) -> bool {
+        // Apply filters
+        if meta.protocol == Protocol::Unknown || !self.filter.matches(&meta) {
+            return true;
+        }
+
+        // Always update live in-memory stats (unaffected by sampling)
+        self.traffic_state.update(&meta);
+
+        // Sampling gate: only forward every Nth packet to storage
+        self.sample_counter = self.sample_counter.wrapping_add(1);
+        if self.sample_counter % self.sample_rate == 0 {
+            self.export(&meta);
+            return self.forward(meta);
+        }
+        true
+    }
+
+    /// Hand a packet to the exporter. Exporting never stalls the capture: when the
+    /// exporter's queue is full the packet is only stored.
+    fn export(&self, meta: &PacketMetadata) {
+        if let Some(ref export) = self.export {
+            if let Err(TrySendError::Full(_)) = export.tx.try_send(meta.clone()) {
+                export.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Hand a packet to the storage writer, applying the backpressure policy when its queue is full
+    fn forward(&self, meta: PacketMetadata) -> bool {
+        match self.tx.try_send(meta) {
+            Ok(()) => true,
+            Err(TrySendError::Closed(_)) => false,
+            Err(TrySendError::Full(meta)) => {
+                self.stats.channel_full.fetch_add(1, Ordering::Relaxed);
+                match self.backpressure {
+                    BackpressurePolicy::Block => self.tx.blocking_send(meta).is_ok(),
+                    BackpressurePolicy::Drop => {
+                        self.stats.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl From<TimestampPrecision> for Precision {
+    fn from(precision: TimestampPrecision) -> Self {
+        match precision {
+            TimestampPrecision::Micro => Precision::Micro,
+            TimestampPrecision::Nano => Precision::Nano,
+        }
+    }
+}
+
+extern "C" {
+    // Not exposed by the pcap crate; libpcap is linked through it
+    fn pcap_get_tstamp_precision(p: *mut c_void) -> c_int;
+    fn pcap_activate(p: *mut c_void) -> c_int;
+    fn pcap_geterr(p: *mut c_void) -> *const c_char;
+}
+
+const PCAP_TSTAMP_PRECISION_NANO: c_int = 1;
+const PCAP_ERROR_PERM_DENIED: c_int = -8;
+const PCAP_ERROR_PROMISC_PERM_DENIED: c_int = -11;
+
+/// The timestamp precision libpcap delivers on an open capture. Requesting nanoseconds
+/// is only a hint: without support from the platform or driver it stays at microseconds.
+fn effective_precision<T: Activated + ?Sized>(cap: &Capture<T>) -> TimestampPrecision {
+    // SAFETY: the handle is valid while `cap` is alive
+    precision_from_pcap(unsafe { pcap_get_tstamp_precision(cap.as_ptr().cast()) })
+}
+
+fn precision_from_pcap(value: c_int) -> TimestampPrecision {
+    if value == PCAP_TSTAMP_PRECISION_NANO {
+        TimestampPrecision::Nano
     } else {
-        Device::lookup().expect("Device lookup failed").expect("No default device")
+        TimestampPrecision::Micro
+    }
+}
+
+/// Capture time of a packet in nanoseconds since the Unix epoch, as recorded in its pcap header.
+/// With nanosecond precision libpcap stores nanoseconds in `tv_usec`.
+// timeval field widths differ between platforms, hence the casts
+#[allow(clippy::unnecessary_cast)]
+fn header_timestamp_nanos(header: &PacketHeader, precision: TimestampPrecision) -> i64 {
+    timeval_to_nanos(header.ts.tv_sec as i64, header.ts.tv_usec as i64, precision)
+}
+
+fn timeval_to_nanos(seconds: i64, fraction: i64, precision: TimestampPrecision) -> i64 {
+    let fraction_ns = match precision {
+        TimestampPrecision::Micro => fraction * 1_000,
+        TimestampPrecision::Nano => fraction,
     };
+    seconds * 1_000_000_000 + fraction_ns
+}
 
-    if !quiet {
-        println!("Capturing on device: {}", device.name);
-        if filter.port.is_some() || filter.ip.is_some() || filter.protocol.is_some() {
-            println!("Filters: port={:?}, ip={:?}, protocol={:?}", 
-                filter.port, filter.ip, filter.protocol);
+/// Decode a captured frame into packet metadata. Returns `None` if the frame cannot be parsed.
+pub fn parse_packet(
+    linktype: Linktype,
+    data: &[u8],
+    wire_len: u32,
+    timestamp_ns: i64,
+    interface: &Arc<str>,
+) -> Option<PacketMetadata> {
+    let sliced = linklayer::slice_packet(linktype, data)?;
+
+    let mut meta = PacketMetadata {
+        timestamp: timestamp_ns.div_euclid(1_000_000),
+        timestamp_ns,
+        interface: interface.clone(),
+        src_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        dst_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        src_port: 0,
+        dst_port: 0,
+        protocol: Protocol::Unknown,
+        ip_protocol: 0,
+        vlan: sliced.vlan.as_ref().map(|vlan| match vlan {
+            VlanSlice::SingleVlan(header) => header.vlan_identifier().value(),
+            VlanSlice::DoubleVlan(header) => header.outer().vlan_identifier().value(),
+        }),
+        length: wire_len as usize,
+        tcp_flags: 0,
+    };
+
+    match sliced.net {
+        Some(NetSlice::Ipv4(slice)) => {
+            let header = slice.header();
+            meta.src_ip = header.source_addr().into();
+            meta.dst_ip = header.destination_addr().into();
+            meta.protocol = Protocol::Ipv4;
+            meta.ip_protocol = slice.payload().ip_number.0;
+        }
+        Some(NetSlice::Ipv6(slice)) => {
+            let header = slice.header();
+            meta.src_ip = header.source_addr().into();
+            meta.dst_ip = header.destination_addr().into();
+            meta.protocol = Protocol::Ipv6;
+            meta.ip_protocol = slice.payload().ip_number.0;
+        }
+        _ => {}
+    }
+
+    match sliced.transport {
+        Some(TransportSlice::Tcp(header)) => {
+            meta.src_port = header.source_port();
+            meta.dst_port = header.destination_port();
+            meta.protocol = Protocol::Tcp;
+            meta.tcp_flags = tcp_flags(&header);
+        }
+        Some(TransportSlice::Udp(header)) => {
+            meta.src_port = header.source_port();
+            meta.dst_port = header.destination_port();
+            meta.protocol = Protocol::Udp;
+        }
+        _ => {}
+    }
+
+    Some(meta)
+}
+
+fn tcp_flags(header: &etherparse::TcpSlice<'_>) -> u8 {
+    let mut flags = 0;
+    for (set, bit) in [
+        (header.fin(), TCP_FIN),
+        (header.syn(), TCP_SYN),
+        (header.rst(), TCP_RST),
+        (header.psh(), TCP_PSH),
+        (header.ack(), TCP_ACK),
+    ] {
+        if set {
+            flags |= bit;
         }
     }
+    flags
+}
+
+/// Supervise the live capture on one interface, reopening it with exponential backoff
+/// when it fails (e.g. a veth pair being recreated). Gives up on errors that retrying
+/// cannot fix, such as missing capture permissions or an invalid BPF expression.
+pub fn supervise_sniffer(interface: String, ctx: CaptureContext, options: CaptureOptions) {
+    let mut backoff = RESTART_BACKOFF_MIN;
+
+    while ctx.running.load(Ordering::Relaxed) {
+        let started = Instant::now();
+        match start_sniffer(&interface, &ctx, &options) {
+            Ok(()) => break,
+            Err(e) => {
+                let retry = e.is_retryable() && ctx.running.load(Ordering::Relaxed);
+                ctx.health.record_error(&interface, &e.to_string(), retry);
+                if !retry {
+                    eprintln!("Capture on {} stopped: {}", interface, e);
+                    break;
+                }
+
+                // A capture that ran for a while before failing starts over with a short delay
+                if started.elapsed() > RESTART_BACKOFF_MAX {
+                    backoff = RESTART_BACKOFF_MIN;
+                }
+                eprintln!("Capture on {} failed: {} (restarting in {:?})", interface, e, backoff);
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+            }
+        }
+    }
+}
 
-    let mut cap = pcap::Capture::from_device(device)
-        .unwrap()
+/// Open `device` for live capture. The handle is activated here rather than with
+/// `Capture::open`, whose error keeps only libpcap's message, because the activation
+/// status is what tells missing privileges apart from other failures.
+fn open_device(interface: &str, device: Device, options: &CaptureOptions) -> Result<Capture<Active>, SnifferError> {
+    let open_error = |source, status| SnifferError::Open {
+        device: interface.to_string(),
+        source,
+        status,
+    };
+    let cap = Capture::from_device(device)
+        .map_err(|source| open_error(source, None))?
         .promisc(true)
         .snaplen(65535)
         .timeout(1000)
-        .open()
-        .unwrap();
+        .precision(options.precision.into());
 
-    // Sampling: keep 1 out of every sample_rate packets for storage.
-    // A rate of 0 or 1 means keep everything.
-    let effective_rate = if sample_rate == 0 { 1 } else { sample_rate };
-    let mut sample_counter: u32 = 0;
+    let handle = cap.as_ptr();
+    // SAFETY: the handle is valid while `cap` is alive
+    let status = unsafe { pcap_activate(handle.cast()) };
+    if status < 0 {
+        // SAFETY: as above; the message stays valid until the next call on the handle
+        let message = unsafe { CStr::from_ptr(pcap_geterr(handle.cast())) }.to_string_lossy().into_owned();
+        // `cap` is dropped here and closes the handle
+        return Err(open_error(pcap::Error::PcapError(message), Some(status)));
+    }
+    // Positive statuses are warnings; the capture works. The activated handle moves to
+    // a `Capture<Active>`, so `cap` must not close it when dropped.
+    std::mem::forget(cap);
+    Ok(Capture::from(NonNull::new(handle).expect("pcap handle is not null")))
+}
 
-    while running.load(Ordering::Relaxed) {
-        match cap.next_packet() {
-            Ok(packet) => {
-                if let Ok(sliced) = SlicedPacket::from_ethernet(&packet.data) {
-                    let mut meta = PacketMetadata {
-                        timestamp: chrono::Utc::now().timestamp_millis(),
-                        src_ip: "?.?.?.?".to_string(),
-                        dst_ip: "?.?.?.?".to_string(),
-                        src_port: 0,
-                        dst_port: 0,
-                        protocol: "Unknown".to_string(),
-                        length: packet.header.len as usize,
-                    };
-
-                    match sliced.net {
-                        Some(NetSlice::Ipv4(slice)) => {
-                            let header = slice.header();
-                            meta.src_ip = header.source_addr().to_string();
-                            meta.dst_ip = header.destination_addr().to_string();
-                            meta.protocol = "IPv4".to_string();
-                        }
-                        Some(NetSlice::Ipv6(slice)) => {
-                            let header = slice.header();
-                            meta.src_ip = header.source_addr().to_string();
-                            meta.dst_ip = header.destination_addr().to_string();
-                            meta.protocol = "IPv6".to_string();
-                        }
-                        _ => {}
-                    }
+/// Open the interface and capture until shutdown or until the storage channel closes
+fn start_sniffer(
+    interface: &str,
+    ctx: &CaptureContext,
+    options: &CaptureOptions,
+) -> Result<(), SnifferError> {
+    let device = find_device(interface)?;
 
-                    match sliced.transport {
-                        Some(TransportSlice::Tcp(header)) => {
-                            meta.src_port = header.source_port();
-                            meta.dst_port = header.destination_port();
-                            meta.protocol = "TCP".to_string();
-                        }
-                        Some(TransportSlice::Udp(header)) => {
-                            meta.src_port = header.source_port();
-                            meta.dst_port = header.destination_port();
-                            meta.protocol = "UDP".to_string();
-                        }
-                        _ => {}
-                    }
+    let mut cap = open_device(interface, device, options)?;
 
-                    // Apply filters
-                    if meta.protocol != "Unknown" && filter.matches(&meta) {
-                        // Always update live in-memory stats (unaffected by sampling)
-                        traffic_state.update(&meta);
-
-                        // Sampling gate: only forward every Nth packet to storage
-                        sample_counter = sample_counter.wrapping_add(1);
-                        if sample_counter % effective_rate == 0 {
-                            if let Err(_) = tx.blocking_send(meta) {
-                                break;
-                            }
-                        }
-                    }
+    options.apply_bpf(&mut cap)?;
+
+    let linktype = cap.get_datalink();
+    warn_unsupported_linktype(interface, linktype);
+
+    let precision = effective_precision(&cap);
+    if precision != options.precision {
+        eprintln!(
+            "Nanosecond timestamps are not supported on {}, using microseconds",
+            interface
+        );
+    }
+
+    if !options.quiet {
+        println!("Capturing on device: {}", interface);
+        options.print_filters();
+    }
+    ctx.health.set_status(interface, CaptureStatus::Running);
+
+    let mut pipeline = Pipeline::new(interface, linktype, ctx, options);
+    let mut drop_counter = PcapDropCounter::default();
+    let mut last_stats = Instant::now();
+
+    while ctx.running.load(Ordering::Relaxed) {
+        if last_stats.elapsed() >= PCAP_STATS_INTERVAL {
+            last_stats = Instant::now();
+            drop_counter.poll(&mut cap, interface, &ctx.health);
+        }
+
+        match cap.next_packet() {
+            Ok(packet) => {
+                let timestamp_ns = header_timestamp_nanos(packet.header, precision);
+                if !pipeline.process(packet.data, packet.header.len, timestamp_ns) {
+                    break;
                 }
             }
             Err(pcap::Error::TimeoutExpired) => continue,
             Err(e) => {
-                eprintln!("Packet capture error: {}", e);
+                drop_counter.poll(&mut cap, interface, &ctx.health);
+                return Err(SnifferError::Capture(e));
+            }
+        }
+    }
+    drop_counter.poll(&mut cap, interface, &ctx.health);
+    Ok(())
+}
+
+/// Turns the per-handle libpcap counters into increments, so totals survive capture restarts
+#[derive(Default)]
+struct PcapDropCounter {
+    last: Option<pcap::Stat>,
+}
+
+impl PcapDropCounter {
+    fn poll<T: Activated + ?Sized>(&mut self, cap: &mut Capture<T>, interface: &str, health: &CaptureHealth) {
+        let Ok(stat) = cap.stats() else {
+            return;
+        };
+        let last = self.last.unwrap_or(pcap::Stat {
+            received: 0,
+            dropped: 0,
+            if_dropped: 0,
+        });
+        // The kernel counters are 32-bit and wrap around on busy links
+        health.add_pcap_stats(
+            interface,
+            stat.received.wrapping_sub(last.received) as u64,
+            stat.dropped.wrapping_sub(last.dropped) as u64,
+            stat.if_dropped.wrapping_sub(last.if_dropped) as u64,
+        );
+        self.last = Some(stat);
+    }
+}
+
+/// Resolve the name of the interface to capture on: the given one if it exists,
+/// otherwise the system default device.
+pub fn resolve_interface(name: Option<&str>) -> Result<String, SnifferError> {
+    match name {
+        Some(name) => find_device(name).map(|device| device.name),
+        None => Device::lookup()
+            .map_err(SnifferError::DeviceList)?
+            .map(|device| device.name)
+            .ok_or(SnifferError::NoDefaultDevice),
+    }
+}
+
+fn find_device(name: &str) -> Result<Device, SnifferError> {
+    let devices = Device::list().map_err(SnifferError::DeviceList)?;
+    let available: Vec<String> = devices.iter().map(|d| d.name.clone()).collect();
+    devices
+        .into_iter()
+        .find(|d| d.name == name)
+        .ok_or_else(|| SnifferError::DeviceNotFound {
+            name: name.to_string(),
+            available,
+        })
+}
+
+fn warn_unsupported_linktype(source: &str, linktype: Linktype) {
+    if !linklayer::is_supported(linktype) {
+        eprintln!(
+            "Unsupported link type {} ({}) on {}, packets will not be decoded",
+            linktype.0,
+            linktype.get_name().unwrap_or_else(|_| "unknown".to_string()),
+            source
+        );
+    }
+}
+
+/// Paces an offline replay so packets are released with the same spacing they were captured with.
+struct ReplayClock {
+    origin: Option<(i64, Instant)>,
+}
+
+impl ReplayClock {
+    fn new() -> Self {
+        Self { origin: None }
+    }
+
+    /// Sleep until the packet captured at `timestamp_ns` is due, relative to the first packet.
+    fn wait_until(&mut self, timestamp_ns: i64) {
+        let (first_ns, started) = *self.origin.get_or_insert((timestamp_ns, Instant::now()));
+        let due = Duration::from_nanos((timestamp_ns - first_ns).max(0) as u64);
+        let elapsed = started.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// Name replayed packets are attributed to: the capture file name without its directory
+pub fn replay_source_name(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Replay a saved .pcap/.pcapng file through the same pipeline as a live capture.
+/// Packets keep the timestamps recorded in the file.
+pub fn start_replay(path: String, speed: ReplaySpeed, ctx: CaptureContext, options: CaptureOptions) {
+    let source = replay_source_name(&path);
+    match replay_file(&path, &source, speed, &ctx, &options) {
+        Ok(replayed) => {
+            ctx.health.set_status(&source, CaptureStatus::Finished);
+            if !options.quiet {
+                println!("Replay finished: {} packets read from {}", replayed, path);
             }
         }
+        Err(e) => {
+            ctx.health.record_error(&source, &e.to_string(), false);
+            eprintln!("Replay of {} failed: {}", path, e);
+        }
+    }
+}
+
+fn replay_file(
+    path: &str,
+    source: &str,
+    speed: ReplaySpeed,
+    ctx: &CaptureContext,
+    options: &CaptureOptions,
+) -> Result<u64, SnifferError> {
+    let precision = options.precision;
+    let mut cap = Capture::from_file_with_precision(path, precision.into()).map_err(|source| {
+        SnifferError::Open {
+            device: path.to_string(),
+            source,
+            status: None,
+        }
+    })?;
+
+    options.apply_bpf(&mut cap)?;
+
+    if !options.quiet {
+        println!("Replaying capture file: {} (speed: {:?})", path, speed);
+        options.print_filters();
+    }
+    ctx.health.set_status(source, CaptureStatus::Running);
+
+    let linktype = cap.get_datalink();
+    warn_unsupported_linktype(source, linktype);
+    let mut pipeline = Pipeline::new(source, linktype, ctx, options);
+    let mut clock = ReplayClock::new();
+    let mut replayed: u64 = 0;
+
+    while ctx.running.load(Ordering::Relaxed) {
+        match cap.next_packet() {
+            Ok(packet) => {
+                let timestamp_ns = header_timestamp_nanos(packet.header, precision);
+                if speed == ReplaySpeed::Realtime {
+                    clock.wait_until(timestamp_ns);
+                }
+                replayed += 1;
+                if !pipeline.process(packet.data, packet.header.len, timestamp_ns) {
+                    break;
+                }
+            }
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => return Err(SnifferError::Capture(e)),
+        }
+    }
+
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::PacketBuilder;
+
+    fn tcp_frame(payload: &[u8]) -> Vec<u8> {
+        let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [7, 8, 9, 10, 11, 12])
+            .ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(40000, 443, 1, 1024);
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        frame
+    }
+
+    fn test_context(tx: Sender<PacketMetadata>) -> CaptureContext {
+        CaptureContext {
+            tx,
+            running: Arc::new(AtomicBool::new(true)),
+            traffic_state: Arc::new(TrafficState::new()),
+            health: Arc::new(CaptureHealth::new()),
+            pipeline_stats: Arc::new(PipelineStats::default()),
+            export: None,
+        }
+    }
+
+    fn test_options() -> CaptureOptions {
+        CaptureOptions::from(&Config::default())
+    }
+
+    #[test]
+    fn test_parse_tcp_frame() {
+        let frame = tcp_frame(b"hello");
+        let interface: Arc<str> = Arc::from("eth0");
+        let meta = parse_packet(Linktype::ETHERNET, &frame, frame.len() as u32, 1_700_000_000_123_456_789, &interface).unwrap();
+
+        assert_eq!(meta.timestamp, 1_700_000_000_123);
+        assert_eq!(meta.timestamp_ns, 1_700_000_000_123_456_789);
+        assert_eq!(meta.src_ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(meta.dst_ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(meta.src_port, 40000);
+        assert_eq!(meta.dst_port, 443);
+        assert_eq!(meta.protocol, Protocol::Tcp);
+        assert_eq!(meta.ip_protocol, crate::state::IPPROTO_TCP);
+        assert_eq!(meta.length, frame.len());
+        assert_eq!(&*meta.interface, "eth0");
+    }
+
+    #[test]
+    fn test_timestamp_precision() {
+        assert_eq!(
+            timeval_to_nanos(1_700_000_000, 123_456, TimestampPrecision::Micro),
+            1_700_000_000_123_456_000
+        );
+        assert_eq!(
+            timeval_to_nanos(1_700_000_000, 123_456, TimestampPrecision::Nano),
+            1_700_000_000_000_123_456
+        );
+    }
+
+    #[test]
+    fn test_precision_fallback() {
+        assert_eq!(precision_from_pcap(PCAP_TSTAMP_PRECISION_NANO), TimestampPrecision::Nano);
+        // A capture that fell back to microseconds must not be decoded as nanoseconds
+        let precision = precision_from_pcap(0);
+        assert_eq!(precision, TimestampPrecision::Micro);
+        assert_eq!(timeval_to_nanos(1_700_000_000, 123_456, precision), 1_700_000_000_123_456_000);
+    }
+
+    #[test]
+    fn test_permission_errors() {
+        let open = |source, status| SnifferError::Open {
+            device: "eth0".to_string(),
+            source,
+            status,
+        };
+        let denied = open(pcap::Error::PcapError("eth0: socket failed".to_string()), Some(PCAP_ERROR_PERM_DENIED));
+        assert!(!denied.is_retryable());
+        assert!(denied.to_string().contains("CAP_NET_RAW"));
+        assert!(!open(pcap::Error::IoError(io::ErrorKind::PermissionDenied), None).is_retryable());
+
+        // The status decides, not the wording of the message
+        let missing = open(pcap::Error::PcapError("no permission to see eth0".to_string()), Some(-5));
+        assert!(missing.is_retryable());
+        assert!(!missing.to_string().contains("CAP_NET_RAW"));
+    }
+
+    #[test]
+    fn test_filter_to_bpf() {
+        assert_eq!(FilterConfig::default().to_bpf(), None);
+
+        let filter = FilterConfig {
+            port: Some(443),
+            ip: Some("10.0.0.2".parse().unwrap()),
+            protocol: Some("TCP".into()),
+        };
+        assert_eq!(
+            filter.to_bpf().as_deref(),
+            Some("(port 443 and host 10.0.0.2 and tcp) or (vlan and (port 443 and host 10.0.0.2 and tcp))")
+        );
+
+        // Tagged frames pass the expression and are still matched in userspace
+        let mut frame = tcp_frame(b"");
+        frame.splice(12..12, [0x81, 0x00, 0x00, 0x64]);
+        let interface: Arc<str> = Arc::from("eth0");
+        let meta = parse_packet(Linktype::ETHERNET, &frame, frame.len() as u32, 0, &interface).unwrap();
+        assert_eq!(meta.vlan, Some(100));
+        assert!(filter.matches(&meta));
+
+        // Criteria BPF cannot express are left to the userspace filter
+        let filter = FilterConfig {
+            port: None,
+            ip: None,
+            protocol: Some("ICMP".into()),
+        };
+        assert_eq!(filter.to_bpf(), None);
+    }
+
+    #[test]
+    fn test_pipeline_sampling() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let ctx = test_context(tx);
+        let mut options = test_options();
+        options.sample_rate = 2;
+        let mut pipeline = Pipeline::new("eth0", Linktype::ETHERNET, &ctx, &options);
+
+        let frame = tcp_frame(b"");
+        for _ in 0..4 {
+            assert!(pipeline.process(&frame, frame.len() as u32, 0));
+        }
+
+        // Live stats see every packet, storage only every 2nd
+        assert_eq!(ctx.traffic_state.total_packets.load(Ordering::Relaxed), 4);
+        let mut stored = 0;
+        while rx.try_recv().is_ok() {
+            stored += 1;
+        }
+        assert_eq!(stored, 2);
+    }
+
+    #[test]
+    fn test_backpressure_drop_policy() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let ctx = test_context(tx);
+        let mut options = test_options();
+        options.backpressure = BackpressurePolicy::Drop;
+        let mut pipeline = Pipeline::new("eth0", Linktype::ETHERNET, &ctx, &options);
+
+        let frame = tcp_frame(b"");
+        for _ in 0..3 {
+            assert!(pipeline.process(&frame, frame.len() as u32, 0));
+        }
+
+        // One packet fits in the queue, the other two are dropped instead of blocking
+        assert_eq!(ctx.pipeline_stats.channel_full.load(Ordering::Relaxed), 2);
+        assert_eq!(ctx.pipeline_stats.dropped_packets.load(Ordering::Relaxed), 2);
+        assert_eq!(ctx.traffic_state.total_packets.load(Ordering::Relaxed), 3);
     }
 }
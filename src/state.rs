@@ -1,78 +1,500 @@
+use crate::rates::RateHistory;
+use dashmap::mapref::entry::{Entry, VacantEntry};
 use dashmap::DashMap;
 use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use tokio::time::Instant;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant};
+
+/// TCP header flag bits as stored in `PacketMetadata::tcp_flags`
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+/// Capacity of the flow event channel; slower subscribers miss the oldest events
+const FLOW_EVENT_CAPACITY: usize = 4096;
+
+/// Capacity of the packet event channel, which only carries packets while someone listens
+const PACKET_EVENT_CAPACITY: usize = 8192;
+
+/// Number of ended flows kept for `/api/live`
+const RECENTLY_CLOSED_LEN: usize = 50;
+
+/// How long a closed TCP flow's key is remembered so that trailing ACKs and retransmitted
+/// FIN/RST segments do not start a new flow
+const CLOSED_FLOW_LINGER: Duration = Duration::from_secs(30);
+
+/// IP protocol numbers
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+/// Protocol label of a packet: the transport protocol when it is TCP or UDP,
+/// otherwise the IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Protocol {
+    #[serde(rename = "TCP")]
+    Tcp,
+    #[serde(rename = "UDP")]
+    Udp,
+    #[serde(rename = "IPv4")]
+    Ipv4,
+    #[serde(rename = "IPv6")]
+    Ipv6,
+    Unknown,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+            Protocol::Ipv4 => "IPv4",
+            Protocol::Ipv6 => "IPv6",
+            Protocol::Unknown => "Unknown",
+        }
+    }
+
+    /// IP protocol number for transport protocols, 0 when not known
+    pub fn ip_number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => IPPROTO_TCP,
+            Protocol::Udp => IPPROTO_UDP,
+            _ => 0,
+        }
+    }
+
+    /// Label for traffic of IP protocol `number` between addresses of `addr`'s family
+    pub fn from_ip_number(number: u8, addr: &IpAddr) -> Self {
+        match (number, addr) {
+            (IPPROTO_TCP, _) => Protocol::Tcp,
+            (IPPROTO_UDP, _) => Protocol::Udp,
+            (_, IpAddr::V4(_)) => Protocol::Ipv4,
+            (_, IpAddr::V6(_)) => Protocol::Ipv6,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = ();
+
+    /// Case-insensitive; anything unrecognised maps to `Unknown`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            "ipv4" => Protocol::Ipv4,
+            "ipv6" => Protocol::Ipv6,
+            _ => Protocol::Unknown,
+        })
+    }
+}
+
+/// Identifies a unidirectional flow: the 5-tuple plus where it was observed.
+/// Cheap to hash and clone, so it is used directly as a map key on the hot path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct FlowKey {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// IP protocol number (6 = TCP, 17 = UDP, ...)
+    pub protocol: u8,
+    pub vlan: Option<u16>,
+    pub interface: Arc<str>,
+}
+
+impl FlowKey {
+    pub fn from_packet(packet: &PacketMetadata) -> Self {
+        Self {
+            src_ip: packet.src_ip,
+            dst_ip: packet.dst_ip,
+            src_port: packet.src_port,
+            dst_port: packet.dst_port,
+            protocol: packet.ip_protocol,
+            vlan: packet.vlan,
+            interface: packet.interface.clone(),
+        }
+    }
+
+    pub fn src(&self) -> SocketAddr {
+        SocketAddr::new(self.src_ip, self.src_port)
+    }
+
+    pub fn dst(&self) -> SocketAddr {
+        SocketAddr::new(self.dst_ip, self.dst_port)
+    }
+
+    /// Direction-independent form of the key: the lower endpoint always comes first,
+    /// so both directions of a conversation map to the same key.
+    pub fn canonical(mut self) -> Self {
+        if (self.dst_ip, self.dst_port) < (self.src_ip, self.src_port) {
+            std::mem::swap(&mut self.src_ip, &mut self.dst_ip);
+            std::mem::swap(&mut self.src_port, &mut self.dst_port);
+        }
+        self
+    }
+}
+
+impl fmt::Display for FlowKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.src(), self.dst())
+    }
+}
 
 #[derive(Debug, Clone, Serialize)]
 pub struct PacketMetadata {
+    /// Capture time in milliseconds since the Unix epoch
     pub timestamp: i64,
-    pub src_ip: String,
-    pub dst_ip: String,
+    /// Capture time in nanoseconds since the Unix epoch (microsecond resolution unless nano precision is enabled)
+    pub timestamp_ns: i64,
+    /// Interface (or replayed capture file) the packet was captured on
+    pub interface: Arc<str>,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
     pub src_port: u16,
     pub dst_port: u16,
-    pub protocol: String,
+    pub protocol: Protocol,
+    /// IP protocol number (next header for IPv6)
+    pub ip_protocol: u8,
+    /// Outer VLAN ID, if the frame was tagged
+    pub vlan: Option<u16>,
     pub length: usize,
+    /// TCP flags (`TCP_SYN`, `TCP_ACK`, ...), 0 for other protocols
+    pub tcp_flags: u8,
 }
 
+/// Lifecycle state of a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowState {
+    /// Client sent a SYN, no reply seen yet
+    SynSent,
+    /// Server answered with SYN-ACK, waiting for the client's ACK
+    SynReceived,
+    /// Handshake completed, or a TCP flow picked up mid-stream
+    Established,
+    /// One side sent a FIN
+    Closing,
+    /// Both sides sent a FIN
+    Closed,
+    /// Terminated by a RST
+    Reset,
+    /// Connectionless traffic (UDP, other IP protocols)
+    Active,
+}
+
+impl FlowState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlowState::SynSent => "syn_sent",
+            FlowState::SynReceived => "syn_received",
+            FlowState::Established => "established",
+            FlowState::Closing => "closing",
+            FlowState::Closed => "closed",
+            FlowState::Reset => "reset",
+            FlowState::Active => "active",
+        }
+    }
+}
+
+/// Why a flow ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides sent a FIN
+    Fin,
+    /// A RST was seen
+    Rst,
+    /// No packets for `connection_timeout` seconds
+    Timeout,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Fin => "fin",
+            CloseReason::Rst => "rst",
+            CloseReason::Timeout => "timeout",
+        }
+    }
+}
+
+/// Stats for one bidirectional conversation. "Sent" is the client-to-server direction,
+/// "received" the server-to-client direction.
 #[derive(Debug, Serialize, Clone)]
 pub struct ConnectionStats {
+    /// Initiator of the conversation
+    pub client: SocketAddr,
+    /// Responder of the conversation
+    pub server: SocketAddr,
+    pub protocol: Protocol,
+    pub state: FlowState,
+    /// Capture timestamps of the first and latest packet (ns since Unix epoch)
+    pub first_seen_ns: i64,
+    pub last_seen_ns: i64,
     pub bytes_sent: u64,
     pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
     pub packets_count: u64,
+    /// Union of the TCP flags sent by the client and by the server
+    #[serde(skip)]
+    pub tcp_flags_sent: u8,
+    #[serde(skip)]
+    pub tcp_flags_received: u8,
     #[serde(skip)]
     pub last_seen: Instant,
+    #[serde(skip)]
+    fin_from_client: bool,
+    #[serde(skip)]
+    fin_from_server: bool,
 }
 
-impl Default for ConnectionStats {
-    fn default() -> Self {
-        Self {
+impl ConnectionStats {
+    /// Start tracking a conversation from its first observed packet(s). The initiator is the
+    /// sender of a SYN, the receiver of a SYN-ACK, or otherwise the sender of the first packet.
+    fn from_first_packet(packet: &PacketMetadata, packets: u64, first_ns: i64, src: SocketAddr, dst: SocketAddr) -> Self {
+        let syn_ack = packet.tcp_flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK;
+        let (client, server) = if syn_ack { (dst, src) } else { (src, dst) };
+        let state = match packet.protocol {
+            Protocol::Tcp if syn_ack => FlowState::SynReceived,
+            Protocol::Tcp if packet.tcp_flags & TCP_SYN != 0 => FlowState::SynSent,
+            Protocol::Tcp => FlowState::Established,
+            _ => FlowState::Active,
+        };
+        let mut stats = Self {
+            client,
+            server,
+            protocol: packet.protocol,
+            state,
+            first_seen_ns: first_ns,
+            last_seen_ns: packet.timestamp_ns,
             bytes_sent: 0,
             bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
             packets_count: 0,
+            tcp_flags_sent: 0,
+            tcp_flags_received: 0,
             last_seen: Instant::now(),
+            fin_from_client: false,
+            fin_from_server: false,
+        };
+        stats.record(packet, packets, first_ns, !syn_ack);
+        stats
+    }
+
+    /// Account for `packets` packets of `packet.length` bytes in total, seen from `first_ns`
+    /// to `packet.timestamp_ns`, and advance the TCP state machine. Returns the close reason
+    /// when the packets end the flow.
+    fn record(&mut self, packet: &PacketMetadata, packets: u64, first_ns: i64, from_client: bool) -> Option<CloseReason> {
+        let bytes = packet.length as u64;
+        if from_client {
+            self.bytes_sent += bytes;
+            self.packets_sent += packets;
+            self.tcp_flags_sent |= packet.tcp_flags;
+        } else {
+            self.bytes_received += bytes;
+            self.packets_received += packets;
+            self.tcp_flags_received |= packet.tcp_flags;
+        }
+        self.packets_count += packets;
+        self.last_seen = Instant::now();
+        self.first_seen_ns = self.first_seen_ns.min(first_ns);
+        self.last_seen_ns = self.last_seen_ns.max(packet.timestamp_ns);
+
+        if self.protocol == Protocol::Tcp {
+            self.advance(packet.tcp_flags, from_client)
+        } else {
+            None
+        }
+    }
+
+    fn advance(&mut self, flags: u8, from_client: bool) -> Option<CloseReason> {
+        if flags & TCP_RST != 0 {
+            self.state = FlowState::Reset;
+            return Some(CloseReason::Rst);
+        }
+
+        if flags & TCP_FIN != 0 {
+            if from_client {
+                self.fin_from_client = true;
+            } else {
+                self.fin_from_server = true;
+            }
+            if self.fin_from_client && self.fin_from_server {
+                self.state = FlowState::Closed;
+                return Some(CloseReason::Fin);
+            }
+            self.state = FlowState::Closing;
+            return None;
+        }
+
+        let syn_ack = flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK;
+        self.state = match self.state {
+            FlowState::SynSent if syn_ack && !from_client => FlowState::SynReceived,
+            FlowState::SynReceived if flags & TCP_ACK != 0 && !syn_ack && from_client => {
+                FlowState::Established
+            }
+            state => state,
+        };
+        None
+    }
+
+    fn summary(&self, key: FlowKey, reason: CloseReason) -> FlowSummary {
+        FlowSummary {
+            key,
+            client: self.client,
+            server: self.server,
+            protocol: self.protocol,
+            state: self.state,
+            close_reason: reason,
+            start_ns: self.first_seen_ns,
+            end_ns: self.last_seen_ns,
+            duration_ms: (self.last_seen_ns - self.first_seen_ns) / 1_000_000,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            tcp_flags_sent: self.tcp_flags_sent,
+            tcp_flags_received: self.tcp_flags_received,
         }
     }
 }
 
-/// Holds accumulated stats for a single connection within an aggregation time window.
-/// Used by the storage writer when aggregation is enabled.
+/// Final record of a conversation, produced when it ends
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowSummary {
+    /// Canonical flow key
+    pub key: FlowKey,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub protocol: Protocol,
+    /// State the flow was in when it ended; a timeout in `syn_sent` marks a half-open connection
+    pub state: FlowState,
+    pub close_reason: CloseReason,
+    pub start_ns: i64,
+    pub end_ns: i64,
+    pub duration_ms: i64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Union of the TCP flags sent by the client and by the server
+    #[serde(skip)]
+    pub tcp_flags_sent: u8,
+    #[serde(skip)]
+    pub tcp_flags_received: u8,
+}
+
+/// Flow lifecycle notification broadcast by `TrafficState`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FlowEvent {
+    Start {
+        key: FlowKey,
+        client: SocketAddr,
+        server: SocketAddr,
+        protocol: Protocol,
+        timestamp_ns: i64,
+    },
+    End(FlowSummary),
+}
+
+/// Packets counted by `TrafficState`, broadcast to live stream subscribers
+#[derive(Debug, Clone)]
+pub struct PacketEvent {
+    pub packet: PacketMetadata,
+    /// Number of packets `packet` stands for; more than 1 for received flow records
+    pub packets: u64,
+}
+
+/// Packet and byte counters for a single capture interface or protocol
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct TrafficCounters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Holds accumulated stats for a single conversation within an aggregation time window.
+/// Used by the storage writer when aggregation is enabled. "Sent" is the direction of the
+/// first packet seen in the window.
 #[derive(Debug, Clone)]
 pub struct AggregatedBucket {
-    pub first_timestamp: i64,
-    pub src_ip: String,
-    pub dst_ip: String,
-    pub src_port: u16,
-    pub dst_port: u16,
-    pub protocol: String,
-    pub packet_count: u64,
-    pub total_bytes: u64,
+    pub first_timestamp_ns: i64,
+    pub last_timestamp_ns: i64,
+    /// Canonical flow key
+    pub key: FlowKey,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub protocol: Protocol,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
 }
 
 impl AggregatedBucket {
     pub fn from_packet(packet: &PacketMetadata) -> Self {
-        Self {
-            first_timestamp: packet.timestamp,
-            src_ip: packet.src_ip.clone(),
-            dst_ip: packet.dst_ip.clone(),
-            src_port: packet.src_port,
-            dst_port: packet.dst_port,
-            protocol: packet.protocol.clone(),
-            packet_count: 1,
-            total_bytes: packet.length as u64,
-        }
+        let mut bucket = Self {
+            first_timestamp_ns: packet.timestamp_ns,
+            last_timestamp_ns: packet.timestamp_ns,
+            key: FlowKey::from_packet(packet).canonical(),
+            src: SocketAddr::new(packet.src_ip, packet.src_port),
+            dst: SocketAddr::new(packet.dst_ip, packet.dst_port),
+            protocol: packet.protocol,
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
+        };
+        bucket.merge(packet);
+        bucket
     }
 
     pub fn merge(&mut self, packet: &PacketMetadata) {
-        self.packet_count += 1;
-        self.total_bytes += packet.length as u64;
+        let bytes = packet.length as u64;
+        if SocketAddr::new(packet.src_ip, packet.src_port) == self.src {
+            self.bytes_sent += bytes;
+            self.packets_sent += 1;
+        } else {
+            self.bytes_received += bytes;
+            self.packets_received += 1;
+        }
+        self.first_timestamp_ns = self.first_timestamp_ns.min(packet.timestamp_ns);
+        self.last_timestamp_ns = self.last_timestamp_ns.max(packet.timestamp_ns);
     }
 }
 
 pub struct TrafficState {
-    pub connections: DashMap<String, ConnectionStats>, // Key: "src_ip:port -> dst_ip:port"
+    /// Active conversations, keyed by `FlowKey::canonical`
+    pub connections: DashMap<FlowKey, ConnectionStats>,
     pub total_packets: AtomicU64,
     pub total_bytes: AtomicU64,
     pub active_connections: AtomicUsize,
+    pub interfaces: DashMap<Arc<str>, TrafficCounters>,
+    pub protocols: DashMap<Protocol, TrafficCounters>,
+    /// Keys of recently closed TCP flows and when they closed
+    closed: DashMap<FlowKey, Instant>,
+    /// Latest ended flows, newest first
+    pub recently_closed: Mutex<VecDeque<FlowSummary>>,
+    /// Per-second packet and byte counts of the last hour
+    pub rates: Mutex<RateHistory>,
+    events: broadcast::Sender<FlowEvent>,
+    packet_events: broadcast::Sender<PacketEvent>,
 }
 
 impl TrafficState {
@@ -82,38 +504,146 @@ impl TrafficState {
             total_packets: AtomicU64::new(0),
             total_bytes: AtomicU64::new(0),
             active_connections: AtomicUsize::new(0),
+            interfaces: DashMap::new(),
+            protocols: DashMap::new(),
+            closed: DashMap::new(),
+            recently_closed: Mutex::new(VecDeque::with_capacity(RECENTLY_CLOSED_LEN)),
+            rates: Mutex::new(RateHistory::default()),
+            events: broadcast::channel(FLOW_EVENT_CAPACITY).0,
+            packet_events: broadcast::channel(PACKET_EVENT_CAPACITY).0,
         }
     }
 
+    /// Receive flow start and end events
+    pub fn subscribe(&self) -> broadcast::Receiver<FlowEvent> {
+        self.events.subscribe()
+    }
+
+    /// Receive every packet as it is counted. Packets are only copied while at least one
+    /// receiver exists; receivers that fall behind miss the oldest packets.
+    pub fn subscribe_packets(&self) -> broadcast::Receiver<PacketEvent> {
+        self.packet_events.subscribe()
+    }
+
     pub fn update(&self, packet: &PacketMetadata) {
-        let key = format!(
-            "{}:{} -> {}:{}",
-            packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port
-        );
+        self.update_many(packet, 1, packet.timestamp_ns);
+    }
 
-        self.connections
-            .entry(key)
-            .and_modify(|stats| {
-                stats.packets_count += 1;
-                stats.bytes_sent += packet.length as u64; 
-                stats.last_seen = Instant::now();
-            })
-            .or_insert_with(|| {
-                self.active_connections.fetch_add(1, Ordering::Relaxed);
-                ConnectionStats {
-                    bytes_sent: packet.length as u64,
-                    packets_count: 1,
-                    ..Default::default()
-                }
+    /// Account for several packets of one direction of a flow at once, e.g. a flow record
+    /// received from an exporter. `packet.length` is their total size, `first_ns` the time
+    /// of the first and `packet.timestamp_ns` of the last.
+    pub fn update_many(&self, packet: &PacketMetadata, packets: u64, first_ns: i64) {
+        if self.packet_events.receiver_count() > 0 {
+            let _ = self.packet_events.send(PacketEvent {
+                packet: packet.clone(),
+                packets,
             });
+        }
+
+        let src = SocketAddr::new(packet.src_ip, packet.src_port);
+        let dst = SocketAddr::new(packet.dst_ip, packet.dst_port);
+        // Both directions of a conversation share one canonical key
+        let key = FlowKey::from_packet(packet).canonical();
 
-        self.total_packets.fetch_add(1, Ordering::Relaxed);
+        let ended = match self.connections.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let from_client = entry.get().client == src;
+                match entry.get_mut().record(packet, packets, first_ns, from_client) {
+                    Some(reason) => {
+                        let (key, stats) = entry.remove_entry();
+                        self.closed.insert(key.clone(), Instant::now());
+                        Some(stats.summary(key, reason))
+                    }
+                    None => None,
+                }
+            }
+            Entry::Vacant(entry) => self.start_flow(entry, packet, packets, first_ns, src, dst),
+        };
+        if let Some(summary) = ended {
+            self.end_flow(summary);
+        }
+
+        let mut iface = self.interfaces.entry(packet.interface.clone()).or_default();
+        iface.packets += packets;
+        iface.bytes += packet.length as u64;
+        drop(iface);
+
+        let mut protocol = self.protocols.entry(packet.protocol).or_default();
+        protocol.packets += packets;
+        protocol.bytes += packet.length as u64;
+        drop(protocol);
+
+        self.total_packets.fetch_add(packets, Ordering::Relaxed);
         self.total_bytes
             .fetch_add(packet.length as u64, Ordering::Relaxed);
     }
 
-    /// Remove connections that haven't been seen for the given duration
-    pub fn cleanup_stale_connections(&self, timeout: tokio::time::Duration) {
+    fn start_flow(
+        &self,
+        entry: VacantEntry<'_, FlowKey, ConnectionStats>,
+        packet: &PacketMetadata,
+        packets: u64,
+        first_ns: i64,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> Option<FlowSummary> {
+        // Late segments of a closed connection must not resurrect it; only a new SYN
+        // (port reuse) starts a fresh flow.
+        if packet.protocol == Protocol::Tcp && self.closed.contains_key(entry.key()) {
+            if packet.tcp_flags & TCP_SYN == 0 {
+                return None;
+            }
+            self.closed.remove(entry.key());
+        }
+
+        let stats = ConnectionStats::from_first_packet(packet, packets, first_ns, src, dst);
+        let _ = self.events.send(FlowEvent::Start {
+            key: entry.key().clone(),
+            client: stats.client,
+            server: stats.server,
+            protocol: stats.protocol,
+            timestamp_ns: stats.first_seen_ns,
+        });
+
+        // A lone RST is a complete (refused) flow on its own
+        if stats.state == FlowState::Reset {
+            let key = entry.key().clone();
+            self.closed.insert(key.clone(), Instant::now());
+            self.active_connections.fetch_add(1, Ordering::Relaxed);
+            return Some(stats.summary(key, CloseReason::Rst));
+        }
+
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        entry.insert(stats);
+        None
+    }
+
+    fn end_flow(&self, summary: FlowSummary) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+
+        let mut recent = self.recently_closed.lock().unwrap();
+        if recent.len() == RECENTLY_CLOSED_LEN {
+            recent.pop_back();
+        }
+        recent.push_front(summary.clone());
+        drop(recent);
+
+        let _ = self.events.send(FlowEvent::End(summary));
+    }
+
+    /// Add the traffic counted since the previous call to the rate history. Called once a second.
+    pub fn sample_rates(&self) {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.rates.lock().unwrap().record(
+            now,
+            self.total_packets.load(Ordering::Relaxed),
+            self.total_bytes.load(Ordering::Relaxed),
+        );
+    }
+
+    /// End connections that haven't been seen for the given duration and forget closed
+    /// flows once their linger period is over
+    pub fn cleanup_stale_connections(&self, timeout: Duration) {
         let now = Instant::now();
         let mut to_remove = Vec::new();
 
@@ -123,34 +653,37 @@ impl TrafficState {
             }
         }
 
-        let removed_count = to_remove.len();
         for key in to_remove {
-            self.connections.remove(&key);
+            // Re-check under the shard lock: a packet may have arrived in the meantime
+            if let Some((key, stats)) = self
+                .connections
+                .remove_if(&key, |_, stats| now.duration_since(stats.last_seen) > timeout)
+            {
+                self.end_flow(stats.summary(key, CloseReason::Timeout));
+            }
         }
 
-        if removed_count > 0 {
-            self.active_connections
-                .fetch_sub(removed_count, Ordering::Relaxed);
-        }
+        self.closed
+            .retain(|_, closed_at| now.duration_since(*closed_at) <= CLOSED_FLOW_LINGER);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing;
+
+    fn tcp_packet(src_ip: &str, src_port: u16, dst_ip: &str, dst_port: u16, length: usize, tcp_flags: u8) -> PacketMetadata {
+        PacketMetadata {
+            tcp_flags,
+            ..testing::packet(Protocol::Tcp, src_ip, src_port, dst_ip, dst_port, length)
+        }
+    }
 
     #[test]
     fn test_traffic_state_update() {
         let state = TrafficState::new();
-        let packet = PacketMetadata {
-            timestamp: 0,
-            src_ip: "127.0.0.1".into(),
-            dst_ip: "127.0.0.1".into(),
-            src_port: 80,
-            dst_port: 1234,
-            protocol: "TCP".into(),
-            length: 100,
-        };
+        let packet = testing::packet(Protocol::Tcp, "127.0.0.1", 80, "127.0.0.1", 1234, 100);
 
         state.update(&packet);
 
@@ -164,5 +697,147 @@ mod tests {
         assert_eq!(state.total_bytes.load(Ordering::Relaxed), 200);
         // Connection count should stay 1
         assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+        assert_eq!(state.interfaces.get("eth0").unwrap().packets, 2);
+        assert_eq!(state.protocols.get(&Protocol::Tcp).unwrap().bytes, 200);
+    }
+
+    #[test]
+    fn test_bidirectional_flow() {
+        let state = TrafficState::new();
+        let packet = |src_ip: &str, src_port, dst_ip: &str, dst_port, length, tcp_flags| {
+            tcp_packet(src_ip, src_port, dst_ip, dst_port, length, tcp_flags)
+        };
+
+        // Capture starts at the SYN-ACK: the server is the sender
+        state.update(&packet("10.0.0.2", 443, "10.0.0.1", 50000, 60, TCP_SYN | TCP_ACK));
+        state.update(&packet("10.0.0.1", 50000, "10.0.0.2", 443, 500, TCP_ACK));
+        state.update(&packet("10.0.0.2", 443, "10.0.0.1", 50000, 1500, TCP_ACK));
+
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+        let flow = state.connections.iter().next().unwrap();
+        assert_eq!(flow.client, "10.0.0.1:50000".parse().unwrap());
+        assert_eq!(flow.server, "10.0.0.2:443".parse().unwrap());
+        assert_eq!(flow.bytes_sent, 500);
+        assert_eq!(flow.packets_sent, 1);
+        assert_eq!(flow.bytes_received, 1560);
+        assert_eq!(flow.packets_received, 2);
+        assert_eq!(flow.packets_count, 3);
+    }
+
+    #[test]
+    fn test_flow_key_keeps_protocols_apart() {
+        let tcp = tcp_packet("10.0.0.1", 5353, "10.0.0.2", 53, 60, 0);
+        let mut udp = tcp.clone();
+        udp.protocol = Protocol::Udp;
+        udp.ip_protocol = IPPROTO_UDP;
+
+        let state = TrafficState::new();
+        state.update(&tcp);
+        state.update(&udp);
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 2);
+
+        let forward = FlowKey::from_packet(&tcp);
+        let reverse = FlowKey::from_packet(&tcp_packet("10.0.0.2", 53, "10.0.0.1", 5353, 60, 0));
+        assert_ne!(forward, reverse);
+        assert_eq!(forward.canonical(), reverse.canonical());
+    }
+
+    #[test]
+    fn test_tcp_lifecycle() {
+        let state = TrafficState::new();
+        let mut events = state.subscribe();
+        let client = |flags| tcp_packet("10.0.0.1", 50000, "10.0.0.2", 80, 60, flags);
+        let server = |flags| tcp_packet("10.0.0.2", 80, "10.0.0.1", 50000, 60, flags);
+        let flow_state = || state.connections.iter().next().map(|flow| flow.state);
+
+        state.update(&client(TCP_SYN));
+        assert_eq!(flow_state(), Some(FlowState::SynSent));
+        state.update(&server(TCP_SYN | TCP_ACK));
+        assert_eq!(flow_state(), Some(FlowState::SynReceived));
+        state.update(&client(TCP_ACK));
+        assert_eq!(flow_state(), Some(FlowState::Established));
+
+        state.update(&client(TCP_FIN | TCP_ACK));
+        assert_eq!(flow_state(), Some(FlowState::Closing));
+        state.update(&server(TCP_FIN | TCP_ACK));
+        assert!(state.connections.is_empty());
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 0);
+
+        // The final ACK belongs to the closed flow and must not start a new one
+        state.update(&client(TCP_ACK));
+        assert!(state.connections.is_empty());
+
+        assert!(matches!(events.try_recv(), Ok(FlowEvent::Start { .. })));
+        match events.try_recv() {
+            Ok(FlowEvent::End(summary)) => {
+                assert_eq!(summary.close_reason, CloseReason::Fin);
+                assert_eq!(summary.packets_sent, 3);
+                assert_eq!(summary.packets_received, 2);
+            }
+            other => panic!("expected flow end, got {:?}", other),
+        }
+
+        // Port reuse: a new SYN starts a new flow
+        state.update(&client(TCP_SYN));
+        assert_eq!(flow_state(), Some(FlowState::SynSent));
+    }
+
+    #[test]
+    fn test_reset_and_timeout() {
+        let state = TrafficState::new();
+        state.update(&tcp_packet("10.0.0.1", 50000, "10.0.0.2", 80, 60, TCP_SYN));
+        state.update(&tcp_packet("10.0.0.2", 80, "10.0.0.1", 50000, 60, TCP_RST | TCP_ACK));
+        state.update(&tcp_packet("10.0.0.1", 50001, "10.0.0.2", 80, 60, TCP_SYN));
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+
+        std::thread::sleep(Duration::from_millis(5));
+        state.cleanup_stale_connections(Duration::from_millis(1));
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 0);
+
+        let recent = state.recently_closed.lock().unwrap();
+        assert_eq!(recent.len(), 2);
+        // A connection that never completed its handshake is half-open
+        assert_eq!(recent[0].close_reason, CloseReason::Timeout);
+        assert_eq!(recent[0].state, FlowState::SynSent);
+        assert_eq!(recent[1].close_reason, CloseReason::Rst);
+    }
+
+    /// Compares the former formatted-string keys with `FlowKey` on the `update` hot path.
+    /// Run with `cargo test --release bench_flow_key -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_flow_key_vs_string_key() {
+        const PACKETS: usize = 2_000_000;
+        let packets: Vec<PacketMetadata> = (0..1024u16)
+            .map(|i| tcp_packet("10.0.0.1", 30000 + i, "192.168.1.20", 443, 1500, TCP_ACK))
+            .collect();
+
+        let string_map: DashMap<String, u64> = DashMap::new();
+        let started = std::time::Instant::now();
+        for i in 0..PACKETS {
+            let packet = &packets[i % packets.len()];
+            let key = format!(
+                "{}:{} -> {}:{}",
+                packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port
+            );
+            *string_map.entry(key).or_default() += 1;
+        }
+        let string_elapsed = started.elapsed();
+
+        let key_map: DashMap<FlowKey, u64> = DashMap::new();
+        let started = std::time::Instant::now();
+        for i in 0..PACKETS {
+            let key = FlowKey::from_packet(&packets[i % packets.len()]).canonical();
+            *key_map.entry(key).or_default() += 1;
+        }
+        let key_elapsed = started.elapsed();
+
+        let rate = |elapsed: std::time::Duration| PACKETS as f64 / elapsed.as_secs_f64() / 1e6;
+        println!(
+            "string key: {:.2} Mpps, FlowKey: {:.2} Mpps ({:.1}x)",
+            rate(string_elapsed),
+            rate(key_elapsed),
+            string_elapsed.as_secs_f64() / key_elapsed.as_secs_f64()
+        );
     }
 }
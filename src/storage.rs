@@ -1,51 +1,231 @@
-use crate::state::{AggregatedBucket, PacketMetadata};
-use chrono;
-use rusqlite::{params, Connection, Result};
+use crate::metrics::Histogram;
+use crate::migrations::{self, MigrationError};
+use crate::rollups::{self, Dimension, Resolution, RollupPoint};
+use crate::state::{AggregatedBucket, FlowEvent, FlowKey, FlowSummary, PacketMetadata, Protocol};
+use ipnet::IpNet;
+use rusqlite::{params, Connection, Result, Row, ToSql};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::Receiver;
 use tokio::time::{interval, Duration};
 
+/// Rows fetched per round when CIDR filters are checked outside SQL
+const HISTORY_BATCH: usize = 1000;
+
+/// Rows examined per history request before a partial page is returned
+const HISTORY_MAX_SCAN: usize = 100_000;
+
 #[derive(Clone)]
 pub struct Storage {
     conn: Arc<std::sync::Mutex<Connection>>,
+    /// Failed transactions, statements and inserts in the writer
+    write_failures: Arc<AtomicU64>,
+    /// Ended flows the flow writer fell too far behind to receive
+    lost_flow_records: Arc<AtomicU64>,
+    /// Time taken by committed `packets` and `flows` batches
+    packet_flush_latency: Arc<Histogram>,
+    flow_flush_latency: Arc<Histogram>,
+}
+
+/// Origin of a row in the `flows` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowKind {
+    /// A complete conversation, written when it ended
+    Flow,
+    /// Traffic of one conversation during an aggregation window
+    Window,
+}
+
+impl FlowKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlowKind::Flow => "flow",
+            FlowKind::Window => "window",
+        }
+    }
+}
+
+/// A row of the `flows` table. "Sent" is the `src` to `dst` direction; for ended flows
+/// `src` is the client.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowRecord {
+    pub kind: FlowKind,
+    pub start_ns: i64,
+    pub end_ns: i64,
+    pub duration_ms: i64,
+    pub interface: Arc<str>,
+    pub vlan: Option<u16>,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: Protocol,
+    pub ip_protocol: u8,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// TCP state when the flow ended (ended flows only)
+    pub state: Option<String>,
+    /// `fin`, `rst` or `timeout` (ended flows only)
+    pub close_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Position of a packet row in history order, handed to clients as an opaque token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryCursor {
+    timestamp: i64,
+    id: i64,
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.timestamp, self.id)
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor '{}'", s);
+        let (timestamp, id) = s.split_once('_').ok_or_else(invalid)?;
+        Ok(Self {
+            timestamp: timestamp.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Filters and paging for `query_history`. Every filter is optional.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Time range in ms since the Unix epoch, `from` inclusive, `to` exclusive
+    pub from_ms: Option<i64>,
+    pub to_ms: Option<i64>,
+    pub src: Option<IpNet>,
+    pub dst: Option<IpNet>,
+    /// Either source or destination
+    pub ip: Option<IpNet>,
+    /// Either source or destination port
+    pub port: Option<u16>,
+    /// Protocol label, case-insensitive
+    pub protocol: Option<String>,
+    pub min_length: Option<usize>,
+    pub interface: Option<String>,
+    pub order: SortOrder,
+    /// Continue after this row (the `next_cursor` of the previous page)
+    pub cursor: Option<HistoryCursor>,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    /// Check the address filters. Single addresses were already applied in SQL.
+    fn matches_networks(&self, packet: &PacketMetadata) -> bool {
+        self.src.is_none_or(|net| net.contains(&packet.src_ip))
+            && self.dst.is_none_or(|net| net.contains(&packet.dst_ip))
+            && self
+                .ip
+                .is_none_or(|net| net.contains(&packet.src_ip) || net.contains(&packet.dst_ip))
+    }
+}
+
+#[derive(Debug)]
+pub struct HistoryPage {
+    pub packets: Vec<PacketMetadata>,
+    /// Pass back as `cursor` to fetch the next page; `None` when there are no more rows
+    pub next_cursor: Option<HistoryCursor>,
+}
+
+impl From<&FlowSummary> for FlowRecord {
+    fn from(summary: &FlowSummary) -> Self {
+        Self {
+            kind: FlowKind::Flow,
+            start_ns: summary.start_ns,
+            end_ns: summary.end_ns,
+            duration_ms: summary.duration_ms,
+            interface: summary.key.interface.clone(),
+            vlan: summary.key.vlan,
+            src_ip: summary.client.ip(),
+            dst_ip: summary.server.ip(),
+            src_port: summary.client.port(),
+            dst_port: summary.server.port(),
+            protocol: summary.protocol,
+            ip_protocol: summary.key.protocol,
+            bytes_sent: summary.bytes_sent,
+            bytes_received: summary.bytes_received,
+            packets_sent: summary.packets_sent,
+            packets_received: summary.packets_received,
+            state: Some(summary.state.as_str().to_string()),
+            close_reason: Some(summary.close_reason.as_str().to_string()),
+        }
+    }
+}
+
+impl From<&AggregatedBucket> for FlowRecord {
+    fn from(bucket: &AggregatedBucket) -> Self {
+        Self {
+            kind: FlowKind::Window,
+            start_ns: bucket.first_timestamp_ns,
+            end_ns: bucket.last_timestamp_ns,
+            duration_ms: (bucket.last_timestamp_ns - bucket.first_timestamp_ns) / 1_000_000,
+            interface: bucket.key.interface.clone(),
+            vlan: bucket.key.vlan,
+            src_ip: bucket.src.ip(),
+            dst_ip: bucket.dst.ip(),
+            src_port: bucket.src.port(),
+            dst_port: bucket.dst.port(),
+            protocol: bucket.protocol,
+            ip_protocol: bucket.key.protocol,
+            bytes_sent: bucket.bytes_sent,
+            bytes_received: bucket.bytes_received,
+            packets_sent: bucket.packets_sent,
+            packets_received: bucket.packets_received,
+            state: None,
+            close_reason: None,
+        }
+    }
 }
 
 impl Storage {
-    pub fn new(db_path: &str) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
+    /// Open the database and migrate it to the current schema
+    pub fn new(db_path: &str) -> std::result::Result<Self, MigrationError> {
+        let mut conn = Connection::open(db_path)?;
         
         // Enable WAL mode for concurrency (PRAGMA returns a result, so use query_row)
         let _: String = conn.query_row("PRAGMA journal_mode=WAL;", [], |row| row.get(0))?;
         conn.execute_batch("PRAGMA synchronous=NORMAL;")?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS packets (
-                id INTEGER PRIMARY KEY,
-                timestamp INTEGER NOT NULL,
-                src_ip TEXT NOT NULL,
-                dst_ip TEXT NOT NULL,
-                src_port INTEGER,
-                dst_port INTEGER,
-                protocol TEXT,
-                length INTEGER
-            )",
-            [],
-        )?;
-        
-        conn.execute(
-             "CREATE INDEX IF NOT EXISTS idx_timestamp ON packets(timestamp)",
-             []
-        )?;
+        migrations::migrate(&mut conn)?;
 
         Ok(Self {
             conn: Arc::new(std::sync::Mutex::new(conn)),
+            write_failures: Arc::new(AtomicU64::new(0)),
+            lost_flow_records: Arc::new(AtomicU64::new(0)),
+            packet_flush_latency: Arc::new(Histogram::default()),
+            flow_flush_latency: Arc::new(Histogram::default()),
         })
     }
 
     /// Main writer loop. Behavior depends on `aggregation_window_seconds`:
     ///   - 0: store every incoming packet individually (original behavior).
-    ///   - >0: accumulate per-connection stats and flush summary rows on a timer.
+    ///   - >0: accumulate per-connection stats and flush `window` flow rows on a timer.
     pub async fn run_writer(&self, rx: Receiver<PacketMetadata>, aggregation_window_seconds: u64) {
         if aggregation_window_seconds == 0 {
             self.run_writer_raw(rx).await;
@@ -76,18 +256,15 @@ impl Storage {
         }
     }
 
-    /// Aggregated mode: collapse packets per connection key over a time window.
+    /// Aggregated mode: collapse packets per conversation over a time window.
     async fn run_writer_aggregated(&self, mut rx: Receiver<PacketMetadata>, window_secs: u64) {
-        let mut buckets: HashMap<String, AggregatedBucket> = HashMap::new();
+        let mut buckets: HashMap<FlowKey, AggregatedBucket> = HashMap::new();
         let mut ticker = interval(Duration::from_secs(window_secs));
 
         loop {
             tokio::select! {
                 Some(packet) = rx.recv() => {
-                    let key = format!(
-                        "{}:{} -> {}:{}",
-                        packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port
-                    );
+                    let key = FlowKey::from_packet(&packet).canonical();
                     buckets
                         .entry(key)
                         .and_modify(|b| b.merge(&packet))
@@ -104,22 +281,26 @@ impl Storage {
 
     fn flush(&self, buffer: &mut Vec<PacketMetadata>) {
          let mut conn = self.conn.lock().unwrap();
+         // Timed from here, so waits for the connection (e.g. a running rollup) are not counted
+         let started = Instant::now();
          let tx = match conn.transaction() {
              Ok(tx) => tx,
              Err(e) => {
                  eprintln!("Failed to start transaction: {}", e);
+                 self.write_failures.fetch_add(1, Ordering::Relaxed);
                  return;
              }
          };
 
          {
              let mut stmt = match tx.prepare(
-                 "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
-                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                 "INSERT INTO packets (timestamp, timestamp_ns, interface, src_ip, dst_ip, src_port, dst_port, protocol, length, tcp_flags)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
              ) {
                  Ok(stmt) => stmt,
                  Err(e) => {
                      eprintln!("Failed to prepare statement: {}", e);
+                     self.write_failures.fetch_add(1, Ordering::Relaxed);
                      return;
                  }
              };
@@ -127,98 +308,361 @@ impl Storage {
              for packet in buffer.iter() {
                  if let Err(e) = stmt.execute(params![
                      packet.timestamp,
-                     packet.src_ip,
-                     packet.dst_ip,
+                     packet.timestamp_ns,
+                     &*packet.interface,
+                     packet.src_ip.to_string(),
+                     packet.dst_ip.to_string(),
                      packet.src_port,
                      packet.dst_port,
-                     packet.protocol,
-                     packet.length
+                     packet.protocol.as_str(),
+                     packet.length,
+                     packet.tcp_flags
                  ]) {
                      eprintln!("Failed to insert packet: {}", e);
+                     self.write_failures.fetch_add(1, Ordering::Relaxed);
                  }
              }
          } // stmt dropped here
 
          if let Err(e) = tx.commit() {
              eprintln!("Failed to commit transaction: {}", e);
+             self.write_failures.fetch_add(1, Ordering::Relaxed);
          } else {
+             self.packet_flush_latency.observe(started.elapsed());
              buffer.clear();
          }
     }
 
-    /// Flush aggregated buckets as summary rows. Each bucket becomes one row where
-    /// `length` holds the total bytes accumulated over the window.
-    fn flush_aggregated(&self, buckets: &mut HashMap<String, AggregatedBucket>) {
+    /// Flush aggregated buckets as `window` rows of the `flows` table
+    fn flush_aggregated(&self, buckets: &mut HashMap<FlowKey, AggregatedBucket>) {
+        let records: Vec<FlowRecord> = buckets.values().map(FlowRecord::from).collect();
+        if self.insert_flows(&records) {
+            buckets.clear();
+        }
+    }
+
+    /// Persist flows as they end. Runs alongside the packet writer in every mode.
+    pub async fn run_flow_writer(&self, mut events: broadcast::Receiver<FlowEvent>) {
+        let mut buffer = Vec::new();
+        let mut ticker = interval(Duration::from_secs(2));
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(FlowEvent::End(summary)) => {
+                        buffer.push(FlowRecord::from(&summary));
+                        if buffer.len() >= 1000 && self.insert_flows(&buffer) {
+                            buffer.clear();
+                        }
+                    }
+                    Ok(FlowEvent::Start { .. }) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        eprintln!("Flow writer fell behind, {} flow records lost", missed);
+                        self.lost_flow_records.fetch_add(missed, Ordering::Relaxed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => {
+                    if !buffer.is_empty() && self.insert_flows(&buffer) {
+                        buffer.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Insert rows into the `flows` table in one transaction. Returns false if the
+    /// transaction could not be committed.
+    pub fn insert_flows(&self, records: &[FlowRecord]) -> bool {
         let mut conn = self.conn.lock().unwrap();
+        let started = Instant::now();
         let tx = match conn.transaction() {
             Ok(tx) => tx,
             Err(e) => {
                 eprintln!("Failed to start transaction: {}", e);
-                return;
+                self.write_failures.fetch_add(1, Ordering::Relaxed);
+                return false;
             }
         };
 
         {
             let mut stmt = match tx.prepare(
-                "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                "INSERT INTO flows (kind, start_ns, end_ns, duration_ms, interface, vlan, src_ip, dst_ip,
+                                    src_port, dst_port, protocol, ip_protocol, bytes_sent, bytes_received,
+                                    packets_sent, packets_received, state, close_reason)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)"
             ) {
                 Ok(stmt) => stmt,
                 Err(e) => {
                     eprintln!("Failed to prepare statement: {}", e);
-                    return;
+                    self.write_failures.fetch_add(1, Ordering::Relaxed);
+                    return false;
                 }
             };
 
-            for bucket in buckets.values() {
+            for record in records {
                 if let Err(e) = stmt.execute(params![
-                    bucket.first_timestamp,
-                    bucket.src_ip,
-                    bucket.dst_ip,
-                    bucket.src_port,
-                    bucket.dst_port,
-                    bucket.protocol,
-                    bucket.total_bytes as i64
+                    record.kind.as_str(),
+                    record.start_ns,
+                    record.end_ns,
+                    record.duration_ms,
+                    &*record.interface,
+                    record.vlan,
+                    record.src_ip.to_string(),
+                    record.dst_ip.to_string(),
+                    record.src_port,
+                    record.dst_port,
+                    record.protocol.as_str(),
+                    record.ip_protocol,
+                    record.bytes_sent as i64,
+                    record.bytes_received as i64,
+                    record.packets_sent as i64,
+                    record.packets_received as i64,
+                    record.state,
+                    record.close_reason
                 ]) {
-                    eprintln!("Failed to insert aggregated row: {}", e);
+                    eprintln!("Failed to insert flow: {}", e);
+                    self.write_failures.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
 
         if let Err(e) = tx.commit() {
             eprintln!("Failed to commit transaction: {}", e);
-        } else {
-            buckets.clear();
+            self.write_failures.fetch_add(1, Ordering::Relaxed);
+            return false;
         }
+        self.flow_flush_latency.observe(started.elapsed());
+        true
     }
-    
-    pub fn query_history(&self, limit: usize) -> Result<Vec<PacketMetadata>> {
-         let conn = self.conn.lock().unwrap();
-         let mut stmt = conn.prepare(
-             "SELECT timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length 
-              FROM packets ORDER BY timestamp DESC LIMIT ?1"
-         )?;
-         
-         let rows = stmt.query_map([limit], |row| {
-             Ok(PacketMetadata {
-                 timestamp: row.get(0)?,
-                 src_ip: row.get(1)?,
-                 dst_ip: row.get(2)?,
-                 src_port: row.get(3)?,
-                 dst_port: row.get(4)?,
-                 protocol: row.get(5)?,
-                 length: row.get(6)?,
-             })
-         })?;
-         
-         let mut result = Vec::new();
-         for row in rows {
-             result.push(row?);
-         }
-         Ok(result)
+
+    /// Check that the database can still be read
+    pub fn check(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        migrations::schema_version(&conn).map(|_| ())
+    }
+
+    pub fn write_failures(&self) -> u64 {
+        self.write_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn lost_flow_records(&self) -> u64 {
+        self.lost_flow_records.load(Ordering::Relaxed)
+    }
+
+    pub fn packet_flush_latency(&self) -> &Histogram {
+        &self.packet_flush_latency
+    }
+
+    pub fn flow_flush_latency(&self) -> &Histogram {
+        &self.flow_flush_latency
+    }
+
+    /// Size of the database in bytes (`page_count * page_size`). This includes changes
+    /// still in the write-ahead log, but not the log file itself, which can be larger.
+    pub fn size_bytes(&self) -> Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok(page_count * page_size)
+    }
+
+    /// Most recently ended flows and aggregation windows, newest first
+    pub fn query_flows(&self, limit: usize, kind: Option<FlowKind>) -> Result<Vec<FlowRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT kind, start_ns, end_ns, duration_ms, COALESCE(interface, ''), vlan, src_ip, dst_ip,
+                    src_port, dst_port, protocol, ip_protocol, bytes_sent, bytes_received,
+                    packets_sent, packets_received, state, close_reason
+             FROM flows WHERE ?2 IS NULL OR kind = ?2 ORDER BY end_ns DESC LIMIT ?1"
+        )?;
+
+        let rows = stmt.query_map(params![limit, kind.map(|kind| kind.as_str())], |row| {
+            let kind = match row.get::<_, String>(0)?.as_str() {
+                "window" => FlowKind::Window,
+                _ => FlowKind::Flow,
+            };
+            let protocol: Protocol = row
+                .get::<_, Option<String>>(10)?
+                .unwrap_or_default()
+                .parse()
+                .unwrap_or(Protocol::Unknown);
+            Ok(FlowRecord {
+                kind,
+                start_ns: row.get(1)?,
+                end_ns: row.get(2)?,
+                duration_ms: row.get(3)?,
+                interface: Arc::from(row.get::<_, String>(4)?),
+                vlan: row.get(5)?,
+                src_ip: parse_ip(&row.get::<_, String>(6)?),
+                dst_ip: parse_ip(&row.get::<_, String>(7)?),
+                src_port: row.get(8)?,
+                dst_port: row.get(9)?,
+                protocol,
+                ip_protocol: row.get(11)?,
+                bytes_sent: row.get::<_, i64>(12)? as u64,
+                bytes_received: row.get::<_, i64>(13)? as u64,
+                packets_sent: row.get::<_, i64>(14)? as u64,
+                packets_received: row.get::<_, i64>(15)? as u64,
+                state: row.get(16)?,
+                close_reason: row.get(17)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    /// Packets matching `query`, one page at a time.
+    ///
+    /// Single addresses are matched in SQL; wider CIDR blocks cannot use an index on the
+    /// text columns and are checked here on batches of rows instead. A page may then come
+    /// back short (even empty) with a `next_cursor` when `HISTORY_MAX_SCAN` rows were
+    /// examined without filling it.
+    pub fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        let mut clauses: Vec<String> = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        let mut post_filter = false;
+
+        if let Some(from) = query.from_ms {
+            values.push(Box::new(from));
+            clauses.push(format!("timestamp >= ?{}", values.len()));
+        }
+        if let Some(to) = query.to_ms {
+            values.push(Box::new(to));
+            clauses.push(format!("timestamp < ?{}", values.len()));
+        }
+        for (net, columns) in [
+            (&query.src, &["src_ip"][..]),
+            (&query.dst, &["dst_ip"][..]),
+            (&query.ip, &["src_ip", "dst_ip"][..]),
+        ] {
+            let Some(net) = net else { continue };
+            if net.prefix_len() == net.max_prefix_len() {
+                values.push(Box::new(net.addr().to_string()));
+                let n = values.len();
+                let matches: Vec<String> = columns.iter().map(|c| format!("{} = ?{}", c, n)).collect();
+                clauses.push(format!("({})", matches.join(" OR ")));
+            } else {
+                post_filter = true;
+            }
+        }
+        if let Some(port) = query.port {
+            values.push(Box::new(port));
+            clauses.push(format!("(src_port = ?{0} OR dst_port = ?{0})", values.len()));
+        }
+        if let Some(ref protocol) = query.protocol {
+            values.push(Box::new(protocol.clone()));
+            clauses.push(format!("protocol = ?{} COLLATE NOCASE", values.len()));
+        }
+        if let Some(min_length) = query.min_length {
+            values.push(Box::new(min_length as i64));
+            clauses.push(format!("length >= ?{}", values.len()));
+        }
+        if let Some(ref interface) = query.interface {
+            values.push(Box::new(interface.clone()));
+            clauses.push(format!("interface = ?{}", values.len()));
+        }
+
+        let (comparison, direction) = match query.order {
+            SortOrder::Asc => (">", "ASC"),
+            SortOrder::Desc => ("<", "DESC"),
+        };
+        // Keyset pagination: the cursor condition is appended last so its parameters can
+        // move from batch to batch
+        let cursor_param = values.len() + 1;
+        let mut cursor_clauses = clauses.clone();
+        cursor_clauses.push(format!("(timestamp, id) {} (?{}, ?{})", comparison, cursor_param, cursor_param + 1));
+
+        let limit = query.limit.max(1);
+        let batch_size = if post_filter { HISTORY_BATCH.max(limit) } else { limit };
+        let sql = |clauses: &[String]| {
+            format!(
+                "SELECT id, timestamp, COALESCE(timestamp_ns, timestamp * 1000000), COALESCE(interface, ''),
+                        src_ip, dst_ip, src_port, dst_port, protocol, length, COALESCE(tcp_flags, 0)
+                 FROM packets {} ORDER BY timestamp {dir}, id {dir} LIMIT {}",
+                if clauses.is_empty() { String::new() } else { format!("WHERE {}", clauses.join(" AND ")) },
+                batch_size,
+                dir = direction
+            )
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let mut first_batch = Some(conn.prepare(&sql(&clauses))?);
+        let mut next_batch = conn.prepare(&sql(&cursor_clauses))?;
+
+        let mut page = HistoryPage { packets: Vec::new(), next_cursor: None };
+        let mut cursor = query.cursor;
+        let mut scanned = 0;
+        loop {
+            let rows: Vec<(HistoryCursor, PacketMetadata)> = match (cursor, first_batch.take()) {
+                (Some(position), _) => {
+                    let mut params: Vec<&dyn ToSql> = values.iter().map(|v| v.as_ref()).collect();
+                    params.push(&position.timestamp);
+                    params.push(&position.id);
+                    next_batch.query_map(params.as_slice(), history_row)?.collect::<Result<_>>()?
+                }
+                (None, Some(mut stmt)) => {
+                    let params: Vec<&dyn ToSql> = values.iter().map(|v| v.as_ref()).collect();
+                    stmt.query_map(params.as_slice(), history_row)?.collect::<Result<_>>()?
+                }
+                (None, None) => unreachable!("later batches always have a cursor"),
+            };
+
+            let exhausted = rows.len() < batch_size;
+            for (position, packet) in rows {
+                scanned += 1;
+                cursor = Some(position);
+                if query.matches_networks(&packet) {
+                    page.packets.push(packet);
+                    if page.packets.len() == limit {
+                        page.next_cursor = cursor;
+                        return Ok(page);
+                    }
+                }
+            }
+            if exhausted {
+                return Ok(page);
+            }
+            if scanned >= HISTORY_MAX_SCAN {
+                page.next_cursor = cursor;
+                return Ok(page);
+            }
+        }
+    }
+
+    /// Roll up stored traffic, see `rollups::step`. The lock is released between steps,
+    /// so a long backlog does not hold up the writer. Returns the buckets written.
+    pub fn run_rollups(&self, grace_ms: i64, sample_rate: u32) -> Result<usize> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let mut written = 0;
+        loop {
+            let (step_written, more) = rollups::step(&mut self.conn.lock().unwrap(), now_ms, grace_ms, sample_rate)?;
+            written += step_written;
+            if !more {
+                return Ok(written);
+            }
+        }
+    }
+
+    pub fn query_rollups(
+        &self,
+        resolution: Resolution,
+        dimension: Dimension,
+        from_ms: i64,
+        to_ms: i64,
+    ) -> Result<Vec<RollupPoint>> {
+        let conn = self.conn.lock().unwrap();
+        rollups::query(&conn, resolution, dimension, from_ms, to_ms)
     }
 
-    /// Delete packets older than the specified number of seconds
+    /// Delete rollup buckets of `resolution` older than the specified number of seconds
+    pub fn delete_old_rollups(&self, resolution: Resolution, older_than_seconds: u64) -> Result<usize> {
+        let cutoff_ms = chrono::Utc::now().timestamp_millis() - (older_than_seconds as i64 * 1000);
+        let conn = self.conn.lock().unwrap();
+        rollups::prune(&conn, resolution, cutoff_ms)
+    }
+
+    /// Delete packets and flows older than the specified number of seconds
     /// Returns the number of deleted rows
     pub fn delete_old_data(&self, older_than_seconds: u64) -> Result<usize> {
         let cutoff_ms = chrono::Utc::now().timestamp_millis() - (older_than_seconds as i64 * 1000);
@@ -227,7 +671,169 @@ impl Storage {
             "DELETE FROM packets WHERE timestamp < ?1",
             params![cutoff_ms],
         )?;
-        Ok(deleted)
+        let deleted_flows = conn.execute(
+            "DELETE FROM flows WHERE end_ns < ?1",
+            params![cutoff_ms.saturating_mul(1_000_000)],
+        )?;
+        Ok(deleted + deleted_flows)
     }
 }
 
+fn history_row(row: &Row) -> Result<(HistoryCursor, PacketMetadata)> {
+    let cursor = HistoryCursor {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+    };
+    let protocol: Protocol = row
+        .get::<_, Option<String>>(8)?
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(Protocol::Unknown);
+    let packet = PacketMetadata {
+        timestamp: cursor.timestamp,
+        timestamp_ns: row.get(2)?,
+        interface: Arc::from(row.get::<_, String>(3)?),
+        src_ip: parse_ip(&row.get::<_, String>(4)?),
+        dst_ip: parse_ip(&row.get::<_, String>(5)?),
+        src_port: row.get::<_, Option<u16>>(6)?.unwrap_or(0),
+        dst_port: row.get::<_, Option<u16>>(7)?.unwrap_or(0),
+        protocol,
+        ip_protocol: protocol.ip_number(),
+        vlan: None,
+        length: row.get::<_, Option<usize>>(9)?.unwrap_or(0),
+        tcp_flags: row.get(10)?,
+    };
+    Ok((cursor, packet))
+}
+
+/// Parse an address column. Rows written before addresses were typed may hold
+/// placeholders such as "?.?.?.?", which read back as the unspecified address.
+fn parse_ip(value: &str) -> IpAddr {
+    value.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn latest(limit: usize) -> HistoryQuery {
+        HistoryQuery { limit, ..Default::default() }
+    }
+
+    #[test]
+    fn test_upgrades_legacy_database() {
+        let path = testing::temp_path("legacy.db");
+        let _ = std::fs::remove_file(&path);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE packets (
+                    id INTEGER PRIMARY KEY,
+                    timestamp INTEGER NOT NULL,
+                    src_ip TEXT NOT NULL,
+                    dst_ip TEXT NOT NULL,
+                    src_port INTEGER,
+                    dst_port INTEGER,
+                    protocol TEXT,
+                    length INTEGER
+                );
+                INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
+                VALUES (1700000000123, '10.0.0.1', '10.0.0.2', 1234, 80, 'TCP', 60);",
+            )
+            .unwrap();
+        }
+
+        let storage = Storage::new(path.to_str().unwrap()).unwrap();
+        let rows = storage.query_history(&latest(10)).unwrap().packets;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].timestamp_ns, 1_700_000_000_123_000_000);
+        assert_eq!(&*rows[0].interface, "");
+
+        drop(storage);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_flow_records() {
+        let path = testing::temp_path("flows.db");
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(path.to_str().unwrap()).unwrap();
+
+        let packet = |src_ip: &str, src_port, dst_ip: &str, dst_port, timestamp_ns| PacketMetadata {
+            timestamp: timestamp_ns / 1_000_000,
+            timestamp_ns,
+            ..testing::packet(Protocol::Udp, src_ip, src_port, dst_ip, dst_port, 100)
+        };
+        let mut bucket = AggregatedBucket::from_packet(&packet("10.0.0.1", 5353, "10.0.0.2", 53, 1_000_000_000));
+        bucket.merge(&packet("10.0.0.2", 53, "10.0.0.1", 5353, 3_000_000_000));
+        assert!(storage.insert_flows(&[FlowRecord::from(&bucket)]));
+
+        let flows = storage.query_flows(10, Some(FlowKind::Window)).unwrap();
+        assert_eq!(flows.len(), 1);
+        let flow = &flows[0];
+        assert_eq!(flow.src_ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!((flow.packets_sent, flow.packets_received), (1, 1));
+        assert_eq!(flow.duration_ms, 2000);
+        assert_eq!(flow.close_reason, None);
+        assert!(storage.query_flows(10, Some(FlowKind::Flow)).unwrap().is_empty());
+        assert!(storage.query_history(&latest(10)).unwrap().packets.is_empty());
+
+        drop(storage);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_history_filters_and_pages() {
+        let path = testing::temp_path("history.db");
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(path.to_str().unwrap()).unwrap();
+
+        let mut packets: Vec<PacketMetadata> = (0..20)
+            .map(|i| PacketMetadata {
+                timestamp: 1_000 + i,
+                timestamp_ns: (1_000 + i) * 1_000_000,
+                ..testing::packet(
+                    Protocol::Tcp,
+                    &format!("10.0.{}.1", i % 2),
+                    40000,
+                    "192.168.1.1",
+                    if i % 4 == 0 { 53 } else { 443 },
+                    100 + i as usize,
+                )
+            })
+            .collect();
+        storage.flush(&mut packets);
+
+        // CIDR filter checked outside SQL, paged oldest first
+        let mut query = HistoryQuery {
+            src: Some("10.0.1.0/24".parse().unwrap()),
+            order: SortOrder::Asc,
+            limit: 4,
+            ..Default::default()
+        };
+        let first = storage.query_history(&query).unwrap();
+        let timestamps: Vec<i64> = first.packets.iter().map(|p| p.timestamp).collect();
+        assert_eq!(timestamps, vec![1_001, 1_003, 1_005, 1_007]);
+
+        query.cursor = first.next_cursor;
+        let second = storage.query_history(&query).unwrap();
+        assert_eq!(second.packets[0].timestamp, 1_009);
+
+        // SQL filters, newest first
+        let query = HistoryQuery {
+            port: Some(53),
+            min_length: Some(105),
+            to_ms: Some(1_016),
+            limit: 10,
+            ..Default::default()
+        };
+        let page = storage.query_history(&query).unwrap();
+        let timestamps: Vec<i64> = page.packets.iter().map(|p| p.timestamp).collect();
+        assert_eq!(timestamps, vec![1_012, 1_008]);
+        assert_eq!(page.next_cursor, None);
+
+        drop(storage);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,29 @@
+// Fixtures shared by the tests of several modules
+
+use crate::state::{PacketMetadata, Protocol};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A packet seen on `eth0` at time 0, without VLAN tag or TCP flags. Set other fields
+/// with struct update syntax.
+pub fn packet(protocol: Protocol, src: &str, src_port: u16, dst: &str, dst_port: u16, length: usize) -> PacketMetadata {
+    PacketMetadata {
+        timestamp: 0,
+        timestamp_ns: 0,
+        interface: Arc::from("eth0"),
+        src_ip: src.parse().unwrap(),
+        dst_ip: dst.parse().unwrap(),
+        src_port,
+        dst_port,
+        protocol,
+        ip_protocol: protocol.ip_number(),
+        vlan: None,
+        length,
+        tcp_flags: 0,
+    }
+}
+
+/// A path in the temp directory for a test's files, unique to `name` and this test run
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("lightshark-{}-{}", std::process::id(), name))
+}
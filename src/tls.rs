@@ -0,0 +1,269 @@
+use crate::config::Config;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::time::{interval, Duration};
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// PEM files the API server's TLS configuration is built from
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    /// CA that client certificates must chain to (mutual TLS)
+    client_ca: Option<PathBuf>,
+}
+
+/// Contents of the files at the time a configuration was built
+#[derive(PartialEq, Eq)]
+struct Loaded {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    client_ca: Option<Vec<u8>>,
+}
+
+impl TlsFiles {
+    /// The configured files, or `None` when TLS is off
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: config.tls_client_ca.as_ref().map(PathBuf::from),
+            })),
+            (None, None) if config.tls_client_ca.is_some() => {
+                Err("tls_client_ca requires tls_cert and tls_key".to_string())
+            }
+            (None, None) => Ok(None),
+            _ => Err("tls_cert and tls_key must be set together".to_string()),
+        }
+    }
+
+    fn read(&self) -> Result<Loaded, String> {
+        let read = |path: &PathBuf| fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+        Ok(Loaded {
+            cert: read(&self.cert)?,
+            key: read(&self.key)?,
+            client_ca: self.client_ca.as_ref().map(read).transpose()?,
+        })
+    }
+
+    /// Read the files and build a server configuration from them
+    pub fn load(&self) -> Result<RustlsConfig, String> {
+        let loaded = self.read()?;
+        Ok(RustlsConfig::from_config(Arc::new(self.server_config(&loaded)?)))
+    }
+
+    fn server_config(&self, loaded: &Loaded) -> Result<ServerConfig, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let certs = CertificateDer::pem_slice_iter(&loaded.cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: {:?}", self.cert.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("{}: no certificates found", self.cert.display()));
+        }
+        let key = PrivateKeyDer::from_pem_slice(&loaded.key)
+            .map_err(|e| format!("{}: {:?}", self.key.display(), e))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match (&self.client_ca, &loaded.client_ca) {
+            (Some(path), Some(pem)) => {
+                let mut roots = RootCertStore::empty();
+                for ca in CertificateDer::pem_slice_iter(pem) {
+                    let ca = ca.map_err(|e| format!("{}: {:?}", path.display(), e))?;
+                    roots.add(ca).map_err(|e| format!("{}: {}", path.display(), e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("{}: {}", self.cert.display(), e))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    /// Reload `config` whenever the files change, e.g. when cert-manager rotates a
+    /// certificate. Contents are compared rather than timestamps, since Kubernetes
+    /// updates mounted secrets by swapping symlinks. A broken update is logged and the
+    /// previous configuration kept.
+    pub async fn watch(self, config: RustlsConfig) {
+        let mut current = self.read().ok();
+        let mut ticker = interval(RELOAD_CHECK_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            self.reload_if_changed(&config, &mut current);
+        }
+    }
+
+    /// Reload `config` if the files no longer have the `current` contents
+    fn reload_if_changed(&self, config: &RustlsConfig, current: &mut Option<Loaded>) {
+        let loaded = match self.read() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                tracing::warn!("Cannot read TLS files, keeping the current certificate: {}", e);
+                return;
+            }
+        };
+        if current.as_ref() == Some(&loaded) {
+            return;
+        }
+        match self.server_config(&loaded) {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                tracing::info!("Reloaded TLS certificate from {}", self.cert.display());
+            }
+            Err(e) => tracing::error!("Invalid TLS files, keeping the current certificate: {}", e),
+        }
+        // Remember broken contents too, so the error is logged once per change
+        *current = Some(loaded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn pem_path(name: &str) -> std::path::PathBuf {
+        testing::temp_path(&format!("tls-{}.pem", name))
+    }
+
+    fn write_pem(name: &str, contents: &str) -> String {
+        let path = pem_path(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_server_config() {
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ca = rcgen::generate_simple_self_signed(vec!["clients".to_string()]).unwrap();
+        let config = Config {
+            tls_cert: Some(write_pem("cert", &server.cert.pem())),
+            tls_key: Some(write_pem("key", &server.key_pair.serialize_pem())),
+            ..Config::default()
+        };
+
+        let files = TlsFiles::from_config(&config).unwrap().unwrap();
+        let server_config = files.server_config(&files.read().unwrap()).unwrap();
+        assert_eq!(server_config.alpn_protocols, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+        let mutual = TlsFiles::from_config(&Config {
+            tls_client_ca: Some(write_pem("ca", &ca.cert.pem())),
+            ..config.clone()
+        })
+        .unwrap()
+        .unwrap();
+        assert!(mutual.server_config(&mutual.read().unwrap()).is_ok());
+        // A client CA file without certificates would reject every client
+        let no_roots = TlsFiles::from_config(&Config {
+            tls_client_ca: Some(write_pem("empty", "")),
+            ..config.clone()
+        })
+        .unwrap()
+        .unwrap();
+        assert!(no_roots.server_config(&no_roots.read().unwrap()).is_err());
+
+        // A key that does not parse is an error, not a server without TLS
+        let broken = TlsFiles::from_config(&Config {
+            tls_key: Some(write_pem("broken", "not a key")),
+            ..config.clone()
+        })
+        .unwrap()
+        .unwrap();
+        assert!(broken.server_config(&broken.read().unwrap()).is_err());
+
+        assert!(TlsFiles::from_config(&Config {
+            tls_key: None,
+            ..config
+        })
+        .is_err());
+        assert!(TlsFiles::from_config(&Config::default()).unwrap().is_none());
+
+        for name in ["cert", "key", "ca", "empty", "broken"] {
+            let _ = fs::remove_file(pem_path(name));
+        }
+    }
+
+    /// Handshake in memory with a server using `config`, returning the certificate it presents
+    fn served_certificate(config: &RustlsConfig, roots: &RootCertStore) -> CertificateDer<'static> {
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        let server_name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+        let mut server = rustls::ServerConnection::new(config.get_inner()).unwrap();
+
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut buf).unwrap();
+            }
+            if !buf.is_empty() {
+                server.read_tls(&mut buf.as_slice()).unwrap();
+                server.process_new_packets().unwrap();
+            }
+            buf.clear();
+            while server.wants_write() {
+                server.write_tls(&mut buf).unwrap();
+            }
+            if !buf.is_empty() {
+                client.read_tls(&mut buf.as_slice()).unwrap();
+                client.process_new_packets().unwrap();
+            }
+        }
+        client.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    #[test]
+    fn test_reload() {
+        let old = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let new = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(old.cert.der().clone()).unwrap();
+        roots.add(new.cert.der().clone()).unwrap();
+
+        let config = Config {
+            tls_cert: Some(write_pem("reload-cert", &old.cert.pem())),
+            tls_key: Some(write_pem("reload-key", &old.key_pair.serialize_pem())),
+            ..Config::default()
+        };
+        let files = TlsFiles::from_config(&config).unwrap().unwrap();
+        let rustls_config = files.load().unwrap();
+        let mut current = files.read().ok();
+        assert_eq!(&served_certificate(&rustls_config, &roots), old.cert.der());
+
+        // Rotated files are picked up
+        write_pem("reload-cert", &new.cert.pem());
+        write_pem("reload-key", &new.key_pair.serialize_pem());
+        files.reload_if_changed(&rustls_config, &mut current);
+        assert_eq!(&served_certificate(&rustls_config, &roots), new.cert.der());
+
+        // Invalid or missing files keep the previous certificate
+        write_pem("reload-key", "not a key");
+        files.reload_if_changed(&rustls_config, &mut current);
+        assert_eq!(&served_certificate(&rustls_config, &roots), new.cert.der());
+        fs::remove_file(config.tls_cert.as_ref().unwrap()).unwrap();
+        files.reload_if_changed(&rustls_config, &mut current);
+        assert_eq!(&served_certificate(&rustls_config, &roots), new.cert.der());
+
+        let _ = fs::remove_file(config.tls_key.as_ref().unwrap());
+    }
+}